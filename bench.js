@@ -0,0 +1,24 @@
+// Generates a large number of mutation records, for `make bench`.
+var ROWS = 2000;
+
+var table = document.createElement("table");
+document.body.appendChild(table);
+
+for (var i = 0; i < ROWS; i++) {
+    var row = document.createElement("tr");
+    row.setAttribute("id", "row" + i);
+    row.setAttribute("class", i % 2 ? "odd" : "even");
+    table.appendChild(row);
+    for (var j = 0; j < 4; j++) {
+        var cell = document.createElement("td");
+        cell.appendChild(document.createTextNode("cell " + i + "," + j));
+        row.appendChild(cell);
+    }
+}
+
+for (var i = 0; i < ROWS; i += 2) {
+    var row = document.getElementById("row" + i);
+    row.firstChild.firstChild.data = "changed";
+    row.removeAttribute("class");
+    table.insertBefore(row, table.firstChild);
+}
@@ -0,0 +1,5 @@
+// Never finishes on its own; see "make check-budget".
+
+print("looping");
+while (true) {}
+print("FAIL the loop finished");
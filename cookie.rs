@@ -0,0 +1,355 @@
+/*
+ * Cookies, kept as RFC 6265 says a browser keeps them.
+ *
+ * A jar stores the cookies that responses set with Set-Cookie and that
+ * scripts set through document.cookie, and says which of them go with a
+ * request. It runs as a task of its own, so that an actor's requests,
+ * each on a thread of its own, can share one; actors can share one too.
+ * There is no public suffix list: a Domain attribute is accepted for any
+ * parent of the host that is not a bare top-level name.
+ */
+
+use std;
+import std::{ sort, time };
+import comm::{ port, chan, send, recv };
+
+export jar, new_jar, close_jar, store, cookie_header, script_cookies;
+
+type cookie = {
+    name: str,
+    value: str,
+    domain: str,
+    // Sent to the host that set it only, not to its subdomains
+    host_only: bool,
+    path: str,
+    // Seconds since the epoch, or none to last as long as the jar
+    expires: option<i64>,
+    secure: bool,
+    http_only: bool,
+    // Orders cookies whose paths are equally long
+    serial: uint
+};
+
+enum jar_msg {
+    set(url::url, str, bool),           // url, Set-Cookie value, from script
+    get(url::url, bool, chan<str>),     // url, for script, reply
+    shutdown,
+}
+
+// Where cookies are kept, by one actor or by all of them.
+type jar = chan<jar_msg>;
+
+
+fn new_jar() -> jar {
+    ret task::spawn_listener {|msgs|
+        let cookies : [cookie] = [], serial = 0u;
+        while true {
+            alt recv(msgs) {
+                set(u, header, from_script) {
+                    alt parse(u, header, from_script, serial, now()) {
+                        option::some(c) {
+                            serial += 1u;
+                            cookies = replace(cookies, c, from_script);
+                        }
+                        option::none { }
+                    }
+                }
+                get(u, for_script, reply) {
+                    let t = now();
+                    cookies = vec::filter(cookies, {|c| !expired(c, t) });
+                    send(reply, header_for(cookies, u, for_script));
+                }
+                shutdown { break; }
+            }
+        }
+    };
+}
+
+
+fn close_jar(j : jar) {
+    send(j, shutdown);
+}
+
+
+/* Stores the cookie in a Set-Cookie header of a response from `u`. */
+fn store(j : jar, u : url::url, header : str) {
+    send(j, set(u, header, false));
+}
+
+
+/* The value of the Cookie header for a request to `u`, or "" for none. */
+fn cookie_header(j : jar, u : url::url) -> str {
+    let p = port();
+    send(j, get(u, false, chan(p)));
+    ret recv(p);
+}
+
+
+/*
+ * document.cookie for a page at `u`: reads it, or, given a new value,
+ * stores it as if from Set-Cookie and returns "". HttpOnly cookies can
+ * be neither read nor set this way.
+ */
+fn script_cookies(j : jar, u : url::url, value : option<str>) -> str {
+    alt value {
+        option::some(v) { send(j, set(u, v, true)); ret ""; }
+        option::none {
+            let p = port();
+            send(j, get(u, true, chan(p)));
+            ret recv(p);
+        }
+    }
+}
+
+
+fn now() -> i64 {
+    ret time::get_time().sec as i64;
+}
+
+
+fn expired(c : cookie, t : i64) -> bool {
+    ret alt c.expires { option::some(e) { e <= t } option::none { false } };
+}
+
+
+fn is_http(u : url::url) -> bool {
+    ret str::eq(u.scheme, "http") || str::eq(u.scheme, "https");
+}
+
+
+fn host_of(u : url::url) -> str {
+    ret alt u.host { option::some(h) { h } option::none { "" } };
+}
+
+
+// IP addresses match only themselves, never as a parent domain.
+fn is_ip(host : str) -> bool {
+    if str::starts_with(host, "[") { ret true; }
+    for b in str::bytes(host) {
+        if !(b == '.' as u8 || (b >= '0' as u8 && b <= '9' as u8)) { ret false; }
+    }
+    ret true;
+}
+
+
+fn domain_match(host : str, domain : str) -> bool {
+    if str::eq(host, domain) { ret true; }
+    ret !is_ip(host) && str::ends_with(host, "." + domain);
+}
+
+
+fn path_match(path : str, cookie_path : str) -> bool {
+    if str::eq(path, cookie_path) { ret true; }
+    if !str::starts_with(path, cookie_path) { ret false; }
+    ret str::ends_with(cookie_path, "/") ||
+        str::bytes(path)[str::len(cookie_path)] == '/' as u8;
+}
+
+
+// The directory of the request's path: "/a/b" for "/a/b/c".
+fn default_path(u : url::url) -> str {
+    if !str::starts_with(u.path, "/") { ret "/"; }
+    ret alt str::rindex(u.path, '/' as u8) {
+        0 { "/" }
+        i { str::slice(u.path, 0u, i as uint) }
+    };
+}
+
+
+/*
+ * Reads a Set-Cookie value sent by `u`. Returns none for a cookie a
+ * browser would ignore: one without a name, for another domain, Secure
+ * from an insecure page, or HttpOnly from a script.
+ */
+fn parse(u : url::url, header : str, from_script : bool, serial : uint,
+         t : i64) -> option<cookie> {
+    if !is_http(u) { ret option::none; }
+    let parts = str::split_char(header, ';');
+    let pair = parts[0];
+    let (name, value) = alt str::index(pair, '=' as u8) {
+        -1 { ret option::none; }
+        i {
+            (str::trim(str::slice(pair, 0u, i as uint)),
+             str::trim(str::slice(pair, i as uint + 1u, str::len(pair))))
+        }
+    };
+    if str::is_empty(name) { ret option::none; }
+
+    let host = host_of(u);
+    let c = {name: name, value: value, domain: host, host_only: true,
+             path: default_path(u), expires: option::none, secure: false,
+             http_only: false, serial: serial};
+    let max_age = option::none;
+    for attr in vec::slice(parts, 1u, vec::len(parts)) {
+        let (k, v) = alt str::index(attr, '=' as u8) {
+            -1 { (str::trim(attr), "") }
+            i {
+                (str::trim(str::slice(attr, 0u, i as uint)),
+                 str::trim(str::slice(attr, i as uint + 1u, str::len(attr))))
+            }
+        };
+        let k = str::to_lower(k);
+        if str::eq(k, "expires") {
+            alt parse_date(v) {
+                option::some(e) { c = {expires: option::some(e) with c}; }
+                option::none { }
+            }
+        } else if str::eq(k, "max-age") {
+            let neg = str::starts_with(v, "-");
+            let digits = if neg { str::slice(v, 1u, str::len(v)) } else { v };
+            alt uint::from_str(digits) {
+                option::some(n) {
+                    max_age = option::some(if neg { t - (n as i64) } else { t + (n as i64) });
+                }
+                option::none { }
+            }
+        } else if str::eq(k, "domain") && !str::is_empty(v) {
+            let d = str::to_lower(if str::starts_with(v, ".") {
+                str::slice(v, 1u, str::len(v))
+            } else { v });
+            // Naming the host itself still makes the cookie go to its
+            // subdomains.
+            if !str::eq(d, host) &&
+               (str::find(d, ".") < 0 || !domain_match(host, d)) {
+                ret option::none;
+            }
+            c = {domain: d, host_only: false with c};
+        } else if str::eq(k, "path") {
+            c = {path: if str::starts_with(v, "/") { v } else { default_path(u) } with c};
+        } else if str::eq(k, "secure") {
+            c = {secure: true with c};
+        } else if str::eq(k, "httponly") {
+            c = {http_only: true with c};
+        }
+    }
+    // Max-Age wins over Expires.
+    if option::is_some(max_age) { c = {expires: max_age with c}; }
+    if c.secure && !str::eq(u.scheme, "https") { ret option::none; }
+    if c.http_only && from_script { ret option::none; }
+    ret option::some(c);
+}
+
+
+// Stores `c` in place of any cookie with its name, domain and path. An
+// expired cookie only removes the old one, and a script cannot replace
+// an HttpOnly cookie.
+fn replace(cookies : [cookie], c : cookie, from_script : bool) -> [cookie] {
+    let kept = [];
+    for old in cookies {
+        if str::eq(old.name, c.name) && str::eq(old.domain, c.domain) &&
+           str::eq(old.path, c.path) {
+            if old.http_only && from_script { ret cookies; }
+            cont;
+        }
+        kept += [old];
+    }
+    ret if expired(c, now()) { kept } else { kept + [c] };
+}
+
+
+// "a=1; b=2", longest paths first, then oldest first.
+fn header_for(cookies : [cookie], u : url::url, for_script : bool) -> str {
+    if !is_http(u) { ret ""; }
+    let host = host_of(u), path = if str::is_empty(u.path) { "/" } else { u.path };
+    let found = vec::filter(cookies, {|c|
+        (if c.host_only { str::eq(host, c.domain) } else { domain_match(host, c.domain) }) &&
+        path_match(path, c.path) &&
+        (!c.secure || str::eq(u.scheme, "https")) &&
+        !(c.http_only && for_script)
+    });
+    fn le(a : cookie, b : cookie) -> bool {
+        let la = str::len(a.path), lb = str::len(b.path);
+        ret la > lb || (la == lb && a.serial <= b.serial);
+    }
+    ret str::connect(vec::map(sort::merge_sort(le, found), {|c|
+        c.name + "=" + c.value
+    }), "; ");
+}
+
+
+/*
+ * Reads a cookie date as RFC 6265 section 5.1.1 does, which takes the
+ * formats servers send: "Wed, 21 Oct 2015 07:28:00 GMT",
+ * "Wednesday, 21-Oct-15 07:28:00 GMT" and "Wed Oct 21 07:28:00 2015".
+ */
+fn parse_date(s : str) -> option<i64> {
+    fn is_delim(c : char) -> bool {
+        ret !((c >= '0' && c <= '9') || (c >= 'a' && c <= 'z') ||
+              (c >= 'A' && c <= 'Z') || c == ':');
+    }
+    fn number(tok : str, min : uint, max : uint) -> option<uint> {
+        let n = 0u, i = 0u;
+        for b in str::bytes(tok) {
+            if b < '0' as u8 || b > '9' as u8 { break; }
+            n = n * 10u + ((b - ('0' as u8)) as uint);
+            i += 1u;
+        }
+        ret if i >= min && i <= max { option::some(n) } else { option::none };
+    }
+    let months = ["jan", "feb", "mar", "apr", "may", "jun",
+                  "jul", "aug", "sep", "oct", "nov", "dec"];
+    let time = option::none, day = option::none, month = option::none,
+        year = option::none;
+    for tok in str::split_func(s, is_delim) {
+        if str::is_empty(tok) { cont; }
+        if option::is_none(time) && str::find(tok, ":") >= 0 {
+            let hms = str::split_char(tok, ':');
+            if vec::len(hms) == 3u {
+                alt (number(hms[0], 1u, 2u), number(hms[1], 1u, 2u),
+                     number(hms[2], 1u, 2u)) {
+                    (option::some(h), option::some(m), option::some(sec)) {
+                        time = option::some((h, m, sec));
+                        cont;
+                    }
+                    _ { }
+                }
+            }
+        }
+        if option::is_none(day) && str::len(tok) <= 2u {
+            alt number(tok, 1u, 2u) {
+                option::some(d) { day = option::some(d); cont; }
+                option::none { }
+            }
+        }
+        if option::is_none(month) && str::len(tok) >= 3u {
+            let m = str::to_lower(str::slice(tok, 0u, 3u)), i = 0u;
+            while i < 12u {
+                if str::eq(m, months[i]) { month = option::some(i + 1u); }
+                i += 1u;
+            }
+            if option::is_some(month) { cont; }
+        }
+        if option::is_none(year) {
+            alt number(tok, 2u, 4u) {
+                option::some(y) {
+                    year = option::some(if y < 70u { y + 2000u }
+                                        else if y < 100u { y + 1900u }
+                                        else { y });
+                }
+                option::none { }
+            }
+        }
+    }
+    alt (time, day, month, year) {
+        (option::some((h, m, sec)), option::some(d), option::some(mo), option::some(y)) {
+            if d < 1u || d > 31u || y < 1601u || h > 23u || m > 59u || sec > 59u {
+                ret option::none;
+            }
+            let days = days_from_civil(y as i64, mo as i64, d as i64);
+            ret option::some(days * 86400i64 + (h * 3600u + m * 60u + sec) as i64);
+        }
+        _ { ret option::none; }
+    }
+}
+
+
+// Days from 1970-01-01 to the given date of the Gregorian calendar.
+fn days_from_civil(y : i64, m : i64, d : i64) -> i64 {
+    let y = if m <= 2i64 { y - 1i64 } else { y };
+    let era = (if y >= 0i64 { y } else { y - 399i64 }) / 400i64;
+    let yoe = y - era * 400i64;
+    let mp = if m > 2i64 { m - 3i64 } else { m + 9i64 };
+    let doy = (153i64 * mp + 2i64) / 5i64 + d - 1i64;
+    let doe = yoe * 365i64 + yoe / 4i64 - yoe / 100i64 + doy;
+    ret era * 146097i64 + doe - 719468i64;
+}
@@ -0,0 +1,592 @@
+/*
+ * Parses the style sheets and style attributes of the DOM mirror and
+ * computes a style for each element.
+ *
+ * Only the properties layout needs so far are handled: display, margin,
+ * padding, width, height, font-size and color. Rules come from a small
+ * user agent sheet, then <style> elements in document order, then style
+ * attributes, and are cascaded by importance, origin, specificity and
+ * order. font-size and color inherit; "inherit" works for all of them.
+ */
+
+use std;
+import std::{ map, sort };
+
+export length, px, percent, auto, sides, color, display, block, inline;
+export display_none;
+export style, rule, decl, parse_stylesheet, parse_declarations;
+export resolver, new_resolver, computed_style, text_style, all_styles;
+export sheets_generation;
+export to_str;
+
+enum length {
+    px(float),
+    percent(float),     // of the containing block's width
+    auto,
+}
+
+type sides = {top: length, right: length, bottom: length, left: length};
+
+type color = {r: u8, g: u8, b: u8};
+
+enum display {
+    block,
+    inline,
+    display_none,
+}
+
+// Lengths in em are already converted to px.
+type style = {
+    display: display,
+    margin: sides,
+    padding: sides,
+    width: length,
+    height: length,
+    font_size: float,
+    color: color
+};
+
+type decl = {name: str, value: str, important: bool};
+
+type rule = {selector: select::selector, specificity: uint, decls: [decl]};
+
+const MEDIUM : float = 16.0;
+
+const UA_SHEET : str = "
+    html, address, blockquote, body, center, dd, div, dl, dt, fieldset,
+    form, frameset, h1, h2, h3, h4, h5, h6, hr, li, menu, noframes, ol, p,
+    pre, ul, table, tr, thead, tbody, tfoot, article, aside, footer, header,
+    nav, section { display: block }
+    head, script, style, title, meta, link, base, noscript { display: none }
+    body { margin: 8px }
+    h1 { font-size: 2em; margin: 0.67em 0 }
+    h2 { font-size: 1.5em; margin: 0.75em 0 }
+    h3 { font-size: 1.17em; margin: 0.83em 0 }
+    h4, p, blockquote, ul, ol, dl, pre { margin: 1em 0 }
+    h5 { font-size: 0.83em; margin: 1.5em 0 }
+    h6 { font-size: 0.75em; margin: 1.67em 0 }
+    blockquote { margin-left: 40px; margin-right: 40px }
+    ul, ol { padding-left: 40px }
+    small { font-size: 0.83em }
+    big { font-size: 1.17em }
+";
+
+const NAMED_COLORS : [(str, u8, u8, u8)] = [
+    ("black", 0u8, 0u8, 0u8), ("silver", 192u8, 192u8, 192u8),
+    ("gray", 128u8, 128u8, 128u8), ("grey", 128u8, 128u8, 128u8),
+    ("white", 255u8, 255u8, 255u8), ("maroon", 128u8, 0u8, 0u8),
+    ("red", 255u8, 0u8, 0u8), ("purple", 128u8, 0u8, 128u8),
+    ("fuchsia", 255u8, 0u8, 255u8), ("green", 0u8, 128u8, 0u8),
+    ("lime", 0u8, 255u8, 0u8), ("olive", 128u8, 128u8, 0u8),
+    ("yellow", 255u8, 255u8, 0u8), ("navy", 0u8, 0u8, 128u8),
+    ("blue", 0u8, 0u8, 255u8), ("teal", 0u8, 128u8, 128u8),
+    ("aqua", 0u8, 255u8, 255u8), ("orange", 255u8, 165u8, 0u8)
+];
+
+
+fn is_space(c : char) -> bool {
+    ret c == ' ' || c == '\t' || c == '\n' || c == '\r' || c == '\x0c';
+}
+
+
+fn strip_comments(src : str) -> str {
+    let out = "", pos = 0u, len = str::len(src);
+    while pos < len {
+        alt str::find(str::slice(src, pos, len), "/*") {
+            -1 { out += str::slice(src, pos, len); break; }
+            i {
+                let start = pos + i as uint;
+                out += str::slice(src, pos, start);
+                alt str::find(str::slice(src, start + 2u, len), "*/") {
+                    -1 { break; }
+                    j { pos = start + 2u + j as uint + 2u; }
+                }
+            }
+        }
+    }
+    ret out;
+}
+
+
+// The offset just past the block that opens at `open`, counting nested
+// braces, or the end of the source for an unclosed block.
+fn skip_block(src : str, open : uint) -> uint {
+    let depth = 0u, pos = open, len = str::len(src);
+    while pos < len {
+        if src[pos] == '{' as u8 {
+            depth += 1u;
+        } else if src[pos] == '}' as u8 {
+            depth -= 1u;
+            if depth == 0u { ret pos + 1u; }
+        }
+        pos += 1u;
+    }
+    ret len;
+}
+
+
+// Expands margin and padding shorthands into their four longhands.
+fn expand(name : str, value : str, important : bool) -> [decl] {
+    if !str::eq(name, "margin") && !str::eq(name, "padding") {
+        ret [{name: name, value: value, important: important}];
+    }
+    let v = vec::filter(str::split_func(value, is_space), {|w| !str::is_empty(w) });
+    let (t, r, b, l) = alt vec::len(v) {
+        1u { (v[0], v[0], v[0], v[0]) }
+        2u { (v[0], v[1], v[0], v[1]) }
+        3u { (v[0], v[1], v[2], v[1]) }
+        4u { (v[0], v[1], v[2], v[3]) }
+        _ { ret []; }
+    };
+    ret [{name: name + "-top", value: t, important: important},
+         {name: name + "-right", value: r, important: important},
+         {name: name + "-bottom", value: b, important: important},
+         {name: name + "-left", value: l, important: important}];
+}
+
+
+/* Parses the contents of a declaration block or style attribute. */
+fn parse_declarations(src : str) -> [decl] {
+    let decls = [];
+    for d in str::split_char(strip_comments(src), ';') {
+        let colon = alt str::index(d, ':' as u8) {
+            -1 { cont; }
+            i { i as uint }
+        };
+        let name = str::to_lower(str::trim(str::slice(d, 0u, colon))),
+            value = str::trim(str::slice(d, colon + 1u, str::len(d))),
+            important = false;
+        alt str::find(value, "!") {
+            -1 { }
+            i {
+                let bang = str::trim(str::slice(value, i as uint + 1u, str::len(value)));
+                if !str::eq(str::to_lower(bang), "important") { cont; }
+                value = str::trim(str::slice(value, 0u, i as uint));
+                important = true;
+            }
+        }
+        if str::is_empty(name) || str::is_empty(value) { cont; }
+        decls += expand(name, value, important);
+    }
+    ret decls;
+}
+
+
+/*
+ * Parses a style sheet into one rule per selector. At-rules, and rules
+ * whose selectors are outside what select.rs handles, are skipped.
+ */
+fn parse_stylesheet(src : str) -> [rule] {
+    let src = strip_comments(src),
+        rules = [],
+        pos = 0u,
+        len = str::len(src);
+    while pos < len {
+        let open = alt str::index_from(src, '{' as u8, pos, len) {
+            -1 { break; }
+            i { i as uint }
+        };
+        let prelude = str::trim(str::slice(src, pos, open));
+        if str::starts_with(prelude, "@") {
+            // Statement at-rules such as @import end at a semicolon;
+            // block at-rules such as @media are skipped whole.
+            alt str::index_from(src, ';' as u8, pos, open) {
+                -1 { pos = skip_block(src, open); }
+                semi { pos = semi as uint + 1u; }
+            }
+            cont;
+        }
+        let close = alt str::index_from(src, '}' as u8, open, len) {
+            -1 { len }
+            i { i as uint }
+        };
+        let decls = parse_declarations(str::slice(src, open + 1u, close));
+        alt select::parse(prelude) {
+            result::ok(group) {
+                for sel in select::split(group) {
+                    rules += [{selector: sel,
+                               specificity: select::specificity(sel),
+                               decls: decls}];
+                }
+            }
+            result::err(_) { }
+        }
+        pos = close + 1u;
+    }
+    ret rules;
+}
+
+
+fn parse_number(s : str) -> option<float> {
+    if str::is_empty(s) { ret option::none; }
+    ret float::from_str(s);
+}
+
+
+fn parse_length(s : str, font_size : float) -> option<length> {
+    let s = str::to_lower(s), len = str::len(s);
+    if str::eq(s, "auto") { ret option::some(auto); }
+    if str::eq(s, "0") { ret option::some(px(0.0)); }
+    let (num, unit) = if str::ends_with(s, "%") {
+        (str::slice(s, 0u, len - 1u), "%")
+    } else if len > 2u {
+        (str::slice(s, 0u, len - 2u), str::slice(s, len - 2u, len))
+    } else {
+        ret option::none;
+    };
+    ret alt parse_number(num) {
+        option::some(n) {
+            alt unit {
+                "px" { option::some(px(n)) }
+                "em" { option::some(px(n * font_size)) }
+                "pt" { option::some(px(n * 4.0 / 3.0)) }
+                "%" { option::some(percent(n)) }
+                _ { option::none }
+            }
+        }
+        option::none { option::none }
+    };
+}
+
+
+fn hex_digit(c : u8) -> option<u8> {
+    ret if c >= '0' as u8 && c <= '9' as u8 { option::some(c - ('0' as u8)) }
+        else if c >= 'a' as u8 && c <= 'f' as u8 { option::some(c - ('a' as u8) + 10u8) }
+        else { option::none };
+}
+
+
+fn parse_color(s : str) -> option<color> {
+    let s = str::to_lower(s), len = str::len(s);
+    for c in NAMED_COLORS {
+        let (name, r, g, b) = c;
+        if str::eq(name, s) { ret option::some({r: r, g: g, b: b}); }
+    }
+    if str::starts_with(s, "#") && (len == 4u || len == 7u) {
+        let digits = [];
+        for c in vec::slice(str::bytes(s), 1u, len) {
+            alt hex_digit(c) {
+                option::some(d) { digits += [d]; }
+                option::none { ret option::none; }
+            }
+        }
+        ret option::some(if len == 4u {
+            {r: digits[0] * 17u8, g: digits[1] * 17u8, b: digits[2] * 17u8}
+        } else {
+            {r: digits[0] * 16u8 + digits[1], g: digits[2] * 16u8 + digits[3],
+             b: digits[4] * 16u8 + digits[5]}
+        });
+    }
+    if str::starts_with(s, "rgb(") && str::ends_with(s, ")") {
+        let parts = str::split_char(str::slice(s, 4u, len - 1u), ',');
+        if vec::len(parts) != 3u { ret option::none; }
+        let c = [];
+        for p in parts {
+            alt uint::from_str(str::trim(p)) {
+                option::some(n) { c += [if n > 255u { 255u8 } else { n as u8 }]; }
+                option::none { ret option::none; }
+            }
+        }
+        ret option::some({r: c[0], g: c[1], b: c[2]});
+    }
+    ret option::none;
+}
+
+
+fn parse_font_size(s : str, parent : float) -> option<float> {
+    ret alt str::to_lower(s) {
+        "xx-small" { option::some(9.0) }
+        "x-small" { option::some(10.0) }
+        "small" { option::some(13.0) }
+        "medium" { option::some(MEDIUM) }
+        "large" { option::some(18.0) }
+        "x-large" { option::some(24.0) }
+        "xx-large" { option::some(32.0) }
+        "smaller" { option::some(parent / 1.2) }
+        "larger" { option::some(parent * 1.2) }
+        _ {
+            alt parse_length(s, parent) {
+                option::some(px(n)) { option::some(n) }
+                option::some(percent(n)) { option::some(parent * n / 100.0) }
+                _ { option::none }
+            }
+        }
+    };
+}
+
+
+fn initial_style() -> style {
+    let zero = {top: px(0.0), right: px(0.0), bottom: px(0.0), left: px(0.0)};
+    ret {
+        display: inline,
+        margin: zero,
+        padding: zero,
+        width: auto,
+        height: auto,
+        font_size: MEDIUM,
+        color: {r: 0u8, g: 0u8, b: 0u8}
+    };
+}
+
+
+// The winning value of each property, after the cascade.
+type specified = map::hashmap<str, str>;
+
+
+fn compute(spec : specified, parent : style) -> style {
+    let initial = initial_style();
+
+    // Non-inherited properties start from their initial values.
+    let s = {display: initial.display, margin: initial.margin,
+             padding: initial.padding, width: initial.width,
+             height: initial.height with parent};
+
+    alt spec.find("font-size") {
+        option::some("inherit") | option::none { }
+        option::some(v) {
+            alt parse_font_size(v, parent.font_size) {
+                option::some(f) { s = {font_size: f with s}; }
+                option::none { }
+            }
+        }
+    }
+    alt spec.find("color") {
+        option::some("inherit") | option::none { }
+        option::some(v) {
+            alt parse_color(v) {
+                option::some(c) { s = {color: c with s}; }
+                option::none { }
+            }
+        }
+    }
+    alt spec.find("display") {
+        option::some(v) {
+            alt str::to_lower(v) {
+                "block" | "list-item" | "table" { s = {display: block with s}; }
+                "inline" | "inline-block" { s = {display: inline with s}; }
+                "none" { s = {display: display_none with s}; }
+                "inherit" { s = {display: parent.display with s}; }
+                _ { }
+            }
+        }
+        option::none { }
+    }
+
+    // Lengths; em is relative to this element's own font size.
+    let font_size = s.font_size;
+    fn length_of(spec : specified, name : str, font_size : float,
+                 inherited : length, initial : length) -> length {
+        ret alt spec.find(name) {
+            option::some("inherit") { inherited }
+            option::some(v) {
+                alt parse_length(v, font_size) {
+                    option::some(l) { l }
+                    option::none { initial }
+                }
+            }
+            option::none { initial }
+        };
+    }
+    fn sides_of(spec : specified, prefix : str, font_size : float,
+                inherited : sides, initial : sides) -> sides {
+        ret {
+            top: length_of(spec, prefix + "-top", font_size, inherited.top, initial.top),
+            right: length_of(spec, prefix + "-right", font_size, inherited.right, initial.right),
+            bottom: length_of(spec, prefix + "-bottom", font_size, inherited.bottom, initial.bottom),
+            left: length_of(spec, prefix + "-left", font_size, inherited.left, initial.left)
+        };
+    }
+    ret {
+        margin: sides_of(spec, "margin", font_size, parent.margin, initial.margin),
+        padding: sides_of(spec, "padding", font_size, parent.padding, initial.padding),
+        width: length_of(spec, "width", font_size, parent.width, initial.width),
+        height: length_of(spec, "height", font_size, parent.height, initial.height)
+        with s
+    };
+}
+
+
+/*
+ * Keeps the computed styles of a document, recomputing them on demand
+ * after mutations have been applied.
+ */
+type resolver = @{
+    doc: @dom::document,
+    ua: [rule],
+    mut styles: map::hashmap<uint, style>,
+    // The text of the author style sheets last cascaded, and how many
+    // times it has changed.
+    mut sheets: [str],
+    mut generation: uint,
+    // Shared with the document's listener, which must not hold the
+    // resolver itself.
+    dirty: @mut bool
+};
+
+
+fn new_resolver(doc : @dom::document) -> resolver {
+    let dirty = @mut true;
+    dom::subscribe(doc, {|_c| *dirty = true; });
+    ret @{doc: doc, ua: parse_stylesheet(UA_SHEET),
+          mut styles: map::new_uint_hash(), mut sheets: [],
+          mut generation: 0u, dirty: dirty};
+}
+
+
+fn text_content(doc : @dom::document, nid : uint) -> str {
+    let out = "";
+    for child in dom::children_of(doc, nid) {
+        alt dom::get_node(doc, child) {
+            dom::text(data, _) { out += data; }
+            _ { }
+        }
+    }
+    ret out;
+}
+
+
+// The author style sheets: the text of the <style> elements, in document
+// order.
+fn author_sheets(doc : @dom::document) -> [str] {
+    let sheets = [];
+    for nid in select::get_elements_by_tag_name(doc, 1u, "style") {
+        alt dom::get_node(doc, nid) {
+            dom::element(e) {
+                if str::eq(e.ns, dom::HTML_NAMESPACE) {
+                    sheets += [text_content(doc, nid)];
+                }
+            }
+            _ { }
+        }
+    }
+    ret sheets;
+}
+
+
+type weighted = {key: (bool, uint, uint, uint), decl: decl};
+
+
+fn cascade(doc : @dom::document, nid : uint, e : dom::element,
+           sheets : [(uint, [rule])]) -> specified {
+    let found = [], order = 0u;
+    for sheet in sheets {
+        let (origin, rules) = sheet;
+        for r in rules {
+            if select::matches(doc, nid, r.selector) {
+                for d in r.decls {
+                    found += [{key: (d.important && origin > 0u, origin,
+                                     r.specificity, order),
+                               decl: d}];
+                    order += 1u;
+                }
+            }
+        }
+    }
+    for a in *e.attr {
+        if str::is_empty(a.ns) && str::eq(a.name, "style") {
+            for d in parse_declarations(a.value) {
+                found += [{key: (d.important, 2u, 0u, order), decl: d}];
+                order += 1u;
+            }
+        }
+    }
+
+    fn le(a : weighted, b : weighted) -> bool { ret a.key <= b.key; }
+    let spec = map::new_str_hash();
+    for w in sort::merge_sort(le, found) {
+        spec.insert(w.decl.name, w.decl.value);
+    }
+    ret spec;
+}
+
+
+fn recompute(r : resolver) {
+    let author = author_sheets(r.doc);
+    if author != r.sheets {
+        r.sheets = author;
+        r.generation += 1u;
+    }
+    let sheets = [(0u, r.ua), (1u, vec::concat(vec::map(author, parse_stylesheet)))];
+    r.styles = map::new_uint_hash();
+
+    fn walk(r : resolver, sheets : [(uint, [rule])], nid : uint, parent : style) {
+        for child in dom::children_of(r.doc, nid) {
+            alt dom::get_node(r.doc, child) {
+                dom::element(e) {
+                    let s = compute(cascade(r.doc, child, e, sheets), parent);
+                    r.styles.insert(child, s);
+                    walk(r, sheets, child, s);
+                }
+                _ { }
+            }
+        }
+    }
+    walk(r, sheets, 1u, initial_style());
+    *r.dirty = false;
+}
+
+
+/* The computed style of an element, or none for other nodes. */
+fn computed_style(r : resolver, nid : uint) -> option<style> {
+    if *r.dirty { recompute(r); }
+    ret r.styles.find(nid);
+}
+
+
+/* The style of a text node: its parent's inherited properties, inline. */
+fn text_style(r : resolver, nid : uint) -> style {
+    let inherited = alt computed_style(r, dom::parent_of(r.doc, nid)) {
+        option::some(s) { s }
+        option::none { initial_style() }
+    };
+    ret {font_size: inherited.font_size, color: inherited.color
+         with initial_style()};
+}
+
+
+/*
+ * Counts the changes to the author style sheets, which can restyle any
+ * element: adding, removing or editing a <style> element, or removing
+ * one with its ancestor.
+ */
+fn sheets_generation(r : resolver) -> uint {
+    if *r.dirty { recompute(r); }
+    ret r.generation;
+}
+
+
+/* Every computed style, by nid. */
+fn all_styles(r : resolver) -> map::hashmap<uint, style> {
+    if *r.dirty { recompute(r); }
+    ret r.styles;
+}
+
+
+fn length_to_str(l : length) -> str {
+    ret alt l {
+        px(n) { float::to_str(n, 2u) + "px" }
+        percent(n) { float::to_str(n, 2u) + "%" }
+        auto { "auto" }
+    };
+}
+
+
+fn sides_to_str(s : sides) -> str {
+    ret str::connect([length_to_str(s.top), length_to_str(s.right),
+                      length_to_str(s.bottom), length_to_str(s.left)], " ");
+}
+
+
+/* A computed style as CSS text, for dumps. */
+fn to_str(s : style) -> str {
+    let display = alt s.display {
+        block { "block" }
+        inline { "inline" }
+        display_none { "none" }
+    };
+    ret #fmt("display: %s; margin: %s; padding: %s; width: %s; height: %s; font-size: %s; color: rgb(%u, %u, %u)",
+             display, sides_to_str(s.margin), sides_to_str(s.padding),
+             length_to_str(s.width), length_to_str(s.height),
+             length_to_str(px(s.font_size)),
+             s.color.r as uint, s.color.g as uint, s.color.b as uint);
+}
@@ -11442,7 +11442,15 @@ defineLazyProperty(impl, "Document", function() {
         URL: attribute(nyi),
         domain: attribute(nyi, nyi),
         referrer: attribute(nyi),
-        cookie: attribute(nyi, nyi),
+        // Cookies are kept by the host, which leaves out HttpOnly ones.
+        // Documents without a window have none.
+        cookie: attribute(function() {
+            if (!this.defaultView) return "";
+            return jsrust_cookie(String(this.defaultView.location.href));
+        }, function(v) {
+            if (!this.defaultView) return;
+            jsrust_cookie(String(this.defaultView.location.href), String(v));
+        }),
         lastModified: attribute(nyi),
         // XXX Temporary hack
         readyState: attribute(function() { return "complete" }),
@@ -14173,8 +14181,6 @@ defineLazyProperty(impl, "HTMLScriptElement", function() {
                     var script = this;
                     var xhr = new XMLHttpRequest();
 
-                    // Web workers support this handler but not the old
-                    // onreadystatechange handler
                     xhr.onloadend = function() {
                         if (xhr.status === 200 ||
                             xhr.status === 0 /* file:// urls */) {
@@ -14188,23 +14194,6 @@ defineLazyProperty(impl, "HTMLScriptElement", function() {
                         }
                     };
 
-                    // My node version of XHR responds to this handler but
-                    // not to onloadend above.
-                    xhr.onreadystatechange = function() {
-                        if (xhr.readyState !== 4) return;
-                        if (xhr.status === 200 ||
-                            xhr.status === 0 /* file:// urls */) {
-                            script._script_text = xhr.responseText;
-                            script._execute();
-                            delete script._script_text;
-                        }
-
-                        // Do this even if we failed
-                        if (script.ownerDocument._parser) {
-                            script.ownerDocument._parser.resume();
-                        }
-                    }
-
                     xhr.open("GET", url);
                     xhr.send();
                 }
@@ -0,0 +1,706 @@
+/*
+ * A Rust-side mirror of a dom.js document.
+ *
+ * dom.js reports every change to a rooted node through the mutation
+ * handler installed with document._setMutationHandler. Applying those
+ * records, in order, to a `document` keeps it in step with the JS DOM.
+ */
+
+use std;
+import std::{ json, map };
+import core::error;
+
+export attr, element, node, doctype, procinst, text, comment, nonode;
+export document, change, value_changed, attr_changed, attr_removed;
+export node_removed, node_moved, node_inserted;
+export HTML_NAMESPACE, new_document, apply_mutation, apply_json;
+export apply_binary;
+export get_node, parent_of, children_of, subscribe, check_mirror;
+export split_qname;
+
+type attr = {
+    name: str,      // local name
+    ns: str,        // namespace URI, "" for none
+    mut prefix: str,
+    mut value: str
+};
+
+
+type element = {
+    mut tag: str,
+    mut ns: str,
+    mut attr: @mut[attr],
+    mut parent: uint,
+    mut child: @mut[uint]
+};
+
+
+// Nodes are indexed by the nid dom.js gives them, less one. Child lists
+// hold nids.
+enum node {
+    doctype(str, str, str, uint),
+    procinst(str, str, uint),
+    text(str, uint),
+    comment(str, uint),
+    element(element),
+    nonode,
+}
+
+
+// What an applied mutation record changed, for subscribers.
+enum change {
+    value_changed(uint),                // nid
+    attr_changed(uint, str, str),       // nid, namespace, local name
+    attr_removed(uint, str, str),       // nid, namespace, local name
+    node_removed(uint, uint),           // nid, old parent
+    node_moved(uint, uint, uint),       // nid, old parent, new parent
+    node_inserted(uint, uint),          // nid, parent
+}
+
+
+type document = {
+    mut nodes: [mut node],
+    mut listeners: [fn@(change)]
+};
+
+
+const HTML_NAMESPACE : str = "http://www.w3.org/1999/xhtml";
+
+
+/*
+ * A mirror of the about:blank document that dom.js starts with (see
+ * createHTMLDocument). Its nodes are inserted before any mutation handler
+ * can be installed, so no records are ever sent for them.
+ */
+fn new_document() -> @document {
+    fn elt(tag : str, ns : str, parent : uint, child : [uint]) -> node {
+        ret element({
+            mut tag: tag,
+            mut ns: ns,
+            mut attr: @mut[],
+            mut parent: parent,
+            mut child: @mut child});
+    }
+
+    ret @{
+        mut nodes: [
+            mut elt("Document", "", 0u, [2u, 3u]),
+            doctype("html", "", "", 1u),
+            elt("html", HTML_NAMESPACE, 1u, [4u, 7u]),
+            elt("head", HTML_NAMESPACE, 3u, [5u]),
+            elt("title", HTML_NAMESPACE, 4u, [6u]),
+            text("", 5u),
+            elt("body", HTML_NAMESPACE, 3u, [])],
+        mut listeners: []
+    };
+}
+
+
+/* Calls `listener` after each mutation record is applied. */
+fn subscribe(doc : @document, listener : fn@(change)) {
+    doc.listeners += [listener];
+}
+
+
+fn notify(doc : @document, c : change) {
+    for listener in doc.listeners {
+        listener(c);
+    }
+}
+
+
+fn get_node(doc : @document, nid : uint) -> node {
+    if nid == 0u || nid > vec::len(doc.nodes) {
+        ret nonode;
+    }
+    ret doc.nodes[nid - 1u];
+}
+
+
+fn set_node(doc : @document, nid : uint, n : node) {
+    while vec::len(doc.nodes) < nid {
+        doc.nodes += [mut nonode];
+    }
+    doc.nodes[nid - 1u] = n;
+}
+
+
+/* The nid of the parent of a node, or 0 for the document itself. */
+fn parent_of(doc : @document, nid : uint) -> uint {
+    ret node_parent(get_node(doc, nid));
+}
+
+
+/* The nids of the children of a node, in order. */
+fn children_of(doc : @document, nid : uint) -> [uint] {
+    ret alt get_node(doc, nid) {
+        element(e) { copy *e.child }
+        _ { [] }
+    };
+}
+
+
+fn node_parent(n : node) -> uint {
+    ret alt n {
+        doctype(_, _, _, p) { p }
+        procinst(_, _, p) { p }
+        text(_, p) { p }
+        comment(_, p) { p }
+        element(e) { e.parent }
+        nonode { 0u }
+    };
+}
+
+
+fn with_parent(n : node, parent : uint) -> node {
+    ret alt n {
+        doctype(name, pubid, sysid, _) { doctype(name, pubid, sysid, parent) }
+        procinst(target, data, _) { procinst(target, data, parent) }
+        text(data, _) { text(data, parent) }
+        comment(data, _) { comment(data, parent) }
+        element(e) { e.parent = parent; n }
+        nonode { nonode }
+    };
+}
+
+
+fn insert_child(doc : @document, parent : uint, index : uint, nid : uint) {
+    alt get_node(doc, parent) {
+        element(e) {
+            let index = uint::min(index, vec::len(*e.child));
+            *e.child = (
+                vec::slice(*e.child, 0u, index)
+                + [nid]
+                + vec::slice(*e.child, index, vec::len(*e.child)));
+        }
+        n { log(error, ("insert into non-element", parent, n)); fail }
+    }
+}
+
+
+fn remove_child(doc : @document, parent : uint, nid : uint) {
+    alt get_node(doc, parent) {
+        element(e) {
+            *e.child = vec::filter(copy *e.child, {|id| id != nid });
+        }
+        nonode { }
+        _ { fail }
+    }
+}
+
+
+fn remove_subtree(doc : @document, nid : uint) {
+    alt get_node(doc, nid) {
+        element(e) {
+            for child in *e.child {
+                remove_subtree(doc, child);
+            }
+        }
+        _ { }
+    }
+    if nid <= vec::len(doc.nodes) {
+        doc.nodes[nid - 1u] = nonode;
+    }
+}
+
+
+fn json_field(m : map::hashmap<str, json::json>, key : str) -> str {
+    ret alt m.find(key) {
+        option::some(json::string(s)) { s }
+        _ { "" }
+    };
+}
+
+
+fn json_uint(m : map::hashmap<str, json::json>, key : str) -> uint {
+    ret alt m.find(key) {
+        option::some(json::num(x)) { x as uint }
+        _ { fail #fmt("missing number %s", key) }
+    };
+}
+
+
+// Namespaces in serialized subtrees use the short codes of dom.js's
+// DOMSTR.serialize. "l" repeats the last custom namespace.
+fn decode_ns(code : str, last_custom : @mut str) -> str {
+    if str::is_empty(code) { ret ""; }
+    ret alt str::char_at(code, 0u) {
+        'h' { HTML_NAMESPACE }
+        'u' { "" }
+        'x' { "http://www.w3.org/XML/1998/namespace" }
+        'n' { "http://www.w3.org/2000/xmlns/" }
+        'm' { "http://www.w3.org/1998/Math/MathML" }
+        's' { "http://www.w3.org/2000/svg" }
+        'l' { *last_custom }
+        'c' {
+            *last_custom = str::slice(code, 1u, str::len(code));
+            *last_custom
+        }
+        _ { fail #fmt("bad namespace code %s", code) }
+    };
+}
+
+
+fn split_qname(qname : str) -> (str, str) {
+    ret alt str::index(qname, ':' as u8) {
+        -1 { ("", qname) }
+        i {
+            let i = i as uint;
+            (str::slice(qname, 0u, i),
+             str::slice(qname, i + 1u, str::len(qname)))
+        }
+    };
+}
+
+
+fn decode_attrs(m : map::hashmap<str, json::json>, last_custom : @mut str)
+        -> @mut[attr] {
+    let attrs = @mut[];
+    alt m.find("attr") {
+        option::some(json::list(l)) {
+            for a in *l {
+                alt a {
+                    json::dict(am) {
+                        let name = json_field(am, "a"),
+                            value = json_field(am, "v");
+                        if am.contains_key("ns") {
+                            let ns = decode_ns(json_field(am, "ns"),
+                                               last_custom),
+                                (prefix, local) = split_qname(name);
+                            *attrs += [{name: local, ns: ns,
+                                        mut prefix: prefix,
+                                        mut value: value}];
+                        } else {
+                            *attrs += [{name: name, ns: "",
+                                        mut prefix: "",
+                                        mut value: value}];
+                        }
+                    }
+                    _ { fail }
+                }
+            }
+        }
+        _ { }
+    }
+    ret attrs;
+}
+
+
+// Adds a serialized subtree to the mirror. dom.js numbers the nodes of an
+// inserted subtree in document order starting at the subtree root, so
+// returns the nid after the last one used.
+fn build_subtree(doc : @document, j : json::json, nid : uint, parent : uint,
+                 last_custom : @mut str) -> uint {
+    let m = alt j {
+        json::string(s) {
+            set_node(doc, nid, text(s, parent));
+            ret nid + 1u;
+        }
+        json::dict(m) { m }
+        _ { fail }
+    };
+
+    if m.contains_key("comment") {
+        set_node(doc, nid, comment(json_field(m, "comment"), parent));
+        ret nid + 1u;
+    }
+    if m.contains_key("pi") {
+        set_node(doc, nid, procinst(json_field(m, "pi"),
+                                    json_field(m, "data"), parent));
+        ret nid + 1u;
+    }
+    if m.contains_key("doctype") {
+        set_node(doc, nid, doctype(json_field(m, "doctype"), "", "",
+                                   parent));
+        ret nid + 1u;
+    }
+
+    let (tag, ns) = if m.contains_key("html") {
+        (json_field(m, "html"), HTML_NAMESPACE)
+    } else {
+        (json_field(m, "tag"), decode_ns(json_field(m, "ns"), last_custom))
+    };
+    let e = {
+        mut tag: tag,
+        mut ns: ns,
+        mut attr: decode_attrs(m, last_custom),
+        mut parent: parent,
+        mut child: @mut[]
+    };
+    set_node(doc, nid, element(e));
+
+    let next = nid + 1u;
+    alt m.find("child") {
+        option::some(json::list(l)) {
+            for c in *l {
+                *e.child += [next];
+                next = build_subtree(doc, c, next, nid, last_custom);
+            }
+        }
+        _ { }
+    }
+    ret next;
+}
+
+
+/* Applies a mutation record that was posted as a JSON string. */
+fn apply_json(doc : @document, record : str) {
+    alt json::from_str(record) {
+        result::ok(v) { apply_mutation(doc, v); }
+        _ { fail #fmt("bad mutation record: %s", record) }
+    }
+}
+
+
+fn apply_mutation(doc : @document, msg_j : json::json) {
+    let msg = alt msg_j {
+            json::dict(x) { x }
+            _ { fail }
+        },
+        typ = json_uint(msg, "type"),
+        target = json_uint(msg, "target");
+
+    alt typ {
+        1u { // MUTATE VALUE
+            mutate_value(doc, target, json_field(msg, "data"));
+        }
+        2u { // MUTATE ATTR
+            mutate_attr(doc, target, json_field(msg, "name"),
+                        json_field(msg, "ns"), json_field(msg, "prefix"),
+                        json_field(msg, "value"));
+        }
+        3u { // REMOVE ATTR
+            remove_attr(doc, target, json_field(msg, "name"),
+                        json_field(msg, "ns"));
+        }
+        4u { // REMOVE
+            remove_node(doc, target);
+        }
+        5u { // MOVE
+            move_node(doc, target, json_uint(msg, "parent"),
+                      json_uint(msg, "index"));
+        }
+        6u { // INSERT
+            let nid = json_uint(msg, "nid"),
+                index = json_uint(msg, "index"),
+                child = msg.get("child");
+            build_subtree(doc, child, nid, target, @mut "");
+            insert_node(doc, target, index, nid);
+        }
+        _y {
+            log(error, _y);
+        }
+    }
+}
+
+
+fn mutate_value(doc : @document, target : uint, data : str) {
+    set_node(doc, target, alt get_node(doc, target) {
+        text(_, p) { text(data, p) }
+        comment(_, p) { comment(data, p) }
+        procinst(t, _, p) { procinst(t, data, p) }
+        n { log(error, ("mutate value of", target, n)); fail }
+    });
+    notify(doc, value_changed(target));
+}
+
+
+fn mutate_attr(doc : @document, target : uint, name : str, ns : str,
+               prefix : str, value : str) {
+    let e = alt get_node(doc, target) {
+            element(e) { e }
+            _ { fail }
+        },
+        found = false;
+    for a in *e.attr {
+        if str::eq(a.name, name) && str::eq(a.ns, ns) {
+            a.prefix = prefix;
+            a.value = value;
+            found = true;
+        }
+    }
+    if !found {
+        *e.attr += [{name: name, ns: ns,
+                     mut prefix: prefix, mut value: value}];
+    }
+    notify(doc, attr_changed(target, ns, name));
+}
+
+
+fn remove_attr(doc : @document, target : uint, name : str, ns : str) {
+    let e = alt get_node(doc, target) {
+        element(e) { e }
+        _ { fail }
+    };
+    *e.attr = vec::filter(copy *e.attr, {|a|
+        !(str::eq(a.name, name) && str::eq(a.ns, ns))
+    });
+    notify(doc, attr_removed(target, ns, name));
+}
+
+
+fn remove_node(doc : @document, target : uint) {
+    let parent = node_parent(get_node(doc, target));
+    remove_child(doc, parent, target);
+    remove_subtree(doc, target);
+    notify(doc, node_removed(target, parent));
+}
+
+
+fn move_node(doc : @document, target : uint, parent : uint, index : uint) {
+    let n = get_node(doc, target),
+        old_parent = node_parent(n);
+    remove_child(doc, old_parent, target);
+    set_node(doc, target, with_parent(n, parent));
+    insert_child(doc, parent, index, target);
+    notify(doc, node_moved(target, old_parent, parent));
+}
+
+
+// Links a subtree that has just been built at `nid` into its parent.
+fn insert_node(doc : @document, parent : uint, index : uint, nid : uint) {
+    insert_child(doc, parent, index, nid);
+    notify(doc, node_inserted(nid, parent));
+}
+
+
+/*
+ * The binary mutation record format.
+ *
+ * layout.js batches the records of one turn of the event loop, and
+ * jsrust_post_mutations encodes the batch as a sequence of records:
+ *
+ *   record  := type:u8 target:u32 fields
+ *   1 value := data:str
+ *   2 attr  := name:str ns:str value:str prefix:str
+ *   3 rmattr:= name:str ns:str
+ *   4 remove:=
+ *   5 move  := parent:u32 index:u32
+ *   6 insert:= index:u32 nid:u32 tree
+ *
+ *   tree    := 'T' data:str | 'C' data:str | 'P' target:str data:str
+ *            | 'D' name:str
+ *            | 'E' nscode:str tag:str nattrs:u32 attr* nkids:u32 tree*
+ *   attr    := nscode:str qname:str value:str
+ *   str     := length:u32 utf8-bytes
+ *
+ * Integers are little endian. Namespace codes are those of DOMSTR (see
+ * decode_ns); an attribute with an empty code was set without a
+ * namespace.
+ */
+
+type reader = {
+    bytes: [u8],
+    mut pos: uint
+};
+
+
+fn read_u8(r : reader) -> u8 {
+    if r.pos >= vec::len(r.bytes) { fail "truncated mutation record"; }
+    let b = r.bytes[r.pos];
+    r.pos += 1u;
+    ret b;
+}
+
+
+fn read_u32(r : reader) -> uint {
+    let b0 = read_u8(r) as uint, b1 = read_u8(r) as uint,
+        b2 = read_u8(r) as uint, b3 = read_u8(r) as uint;
+    ret b0 | (b1 << 8u) | (b2 << 16u) | (b3 << 24u);
+}
+
+
+fn read_str(r : reader) -> str {
+    let len = read_u32(r);
+    if r.pos + len > vec::len(r.bytes) { fail "truncated mutation record"; }
+    let s = str::from_bytes(vec::slice(r.bytes, r.pos, r.pos + len));
+    r.pos += len;
+    ret s;
+}
+
+
+fn read_subtree(doc : @document, r : reader, nid : uint, parent : uint,
+                last_custom : @mut str) -> uint {
+    alt read_u8(r) as char {
+        'T' { set_node(doc, nid, text(read_str(r), parent)); }
+        'C' { set_node(doc, nid, comment(read_str(r), parent)); }
+        'P' {
+            let target = read_str(r), data = read_str(r);
+            set_node(doc, nid, procinst(target, data, parent));
+        }
+        'D' { set_node(doc, nid, doctype(read_str(r), "", "", parent)); }
+        'E' {
+            let ns = decode_ns(read_str(r), last_custom),
+                tag = read_str(r),
+                attrs = @mut[],
+                nattrs = read_u32(r);
+            uint::range(0u, nattrs) {|_i|
+                let code = read_str(r), qname = read_str(r),
+                    value = read_str(r);
+                if str::is_empty(code) {
+                    *attrs += [{name: qname, ns: "", mut prefix: "",
+                                mut value: value}];
+                } else {
+                    let ans = decode_ns(code, last_custom),
+                        (prefix, local) = split_qname(qname);
+                    *attrs += [{name: local, ns: ans, mut prefix: prefix,
+                                mut value: value}];
+                }
+            }
+            let e = {
+                mut tag: tag,
+                mut ns: ns,
+                mut attr: attrs,
+                mut parent: parent,
+                mut child: @mut[]
+            };
+            set_node(doc, nid, element(e));
+
+            let next = nid + 1u, nkids = read_u32(r);
+            uint::range(0u, nkids) {|_i|
+                *e.child += [next];
+                next = read_subtree(doc, r, next, nid, last_custom);
+            }
+            ret next;
+        }
+        c { fail #fmt("bad node kind %c in mutation record", c); }
+    }
+    ret nid + 1u;
+}
+
+
+/* Applies a batch of binary mutation records, returning how many. */
+fn apply_binary(doc : @document, bytes : [u8]) -> uint {
+    let r = { bytes: bytes, mut pos: 0u }, count = 0u;
+    while r.pos < vec::len(r.bytes) {
+        let typ = read_u8(r) as uint, target = read_u32(r);
+        alt typ {
+            1u { mutate_value(doc, target, read_str(r)); }
+            2u {
+                let name = read_str(r), ns = read_str(r),
+                    value = read_str(r), prefix = read_str(r);
+                mutate_attr(doc, target, name, ns, prefix, value);
+            }
+            3u {
+                let name = read_str(r), ns = read_str(r);
+                remove_attr(doc, target, name, ns);
+            }
+            4u { remove_node(doc, target); }
+            5u {
+                let parent = read_u32(r), index = read_u32(r);
+                move_node(doc, target, parent, index);
+            }
+            6u {
+                let index = read_u32(r), nid = read_u32(r);
+                read_subtree(doc, r, nid, target, @mut "");
+                insert_node(doc, target, index, nid);
+            }
+            _ { fail #fmt("bad mutation record type %u", typ); }
+        }
+        count += 1u;
+    }
+    ret count;
+}
+
+
+// Compares the mirror with a serialization of the document made by
+// _mirrorSnapshot in layout.js, returning the differences found.
+fn check_mirror(doc : @document, snapshot : json::json) -> [str] {
+    let errs = @mut[];
+    diff_node(doc, 1u, snapshot, "", errs);
+    ret copy *errs;
+}
+
+
+fn snapshot_field(j : json::json, key : str) -> option::t<json::json> {
+    ret alt j {
+        json::dict(m) { m.find(key) }
+        _ { option::none }
+    };
+}
+
+
+fn snapshot_str(j : json::json, key : str) -> str {
+    ret alt snapshot_field(j, key) {
+        option::some(json::string(s)) { s }
+        _ { "" }
+    };
+}
+
+
+fn diff_str(path : str, what : str, mine : str, theirs : str,
+            errs : @mut[str]) {
+    if !str::eq(mine, theirs) {
+        *errs += [#fmt("%s: %s is \"%s\" in the mirror but \"%s\" in dom.js",
+                       path, what, mine, theirs)];
+    }
+}
+
+
+fn diff_node(doc : @document, nid : uint, j : json::json, path : str,
+             errs : @mut[str]) {
+    alt get_node(doc, nid) {
+        text(data, _) {
+            alt j {
+                json::string(s) { diff_str(path, "text", data, s, errs); }
+                _ { *errs += [#fmt("%s: text node in mirror only", path)]; }
+            }
+        }
+        comment(data, _) {
+            diff_str(path, "comment", data, snapshot_str(j, "comment"), errs);
+        }
+        procinst(target, data, _) {
+            diff_str(path, "pi target", target, snapshot_str(j, "pi"), errs);
+            diff_str(path, "pi data", data, snapshot_str(j, "data"), errs);
+        }
+        doctype(name, _, _, _) {
+            diff_str(path, "doctype", name, snapshot_str(j, "doctype"), errs);
+        }
+        element(e) {
+            diff_str(path, "tag", e.tag, snapshot_str(j, "tag"), errs);
+            diff_str(path, "namespace", e.ns, snapshot_str(j, "ns"), errs);
+
+            let attrs = alt snapshot_field(j, "attr") {
+                    option::some(json::list(l)) { copy *l }
+                    _ { [] }
+                },
+                kids = alt snapshot_field(j, "child") {
+                    option::some(json::list(l)) { copy *l }
+                    _ { [] }
+                };
+
+            if vec::len(attrs) != vec::len(*e.attr) {
+                *errs += [#fmt("%s: %u attributes in the mirror but %u in dom.js",
+                               path, vec::len(*e.attr), vec::len(attrs))];
+            } else {
+                let i = 0u;
+                for a in *e.attr {
+                    let apath = #fmt("%s/@%s", path, a.name);
+                    diff_str(apath, "name", a.name,
+                             snapshot_str(attrs[i], "a"), errs);
+                    diff_str(apath, "namespace", a.ns,
+                             snapshot_str(attrs[i], "ns"), errs);
+                    diff_str(apath, "value", a.value,
+                             snapshot_str(attrs[i], "v"), errs);
+                    i += 1u;
+                }
+            }
+
+            if vec::len(kids) != vec::len(*e.child) {
+                *errs += [#fmt("%s: %u children in the mirror but %u in dom.js",
+                               path, vec::len(*e.child), vec::len(kids))];
+            } else {
+                let i = 0u;
+                for child in *e.child {
+                    diff_node(doc, child, kids[i],
+                              #fmt("%s/%u", path, i), errs);
+                    i += 1u;
+                }
+            }
+        }
+        nonode {
+            *errs += [#fmt("%s: node %u is missing from the mirror",
+                           path, nid)];
+        }
+    }
+}
@@ -0,0 +1,87 @@
+/* Capability-restricted filesystem access for scripts. */
+
+use std;
+import std::io;
+import result::{ ok, err };
+
+export mode, read_only, read_write, policy, install, resolve, read, write;
+
+enum mode {
+    read_only,
+    read_write
+}
+
+type policy = {
+    // Scripts see this directory as "/" and cannot reach outside it.
+    root: str,
+    mode: mode,
+    // The largest file, in bytes, that may be read or written (0 for no
+    // limit).
+    max_bytes: uint
+};
+
+/* Applies the policy to jsrust_read, which runs without the host. */
+fn install(cx : js::context, p : policy) {
+    js::ext::set_file_policy(cx, p.root, p.max_bytes as u32);
+}
+
+/* Maps a script path to a host path, if the policy allows it. */
+fn resolve(cx : js::context, path : str) -> result::t<str, str> {
+    let host_path = js::ext::resolve_path(cx, path);
+    if str::is_empty(host_path) {
+        ret err(#fmt("Permission denied: %s is outside the script's root directory",
+                     path));
+    }
+    ret ok(host_path);
+}
+
+fn too_large(p : policy, path : str) -> str {
+    ret #fmt("%s is larger than the limit of %u bytes", path, p.max_bytes);
+}
+
+fn read(cx : js::context, p : policy, path : str) -> result::t<str, str> {
+    let host_path = alt resolve(cx, path) {
+        ok(x) { x }
+        err(e) { ret err(e); }
+    };
+    let rdr = alt io::file_reader(host_path) {
+        ok(x) { x }
+        err(e) { ret err(e); }
+    };
+
+    // Read one byte past the limit so oversized files are caught without
+    // reading them whole.
+    let bytes = if p.max_bytes == 0u {
+        rdr.read_whole_stream()
+    } else {
+        rdr.read_bytes(p.max_bytes + 1u)
+    };
+    if p.max_bytes != 0u && vec::len(bytes) > p.max_bytes {
+        ret err(too_large(p, path));
+    }
+    if !str::is_utf8(bytes) {
+        ret err(#fmt("%s is not UTF-8 text", path));
+    }
+    ret ok(str::from_bytes(bytes));
+}
+
+fn write(cx : js::context, p : policy, path : str, data : [u8])
+        -> result::t<(), str> {
+    alt p.mode {
+        read_only {
+            ret err(#fmt("Permission denied: %s is read only", p.root));
+        }
+        read_write { }
+    }
+    if p.max_bytes != 0u && vec::len(data) > p.max_bytes {
+        ret err(too_large(p, path));
+    }
+    let host_path = alt resolve(cx, path) {
+        ok(x) { x }
+        err(e) { ret err(e); }
+    };
+    alt io::file_writer(host_path, [io::create, io::truncate]) {
+        ok(w) { w.write(data); ret ok(()); }
+        err(e) { ret err(e); }
+    }
+}
@@ -0,0 +1,71 @@
+// Exercises the filesystem policy; see "make check-fs", which runs this
+// with --fs-root=fsroot --fs-writable. fsroot/up links to "..", and
+// fsroot/out.txt to a file outside the root.
+
+function check(name, ok, detail) {
+    print((ok ? "ok " : "FAIL ") + name + (ok ? "" : ": " + detail));
+}
+
+function denied(path) {
+    try {
+        jsrust_read(path);
+        return "read";
+    } catch (e) {
+        return /^Permission denied/.test(e.message) ? "denied" : e.message;
+    }
+}
+
+check("read under the root", jsrust_read("/sub/inside.txt") === "inside\n", "");
+check(".. cannot leave the root", denied("/../Makefile") === "denied",
+      denied("/../Makefile"));
+check("a linked file cannot leave the root", denied("/out.txt") === "denied",
+      denied("/out.txt"));
+check("a linked directory cannot leave the root",
+      denied("/up/Makefile") === "denied", denied("/up/Makefile"));
+
+// One after another, like httptest.js.
+var steps = [
+    function(next) {
+        readFile("/out.txt", function(err, data) {
+            check("async read through a link", err && /^Permission denied/.test(err.message),
+                  err ? err.message : data);
+            next();
+        });
+    },
+    function(next) {
+        writeFile("/up/escaped.txt", "FAIL escaped", function(err) {
+            check("write through a linked directory",
+                  err && /^Permission denied/.test(err.message),
+                  err ? err.message : "written");
+            next();
+        });
+    },
+    function(next) {
+        writeFile("/sub/new.txt", "new file", function(err) {
+            check("write a new file", !err, err && err.message);
+            readFile("/sub/new.txt", function(err, data) {
+                check("read it back", data === "new file", err ? err.message : data);
+                next();
+            });
+        });
+    },
+    function(next) {
+        // "make check-fs" compares the file with the bytes a, NUL, b.
+        writeFile("/sub/nul.txt", "a\u0000b", function(err) {
+            check("write data holding NUL", !err, err && err.message);
+            next();
+        });
+    },
+    function(next) {
+        readFile("/sub/binary.bin", function(err, data) {
+            check("binary file is an error", err && /not UTF-8/.test(err.message),
+                  err ? err.message : data);
+            next();
+        });
+    }
+];
+
+(function run() {
+    var step = steps.shift();
+    if (step) step(run);
+})();
@@ -0,0 +1,155 @@
+/*
+ * Serializes the DOM mirror as HTML.
+ *
+ * The output follows dom.js's Node.serialize, which implements innerHTML,
+ * so that a mirror can be compared with the document it mirrors.
+ */
+
+export serialize_node, serialize_children, serialize_document;
+
+const XML_NAMESPACE : str = "http://www.w3.org/XML/1998/namespace";
+const XMLNS_NAMESPACE : str = "http://www.w3.org/2000/xmlns/";
+const XLINK_NAMESPACE : str = "http://www.w3.org/1999/xlink";
+const MATHML_NAMESPACE : str = "http://www.w3.org/1998/Math/MathML";
+const SVG_NAMESPACE : str = "http://www.w3.org/2000/svg";
+
+// Elements that cannot have children and have no end tag.
+const VOID_ELEMENTS : [str] = [
+    "area", "base", "basefont", "bgsound", "br", "col", "command", "embed",
+    "frame", "hr", "img", "input", "keygen", "link", "meta", "param",
+    "source", "track", "wbr"
+];
+
+// Elements whose text children are written without escaping.
+const RAW_TEXT_ELEMENTS : [str] = [
+    "style", "script", "xmp", "iframe", "noembed", "noframes", "plaintext",
+    "noscript"
+];
+
+
+fn escape_text(s : str) -> str {
+    let out = "";
+    for c in str::chars(s) {
+        alt c {
+            '&' { out += "&amp;"; }
+            '<' { out += "&lt;"; }
+            '>' { out += "&gt;"; }
+            '\u00a0' { out += "&nbsp;"; }
+            _ { str::push_char(out, c); }
+        }
+    }
+    ret out;
+}
+
+
+fn escape_attr(s : str) -> str {
+    let out = "";
+    for c in str::chars(s) {
+        alt c {
+            '&' { out += "&amp;"; }
+            '"' { out += "&quot;"; }
+            '\u00a0' { out += "&nbsp;"; }
+            _ { str::push_char(out, c); }
+        }
+    }
+    ret out;
+}
+
+
+fn attr_name(a : dom::attr) -> str {
+    if str::is_empty(a.ns) { ret a.name; }
+    if str::eq(a.ns, XML_NAMESPACE) { ret "xml:" + a.name; }
+    if str::eq(a.ns, XLINK_NAMESPACE) { ret "xlink:" + a.name; }
+    if str::eq(a.ns, XMLNS_NAMESPACE) {
+        ret if str::eq(a.name, "xmlns") { "xmlns" } else { "xmlns:" + a.name };
+    }
+    ret if str::is_empty(a.prefix) { a.name } else { a.prefix + ":" + a.name };
+}
+
+
+fn tag_name(e : dom::element) -> str {
+    if str::eq(e.ns, dom::HTML_NAMESPACE) || str::eq(e.ns, SVG_NAMESPACE) ||
+       str::eq(e.ns, MATHML_NAMESPACE) {
+        let (_prefix, local) = dom::split_qname(e.tag);
+        ret local;
+    }
+    ret e.tag;
+}
+
+
+fn is_one_of(tag : str, tags : [str]) -> bool {
+    ret vec::any(tags, {|t| str::eq(t, tag) });
+}
+
+
+fn serialize_into(doc : @dom::document, nid : uint, raw_text : bool,
+                  out : @mut str) {
+    alt dom::get_node(doc, nid) {
+        dom::text(data, _) {
+            *out += if raw_text { data } else { escape_text(data) };
+        }
+        dom::comment(data, _) {
+            *out += "<!--" + data + "-->";
+        }
+        dom::procinst(target, data, _) {
+            *out += "<?" + target + " " + data + ">";
+        }
+        dom::doctype(name, _, _, _) {
+            *out += "<!DOCTYPE " + name + ">";
+        }
+        dom::element(e) {
+            let html = str::eq(e.ns, dom::HTML_NAMESPACE),
+                tag = tag_name(e);
+
+            *out += "<" + tag;
+            for a in *e.attr {
+                *out += " " + attr_name(a) + "=\"" + escape_attr(a.value) + "\"";
+            }
+            *out += ">";
+
+            if html && is_one_of(tag, VOID_ELEMENTS) {
+                ret;
+            }
+            if html && is_one_of(tag, ["pre", "textarea", "listing"]) {
+                *out += "\n";
+            }
+            let raw = html && is_one_of(tag, RAW_TEXT_ELEMENTS);
+            for child in *e.child {
+                serialize_into(doc, child, raw, out);
+            }
+            *out += "</" + tag + ">";
+        }
+        dom::nonode { }
+    }
+}
+
+
+/* The markup for a node and its descendants, like outerHTML. */
+fn serialize_node(doc : @dom::document, nid : uint) -> str {
+    let out = @mut "";
+    serialize_into(doc, nid, false, out);
+    ret *out;
+}
+
+
+/* The markup for the children of a node, like innerHTML. */
+fn serialize_children(doc : @dom::document, nid : uint) -> str {
+    let out = @mut "";
+    alt dom::get_node(doc, nid) {
+        dom::element(e) {
+            let raw = str::eq(e.ns, dom::HTML_NAMESPACE) &&
+                is_one_of(tag_name(e), RAW_TEXT_ELEMENTS);
+            for child in *e.child {
+                serialize_into(doc, child, raw, out);
+            }
+        }
+        _ { }
+    }
+    ret *out;
+}
+
+
+/* The whole document, doctype included, like document.innerHTML. */
+fn serialize_document(doc : @dom::document) -> str {
+    ret serialize_children(doc, 1u);
+}
@@ -0,0 +1,746 @@
+/*
+ * An HTTP/1.1 client for the host, used by XMLHttpRequest.
+ *
+ * Requests carry a Host header and are sent over connections, plain for
+ * http and TLS for https, that are kept alive and reused while the
+ * server allows it. Responses are parsed as they arrive and reported as
+ * events: the head, then pieces of the body as they are decoded, then
+ * the end. Bodies may be delimited by
+ * Content-Length, chunked transfer coding or the end of the connection.
+ * Redirects (301, 302, 303, 307 and 308) are followed without being
+ * reported. Cookies are sent from and stored in the client's jar,
+ * redirects included.
+ */
+
+use std;
+import std::{ json, map };
+import comm::{ port, chan, send, recv };
+import result::{ ok, err };
+
+export request, client, new_client, close_client;
+export request_from_json, fetch, header, is_token;
+export event, head, data, end, parser, new_parser, feed, finish;
+export head_to_json, decoder, new_decoder, decode, flush, from_utf8_lossy;
+
+const MAX_REDIRECTS : uint = 20u;
+
+// How often a waiting read checks whether the request was cancelled.
+const POLL_MS : uint = 100u;
+
+// The most idle connections kept to one server.
+const MAX_IDLE : uint = 4u;
+
+type request = {
+    method: str,
+    url: str,
+    headers: [(str, str)],
+    body: str
+};
+
+enum event {
+    head(uint, str, [(str, str)]),      // status, reason, headers
+    data([u8]),                         // decoded body bytes
+    end,
+}
+
+enum pool_msg {
+    checkout(str, chan<option<net::socket>>),
+    checkin(str, net::socket),
+    shutdown,
+}
+
+// A pool of idle keep-alive connections, keyed by "scheme://host:port",
+// how to check certificates for https, and where cookies are kept. One is
+// made per actor and shared by its requests.
+type client = {pool: chan<pool_msg>, tls: net::tls_config, cookies: cookie::jar};
+
+
+fn new_client(tls : net::tls_config, cookies : cookie::jar) -> client {
+    let pool = task::spawn_listener {|msgs|
+        let idle : map::hashmap<str, [net::socket]> = map::new_str_hash();
+        while true {
+            alt recv(msgs) {
+                checkout(key, reply) {
+                    let socks = alt idle.find(key) { option::some(s) { s } option::none { [] } };
+                    if vec::len(socks) == 0u {
+                        send(reply, option::none);
+                    } else {
+                        idle.insert(key, vec::slice(socks, 0u, vec::len(socks) - 1u));
+                        send(reply, option::some(vec::last_total(socks)));
+                    }
+                }
+                checkin(key, sock) {
+                    let socks = alt idle.find(key) { option::some(s) { s } option::none { [] } };
+                    if vec::len(socks) < MAX_IDLE {
+                        idle.insert(key, socks + [sock]);
+                    } else {
+                        net::close(sock);
+                    }
+                }
+                shutdown {
+                    idle.values {|socks| for s in socks { net::close(s); } };
+                    break;
+                }
+            }
+        }
+    };
+    ret {pool: pool, tls: tls, cookies: cookies};
+}
+
+
+/* Closes the client's idle connections. Its jar is left open. */
+fn close_client(c : client) {
+    send(c.pool, shutdown);
+}
+
+
+/* The first value of a header, by case-insensitive name. */
+fn header(headers : [(str, str)], name : str) -> option<str> {
+    let name = str::to_lower(name);
+    for h in headers {
+        let (k, v) = h;
+        if str::eq(str::to_lower(k), name) { ret option::some(v); }
+    }
+    ret option::none;
+}
+
+
+/*
+ * Reads a request posted by jsrust_http_request: a JSON object with
+ * method, url, headers (a list of [name, value] pairs) and body.
+ */
+fn request_from_json(s : str) -> result::t<request, str> {
+    let m = alt json::from_str(s) {
+        result::ok(json::dict(m)) { m }
+        _ { ret err("malformed request: " + s); }
+    };
+    fn string(m : map::hashmap<str, json::json>, key : str) -> str {
+        ret alt m.find(key) {
+            option::some(json::string(v)) { v }
+            _ { "" }
+        };
+    }
+    let headers = [];
+    alt m.find("headers") {
+        option::some(json::list(l)) {
+            for h in *l {
+                alt h {
+                    json::list(pair) {
+                        if vec::len(*pair) == 2u {
+                            alt (pair[0], pair[1]) {
+                                (json::string(k), json::string(v)) { headers += [(k, v)]; }
+                                _ { }
+                            }
+                        }
+                    }
+                    _ { }
+                }
+            }
+        }
+        _ { }
+    }
+    let method = str::to_upper(string(m, "method"));
+    let method = if str::is_empty(method) { "GET" } else { method };
+    // They go onto the wire as they are, so a CR or LF would let them
+    // add headers or a second request.
+    if !is_token(method) { ret err("invalid method: " + method); }
+    for h in headers {
+        let (k, v) = h;
+        if !is_token(k) { ret err("invalid header name: " + k); }
+        if !is_field_value(v) { ret err("invalid value for header " + k); }
+    }
+    ret ok({method: method,
+            url: string(m, "url"),
+            headers: headers,
+            body: string(m, "body")});
+}
+
+
+// An HTTP token, such as a method or a header name.
+fn is_token(s : str) -> bool {
+    if str::is_empty(s) { ret false; }
+    for b in str::bytes(s) {
+        let c = b as char;
+        if !(char::is_alphanumeric(c) && b < 0x80u8) &&
+           str::index("!#$%&'*+-.^_`|~", b) < 0 {
+            ret false;
+        }
+    }
+    ret true;
+}
+
+
+fn is_field_value(s : str) -> bool {
+    for b in str::bytes(s) {
+        if b == '\r' as u8 || b == '\n' as u8 || b == 0u8 { ret false; }
+    }
+    ret true;
+}
+
+
+// An absolute http or https URL with a host.
+fn split_url(s : str) -> result::t<url::url, str> {
+    let u = alt url::parse(s, option::none) { ok(u) { u } err(e) { ret err(e); } };
+    if !str::eq(u.scheme, "http") && !str::eq(u.scheme, "https") {
+        ret err("unsupported scheme: " + u.scheme);
+    }
+    ret ok(u);
+}
+
+
+fn host_of(u : url::url) -> str {
+    ret alt u.host { option::some(h) { h } option::none { "" } };
+}
+
+
+// Resolves a Location header against the URL it came from.
+fn resolve_location(base : str, loc : str) -> str {
+    ret alt url::parse(base, option::none) {
+        ok(b) {
+            alt url::parse(loc, option::some(b)) {
+                ok(u) { url::to_str(u) }
+                err(_) { loc }
+            }
+        }
+        err(_) { loc }
+    };
+}
+
+
+enum state {
+    status_line,
+    header_lines,
+    fixed(uint),            // body bytes left
+    chunk_size,
+    chunk_data(uint),       // bytes left in this chunk
+    chunk_end,
+    trailers,
+    until_close,
+    finished,
+}
+
+/* An incremental parser for one response. */
+type parser = {
+    method: str,
+    mut state: state,
+    mut buf: [u8],
+    mut version: str,
+    mut status: uint,
+    mut reason: str,
+    mut headers: [(str, str)],
+    // Whether the connection can carry another request afterwards.
+    mut keep_alive: bool
+};
+
+
+fn new_parser(method : str) -> parser {
+    ret {method: method, mut state: status_line, mut buf: [],
+         mut version: "", mut status: 0u, mut reason: "", mut headers: [],
+         mut keep_alive: false};
+}
+
+
+// A line without its CR LF (or bare LF), if a whole one has arrived.
+fn take_line(p : parser) -> option<str> {
+    let i = 0u, n = vec::len(p.buf);
+    while i < n {
+        if p.buf[i] == '\n' as u8 {
+            let end = if i > 0u && p.buf[i - 1u] == '\r' as u8 { i - 1u } else { i };
+            let line = from_utf8_lossy(vec::slice(p.buf, 0u, end));
+            p.buf = vec::slice(p.buf, i + 1u, n);
+            ret option::some(line);
+        }
+        i += 1u;
+    }
+    ret option::none;
+}
+
+
+// Up to `max` bytes of what has arrived.
+fn take_bytes(p : parser, max : uint) -> [u8] {
+    let n = vec::len(p.buf), k = if max < n { max } else { n };
+    let out = vec::slice(p.buf, 0u, k);
+    p.buf = vec::slice(p.buf, k, n);
+    ret out;
+}
+
+
+fn hex_value(s : str) -> option<uint> {
+    let n = 0u, any = false;
+    for c in str::bytes(s) {
+        let d = if c >= '0' as u8 && c <= '9' as u8 { c - ('0' as u8) }
+            else if c >= 'a' as u8 && c <= 'f' as u8 { c - ('a' as u8) + 10u8 }
+            else if c >= 'A' as u8 && c <= 'F' as u8 { c - ('A' as u8) + 10u8 }
+            else { break; };
+        n = n * 16u + (d as uint);
+        any = true;
+    }
+    ret if any { option::some(n) } else { option::none };
+}
+
+
+fn parse_status_line(p : parser, line : str) -> result::t<(), str> {
+    let parts = str::splitn_char(line, ' ', 2u);
+    if vec::len(parts) < 2u || !str::starts_with(parts[0], "HTTP/") {
+        ret err("bad status line: " + line);
+    }
+    p.status = alt uint::from_str(parts[1]) {
+        option::some(s) { s }
+        option::none { ret err("bad status line: " + line); }
+    };
+    p.version = parts[0];
+    p.reason = if vec::len(parts) > 2u { parts[2] } else { "" };
+    p.headers = [];
+    ret ok(());
+}
+
+
+fn add_header_line(p : parser, l : str) {
+    if (l[0] == ' ' as u8 || l[0] == '\t' as u8) && vec::len(p.headers) > 0u {
+        // A folded continuation of the previous header.
+        let (k, v) = vec::last_total(p.headers);
+        p.headers = vec::slice(p.headers, 0u, vec::len(p.headers) - 1u) +
+            [(k, v + " " + str::trim(l))];
+        ret;
+    }
+    alt str::index(l, ':' as u8) {
+        -1 { }
+        i {
+            p.headers += [(str::trim(str::slice(l, 0u, i as uint)),
+                           str::trim(str::slice(l, i as uint + 1u, str::len(l))))];
+        }
+    }
+}
+
+
+// Chooses how the body is delimited once the head is complete.
+fn start_body(p : parser) -> state {
+    let conn = alt header(p.headers, "connection") {
+        option::some(v) { str::to_lower(v) }
+        option::none { "" }
+    };
+    let persistent = str::find(conn, "close") < 0 &&
+        (str::eq(p.version, "HTTP/1.1") || str::find(conn, "keep-alive") >= 0);
+    p.keep_alive = persistent;
+
+    if str::eq(p.method, "HEAD") || p.status == 204u || p.status == 304u {
+        ret finished;
+    }
+    let chunked = alt header(p.headers, "transfer-encoding") {
+        option::some(te) { str::find(str::to_lower(te), "chunked") >= 0 }
+        option::none { false }
+    };
+    if chunked { ret chunk_size; }
+    alt header(p.headers, "content-length") {
+        option::some(v) {
+            alt uint::from_str(str::trim(v)) {
+                option::some(0u) { ret finished; }
+                option::some(n) { ret fixed(n); }
+                option::none { }
+            }
+        }
+        option::none { }
+    }
+    p.keep_alive = false;
+    ret until_close;
+}
+
+
+/* Parses what has arrived so far; returns the events it completes. */
+fn feed(p : parser, bytes : [u8]) -> result::t<[event], str> {
+    p.buf += bytes;
+    let events = [];
+    while true {
+        alt p.state {
+            status_line {
+                alt take_line(p) {
+                    option::some(l) {
+                        alt parse_status_line(p, l) {
+                            ok(_) { p.state = header_lines; }
+                            err(e) { ret err(e); }
+                        }
+                    }
+                    option::none { break; }
+                }
+            }
+            header_lines {
+                alt take_line(p) {
+                    option::some(l) {
+                        if !str::is_empty(l) {
+                            add_header_line(p, l);
+                        } else if p.status >= 100u && p.status < 200u {
+                            // An interim response such as 100 Continue.
+                            p.state = status_line;
+                        } else {
+                            events += [head(p.status, p.reason, p.headers)];
+                            p.state = start_body(p);
+                            alt p.state { finished { events += [end]; } _ { } }
+                        }
+                    }
+                    option::none { break; }
+                }
+            }
+            fixed(left) {
+                let d = take_bytes(p, left);
+                if vec::len(d) == 0u { break; }
+                events += [data(d)];
+                if vec::len(d) == left {
+                    p.state = finished;
+                    events += [end];
+                } else {
+                    p.state = fixed(left - vec::len(d));
+                }
+            }
+            chunk_size {
+                alt take_line(p) {
+                    option::some(l) {
+                        alt hex_value(str::trim(l)) {
+                            option::some(0u) { p.state = trailers; }
+                            option::some(n) { p.state = chunk_data(n); }
+                            option::none { ret err("bad chunk size: " + l); }
+                        }
+                    }
+                    option::none { break; }
+                }
+            }
+            chunk_data(left) {
+                let d = take_bytes(p, left);
+                if vec::len(d) == 0u { break; }
+                events += [data(d)];
+                p.state = if vec::len(d) == left {
+                    chunk_end
+                } else {
+                    chunk_data(left - vec::len(d))
+                };
+            }
+            chunk_end {
+                alt take_line(p) {
+                    option::some(_) { p.state = chunk_size; }
+                    option::none { break; }
+                }
+            }
+            trailers {
+                // Trailers are dropped.
+                alt take_line(p) {
+                    option::some(l) {
+                        if str::is_empty(l) {
+                            p.state = finished;
+                            events += [end];
+                        }
+                    }
+                    option::none { break; }
+                }
+            }
+            until_close {
+                let d = take_bytes(p, vec::len(p.buf));
+                if vec::len(d) > 0u { events += [data(d)]; }
+                break;
+            }
+            finished {
+                // Anything more is not ours; don't reuse the connection.
+                if vec::len(p.buf) > 0u { p.keep_alive = false; }
+                break;
+            }
+        }
+    }
+    ret ok(events);
+}
+
+
+/* Tells the parser the connection has closed. */
+fn finish(p : parser) -> result::t<[event], str> {
+    ret alt p.state {
+        until_close { p.state = finished; ok([end]) }
+        finished { ok([]) }
+        _ { err("connection closed before the response was complete") }
+    };
+}
+
+
+fn is_finished(p : parser) -> bool {
+    ret alt p.state { finished { true } _ { false } };
+}
+
+
+/*
+ * The head of a response as JSON for XMLHttpRequest: status, statusText,
+ * headers as a list of [name, value] pairs, and the final url.
+ */
+fn head_to_json(status : uint, reason : str, headers : [(str, str)],
+                url : str) -> str {
+    fn q(s : str) -> str { ret json::to_str(json::string(s)); }
+    // Scripts never see Set-Cookie.
+    let headers = vec::filter(headers, {|h|
+        let (k, _v) = h;
+        !str::eq(str::to_lower(k), "set-cookie")
+    });
+    let hs = vec::map(headers, {|h|
+        let (k, v) = h;
+        "[" + q(k) + "," + q(v) + "]"
+    });
+    ret #fmt("{\"status\":%u,\"statusText\":%s,\"headers\":[%s],\"url\":%s}",
+             status, q(reason), str::connect(hs, ","), q(url));
+}
+
+
+/*
+ * Turns body bytes into text piece by piece, holding back a UTF-8
+ * sequence split between pieces until the rest arrives.
+ */
+type decoder = {mut pending: [u8]};
+
+
+fn new_decoder() -> decoder {
+    ret {mut pending: []};
+}
+
+
+fn decode(d : decoder, bytes : [u8]) -> str {
+    let all = d.pending + bytes, n = vec::len(all);
+    // Find where the last, possibly incomplete, sequence starts.
+    let start = n, back = 0u;
+    while start > 0u && back < 4u {
+        start -= 1u;
+        back += 1u;
+        let b = all[start];
+        if b & 0xc0u8 != 0x80u8 {
+            let need = if b & 0x80u8 == 0u8 { 1u }
+                else if b & 0xe0u8 == 0xc0u8 { 2u }
+                else if b & 0xf0u8 == 0xe0u8 { 3u }
+                else { 4u };
+            if n - start >= need { start = n; }
+            break;
+        }
+    }
+    d.pending = vec::slice(all, start, n);
+    ret from_utf8_lossy(vec::slice(all, 0u, start));
+}
+
+
+// What was held back when the body ends, which can only be a cut-off
+// sequence.
+fn flush(d : decoder) -> str {
+    let rest = d.pending;
+    d.pending = [];
+    ret from_utf8_lossy(rest);
+}
+
+
+/*
+ * Text from bytes off the network, with each ill-formed UTF-8 sequence
+ * replaced by U+FFFD rather than failing.
+ */
+fn from_utf8_lossy(bytes : [u8]) -> str {
+    let out = [], i = 0u, n = vec::len(bytes);
+    while i < n {
+        let b = bytes[i];
+        if b < 0x80u8 {
+            out += [b];
+            i += 1u;
+            cont;
+        }
+        // How many continuation bytes follow, and the range the first of
+        // them must fall in to rule out overlong forms and surrogates.
+        let (more, lo, hi) =
+            if b >= 0xc2u8 && b <= 0xdfu8 { (1u, 0x80u8, 0xbfu8) }
+            else if b == 0xe0u8 { (2u, 0xa0u8, 0xbfu8) }
+            else if b == 0xedu8 { (2u, 0x80u8, 0x9fu8) }
+            else if b >= 0xe1u8 && b <= 0xefu8 { (2u, 0x80u8, 0xbfu8) }
+            else if b == 0xf0u8 { (3u, 0x90u8, 0xbfu8) }
+            else if b >= 0xf1u8 && b <= 0xf3u8 { (3u, 0x80u8, 0xbfu8) }
+            else if b == 0xf4u8 { (3u, 0x80u8, 0x8fu8) }
+            else { (0u, 0u8, 0u8) };
+        let j = i + 1u;
+        if more > 0u {
+            while j < n && j <= i + more {
+                let c = bytes[j];
+                let ok = if j == i + 1u { c >= lo && c <= hi }
+                    else { c & 0xc0u8 == 0x80u8 };
+                if !ok { break; }
+                j += 1u;
+            }
+        }
+        if more > 0u && j == i + more + 1u {
+            out += vec::slice(bytes, i, j);
+        } else {
+            out += [0xefu8, 0xbfu8, 0xbdu8];
+        }
+        i = j;
+    }
+    ret str::from_bytes(out);
+}
+
+
+fn serialize_request(req : request, u : url::url, cookies : str) -> [u8] {
+    // The port is left out when it is the scheme's default.
+    let host = alt u.port {
+        option::some(p) { #fmt("%s:%u", host_of(u), p) }
+        option::none { host_of(u) }
+    };
+    let out = #fmt("%s %s HTTP/1.1\r\nHost: %s\r\n", req.method,
+                   url::request_target(u), host);
+    for h in req.headers {
+        let (k, v) = h, lk = str::to_lower(k);
+        // The client owns these.
+        if str::eq(lk, "host") || str::eq(lk, "content-length") ||
+           str::eq(lk, "connection") || str::eq(lk, "transfer-encoding") ||
+           str::eq(lk, "cookie") {
+            cont;
+        }
+        out += #fmt("%s: %s\r\n", k, v);
+    }
+    if !str::is_empty(cookies) {
+        out += #fmt("Cookie: %s\r\n", cookies);
+    }
+    if !str::is_empty(req.body) || str::eq(req.method, "POST") ||
+       str::eq(req.method, "PUT") {
+        out += #fmt("Content-Length: %u\r\n", str::len(req.body));
+    }
+    out += "\r\n" + req.body;
+    ret str::bytes(out);
+}
+
+
+fn checkout_socket(c : client, key : str) -> option<net::socket> {
+    let p = port();
+    send(c.pool, checkout(key, chan(p)));
+    ret recv(p);
+}
+
+
+// Sends a request and reports its response. A reused connection that
+// fails before any answer is retried on a new one.
+fn send_once(c : client, req : request, cancelled : fn() -> bool,
+             sink : fn(event)) -> result::t<(), str> {
+    let u = alt split_url(req.url) { ok(u) { u } err(e) { ret err(e); } },
+        host = host_of(u),
+        port = url::port_or_default(u),
+        key = url::origin(u),
+        bytes = serialize_request(req, u, cookie::cookie_header(c.cookies, u));
+
+    let idle = checkout_socket(c, key);
+    while true {
+        let (sock, reused) = alt idle {
+            option::some(s) { (s, true) }
+            option::none {
+                let s = if str::eq(u.scheme, "https") {
+                    net::connect_tls(host, port, c.tls)
+                } else {
+                    net::connect(host, port)
+                };
+                alt s {
+                    ok(s) { (s, false) }
+                    err(e) { ret err(e); }
+                }
+            }
+        };
+        idle = option::none;
+        let p = new_parser(req.method), received = 0u;
+        let result = alt net::write(sock, bytes) {
+            ok(_) {
+                let r = ok(());
+                while !is_finished(p) {
+                    let events = alt net::read(sock, 32768u, POLL_MS) {
+                        ok(option::some(d)) {
+                            received += vec::len(d);
+                            if vec::len(d) == 0u { finish(p) } else { feed(p, d) }
+                        }
+                        ok(option::none) {
+                            if cancelled() { r = err("aborted"); break; }
+                            ok([])
+                        }
+                        err(e) { err(e) }
+                    };
+                    alt events {
+                        ok(evs) {
+                            for e in evs {
+                                alt e {
+                                    head(_, _, headers) {
+                                        for h in headers {
+                                            let (k, v) = h;
+                                            if str::eq(str::to_lower(k), "set-cookie") {
+                                                cookie::store(c.cookies, u, v);
+                                            }
+                                        }
+                                    }
+                                    _ { }
+                                }
+                                sink(e);
+                            }
+                        }
+                        err(e) { r = err(e); break; }
+                    }
+                }
+                r
+            }
+            err(e) { err(e) }
+        };
+        alt result {
+            ok(_) {
+                if p.keep_alive {
+                    send(c.pool, checkin(key, sock));
+                } else {
+                    net::close(sock);
+                }
+                ret ok(());
+            }
+            err(e) {
+                net::close(sock);
+                if !(reused && received == 0u) || cancelled() { ret err(e); }
+            }
+        }
+    }
+    fail;
+}
+
+
+/*
+ * Sends a request, follows any redirects and passes the events of the
+ * final response to `sink`, along with the URL it came from. Blocks on
+ * the network, but gives up soon after `cancelled` says so;
+ * the connection is then closed rather than reused.
+ */
+fn fetch(c : client, req : request, cancelled : fn() -> bool,
+         sink : fn(str, event)) -> result::t<(), str> {
+    let req = req, redirects = 0u;
+    while true {
+        if cancelled() { ret err("aborted"); }
+        let location = "", status = 0u;
+        let r = send_once(c, req, cancelled) {|e|
+            alt e {
+                head(st, _, headers) {
+                    status = st;
+                    let redirect = st == 301u || st == 302u || st == 303u ||
+                        st == 307u || st == 308u;
+                    location = if redirect {
+                        alt header(headers, "location") {
+                            option::some(l) { l }
+                            option::none { "" }
+                        }
+                    } else { "" };
+                }
+                _ { }
+            }
+            // Redirect responses are not reported.
+            if str::is_empty(location) { sink(req.url, e); }
+        };
+        alt r { ok(_) { } err(e) { ret err(e); } }
+        if str::is_empty(location) { ret ok(()); }
+        if redirects == MAX_REDIRECTS {
+            ret err("too many redirects from " + req.url);
+        }
+        redirects += 1u;
+
+        // 307 and 308 repeat the request as it was; the others become a
+        // GET, except that 301 and 302 keep HEAD.
+        let keep = status == 307u || status == 308u ||
+            (status != 303u && str::eq(req.method, "HEAD"));
+        req = if keep {
+            {url: resolve_location(req.url, location) with req}
+        } else {
+            {method: "GET", url: resolve_location(req.url, location),
+             body: "" with req}
+        };
+    }
+    fail;
+}
@@ -0,0 +1,199 @@
+#!/usr/bin/env python
+# A local HTTP server for httptest.js. It answers with each of the ways
+# HTTP/1.1 can delimit a body, redirects, and echoes what it was sent,
+# over HTTP and over WebSocket.
+#
+#   python httpserver.py [port [certfile keyfile]]
+#
+# Given a certificate and its key, it serves HTTPS instead.
+
+import base64
+import hashlib
+import socket
+import ssl
+import struct
+import sys
+import time
+
+try:
+    from http.server import BaseHTTPRequestHandler, HTTPServer
+    from socketserver import ThreadingMixIn
+except ImportError:
+    from BaseHTTPServer import BaseHTTPRequestHandler, HTTPServer
+    from SocketServer import ThreadingMixIn
+
+connections = set()
+
+
+class Handler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def log_message(self, *args):
+        pass
+
+    def reply(self, status, body, headers=()):
+        body = body.encode("utf-8")
+        self.send_response(status)
+        for k, v in headers:
+            self.send_header(k, v)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def handle_any(self):
+        connections.add(self.client_address)
+        if self.path.startswith("/ws/"):
+            self.websocket()
+            return
+        length = int(self.headers.get("Content-Length") or 0)
+        body = self.rfile.read(length).decode("utf-8") if length else ""
+
+        if self.path == "/plain":
+            self.reply(200, "plain body", [("X-Test", "yes")])
+        elif self.path == "/chunked":
+            self.send_response(200)
+            self.send_header("Transfer-Encoding", "chunked")
+            self.end_headers()
+            for part in ["chun", "ked ", "body"]:
+                self.wfile.write(("%x\r\n%s\r\n" % (len(part), part)).encode())
+            self.wfile.write(b"0\r\n\r\n")
+        elif self.path == "/utf8":
+            # A sequence split between chunks, a byte that is never valid
+            # and a sequence cut off by the end of the body.
+            self.wfile.write(b"HTTP/1.1 200 OK\r\nX-Name: caf\xc3\xa9\r\n"
+                             b"Transfer-Encoding: chunked\r\n\r\n")
+            for part in [b"h\xc3", b"\xa9llo \xff ", b"\xe2\x82"]:
+                self.wfile.write(b"%x\r\n%s\r\n" % (len(part), part))
+            self.wfile.write(b"0\r\n\r\n")
+        elif self.path == "/close":
+            self.send_response(200)
+            self.send_header("Connection", "close")
+            self.end_headers()
+            self.wfile.write(b"body until close")
+            self.close_connection = True
+        elif self.path.startswith("/redirect/"):
+            code = int(self.path[len("/redirect/"):])
+            self.reply(code, "", [("Location", "/echo")])
+        elif self.path == "/echo":
+            self.reply(200, "%s %s host=%s" % (self.command, body,
+                                               self.headers.get("Host")))
+        elif self.path == "/slow":
+            # Half the body now and half after a pause, for timeouts and
+            # aborts.
+            self.send_response(200)
+            self.send_header("Content-Length", "10")
+            self.end_headers()
+            self.wfile.write(b"slow ")
+            self.wfile.flush()
+            time.sleep(2)
+            self.wfile.write(b"body.")
+        elif self.path == "/cookies/set":
+            self.reply(200, "", [
+                ("Set-Cookie", "a=1; Path=/"),
+                ("Set-Cookie", "h=2; Path=/; HttpOnly"),
+                ("Set-Cookie", "s=3; Path=/; Secure"),
+                ("Set-Cookie", "other=4; Domain=example.com"),
+                ("Set-Cookie", "deep=5; Path=/cookies/deep"),
+                ("Set-Cookie", "gone=6; Path=/; Max-Age=60")])
+        elif self.path == "/cookies/expire":
+            self.reply(200, "", [
+                ("Set-Cookie", "gone=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT")])
+        elif self.path == "/cookies/redirect":
+            self.reply(302, "", [("Set-Cookie", "r=7; Path=/"),
+                                 ("Location", "/cookies/echo")])
+        elif self.path.startswith("/cookies/"):
+            self.reply(200, self.headers.get("Cookie") or "")
+        elif self.path == "/reset":
+            # The response says the connection stays open, but it is
+            # reset as soon as the response is out.
+            self.reply(200, "reset")
+            self.connection.setsockopt(socket.SOL_SOCKET, socket.SO_LINGER,
+                                       struct.pack("ii", 1, 0))
+            self.connection.close()
+            self.close_connection = True
+        elif self.path == "/connections":
+            self.reply(200, str(len(connections)))
+        else:
+            self.reply(404, "not found")
+
+    def read_frame(self):
+        head = bytearray(self.rfile.read(2))
+        if len(head) < 2:
+            return None, None
+        opcode, n = head[0] & 0x0f, head[1] & 0x7f
+        if n == 126:
+            n = struct.unpack(">H", self.rfile.read(2))[0]
+        elif n == 127:
+            n = struct.unpack(">Q", self.rfile.read(8))[0]
+        mask = bytearray(self.rfile.read(4) if head[1] & 0x80 else b"\0\0\0\0")
+        data = bytearray(self.rfile.read(n))
+        for i in range(n):
+            data[i] ^= mask[i % 4]
+        return opcode, bytes(data)
+
+    def write_frame(self, opcode, data, fin=True):
+        head = bytearray([(0x80 if fin else 0) | opcode])
+        if len(data) < 126:
+            head.append(len(data))
+        elif len(data) < 65536:
+            head += bytearray([126]) + struct.pack(">H", len(data))
+        else:
+            head += bytearray([127]) + struct.pack(">Q", len(data))
+        self.wfile.write(bytes(head) + data)
+        self.wfile.flush()
+
+    # /ws/echo sends back each message, the first split into two frames
+    # after a ping; /ws/close closes with 4001 as soon as it opens.
+    def websocket(self):
+        key = self.headers.get("Sec-WebSocket-Key", "")
+        accept = base64.b64encode(hashlib.sha1(
+            (key + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").encode()).digest())
+        self.send_response(101)
+        self.send_header("Upgrade", "websocket")
+        self.send_header("Connection", "Upgrade")
+        self.send_header("Sec-WebSocket-Accept", accept.decode())
+        offered = self.headers.get("Sec-WebSocket-Protocol")
+        if offered:
+            self.send_header("Sec-WebSocket-Protocol", offered.split(",")[0].strip())
+        self.end_headers()
+        self.close_connection = True
+        if self.path == "/ws/close":
+            self.write_frame(0x8, struct.pack(">H", 4001) + b"server bye")
+            self.read_frame()
+            return
+        first = True
+        while True:
+            opcode, data = self.read_frame()
+            if opcode is None:
+                return
+            if opcode == 0x8:
+                self.write_frame(0x8, data[:2])
+                return
+            if opcode == 0x9:
+                self.write_frame(0xa, data)
+            elif opcode in (0x1, 0x2):
+                if first and len(data) > 1:
+                    self.write_frame(0x9, b"ping")
+                    self.write_frame(opcode, data[:1], fin=False)
+                    self.write_frame(0x0, data[1:])
+                else:
+                    self.write_frame(opcode, data)
+                first = False
+
+    do_GET = do_POST = do_PUT = do_HEAD = handle_any
+
+
+# A slow response must not hold up the others.
+class Server(ThreadingMixIn, HTTPServer):
+    daemon_threads = True
+
+
+if __name__ == "__main__":
+    port = int(sys.argv[1]) if len(sys.argv) > 1 else 8123
+    server = Server(("127.0.0.1", port), Handler)
+    if len(sys.argv) > 3:
+        context = ssl.SSLContext(getattr(ssl, "PROTOCOL_TLS_SERVER",
+                                         ssl.PROTOCOL_SSLv23))
+        context.load_cert_chain(sys.argv[2], sys.argv[3])
+        server.socket = context.wrap_socket(server.socket, server_side=True)
+    server.serve_forever()
@@ -0,0 +1,68 @@
+// Exercises XMLHttpRequest over TLS against httpserver.py with a
+// self-signed certificate for localhost; see "make check-https".
+
+var base = "https://localhost:8443";
+
+function check(name, ok, detail) {
+    print((ok ? "ok " : "FAIL ") + name + (ok ? "" : ": " + detail));
+}
+
+var steps = [
+    function(next) {
+        var xhr = new XMLHttpRequest();
+        xhr.open("GET", base + "/chunked", true);
+        xhr.onload = function() {
+            check("https body", xhr.responseText === "chunked body",
+                  xhr.responseText);
+            next();
+        };
+        xhr.onerror = function() { check("https body", false, "error"); next(); };
+        xhr.send(null);
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest();
+        xhr.open("GET", base + "/echo", true);
+        xhr.onload = function() {
+            check("Host header omits 443", xhr.responseText ===
+                  "GET  host=localhost:8443", xhr.responseText);
+            next();
+        };
+        xhr.send(null);
+    },
+    function(next) {
+        // The pooled connection is reset once /reset has answered, so
+        // the next request writes to a dead socket and must retry.
+        var xhr = new XMLHttpRequest();
+        xhr.open("GET", base + "/reset", true);
+        xhr.onload = function() {
+            setTimeout(function() {
+                var again = new XMLHttpRequest();
+                again.open("GET", base + "/plain", true);
+                again.onload = function() {
+                    check("reset connection retried",
+                          again.responseText === "plain body", again.responseText);
+                    next();
+                };
+                again.onerror = function() {
+                    check("reset connection retried", false, "error");
+                    next();
+                };
+                again.send(null);
+            }, 200);
+        };
+        xhr.send(null);
+    },
+    function(next) {
+        // The certificate names localhost, not the address.
+        var xhr = new XMLHttpRequest();
+        xhr.open("GET", "https://127.0.0.1:8443/plain", true);
+        xhr.onload = function() { check("name mismatch fails", false, "loaded"); next(); };
+        xhr.onerror = function() { check("name mismatch fails", true, ""); next(); };
+        xhr.send(null);
+    }
+];
+
+(function run() {
+    var step = steps.shift();
+    if (step) step(run);
+})();
@@ -0,0 +1,360 @@
+// Exercises XMLHttpRequest against httpserver.py; see "make check-http".
+
+var base = "http://127.0.0.1:8123";
+
+function check(name, ok, detail) {
+    print((ok ? "ok " : "FAIL ") + name + (ok ? "" : ": " + detail));
+}
+
+function get(method, path, body, callback) {
+    var xhr = new XMLHttpRequest();
+    xhr.open(method, base + path, true);
+    xhr.onload = function() { callback(xhr); };
+    xhr.send(body);
+}
+
+function throwsSyntaxError(f) {
+    try { f(); } catch (e) { return e instanceof SyntaxError; }
+    return false;
+}
+
+// One after another, so that the connection can be reused.
+var steps = [
+    function(next) {
+        var order = [];
+        setTimeout(function() {
+            check("jobs before timers", order.join() === "sync,then,microtask,timer",
+                  order.join());
+            next();
+        }, 100);
+        Promise.resolve().then(function() {
+            order.push("then");
+            queueMicrotask(function() { order.push("microtask"); });
+        });
+        setTimeout(function() { order.push("timer"); }, 0);
+        order.push("sync");
+    },
+    function(next) {
+        get("GET", "/plain", null, function(xhr) {
+            check("content-length body", xhr.responseText === "plain body",
+                  xhr.responseText);
+            check("status", xhr.status === 200 && xhr.statusText === "OK",
+                  xhr.status + " " + xhr.statusText);
+            check("header names ignore case",
+                  xhr.getResponseHeader("x-TEST") === "yes",
+                  xhr.getResponseHeader("x-TEST"));
+            check("missing header is null",
+                  xhr.getResponseHeader("X-None") === null, "");
+            next();
+        });
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest(), progress = [];
+        xhr.open("GET", base + "/plain", true);
+        xhr.onprogress = function(e) { progress.push(e.loaded + "/" + e.total); };
+        xhr.onloadend = function(e) {
+            check("progress", progress.join() === "10/10", progress.join());
+            check("loadend after load", e.type === "loadend", e.type);
+            next();
+        };
+        xhr.send(null);
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest(), states = [];
+        xhr.open("GET", base + "/chunked", true);
+        xhr.onreadystatechange = function() {
+            if (states[states.length - 1] !== xhr.readyState) {
+                states.push(xhr.readyState);
+            }
+            if (xhr.readyState !== 4) return;
+            check("chunked body", xhr.responseText === "chunked body",
+                  xhr.responseText);
+            check("ready states", states.join() === "1,2,3,4", states.join());
+            next();
+        };
+        xhr.send(null);
+    },
+    function(next) {
+        get("GET", "/utf8", null, function(xhr) {
+            check("UTF-8 body", xhr.responseText === "h\u00e9llo \ufffd \ufffd",
+                  escape(xhr.responseText));
+            check("UTF-8 header", xhr.getResponseHeader("X-Name") === "caf\u00e9",
+                  escape(xhr.getResponseHeader("X-Name")));
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/echo", null, function(xhr) {
+            check("Host header", xhr.responseText === "GET  host=127.0.0.1:8123",
+                  xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("POST", "/redirect/302", "data", function(xhr) {
+            check("302 becomes GET", xhr.responseText.indexOf("GET ") === 0,
+                  xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("POST", "/redirect/307", "data", function(xhr) {
+            check("307 keeps method and body",
+                  xhr.responseText.indexOf("POST data") === 0, xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/redirect/301", null, function(xhr) {
+            check("301", xhr.status === 200, xhr.status);
+            check("responseURL after redirect",
+                  xhr.responseURL === base + "/echo", xhr.responseURL);
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/connections", null, function(xhr) {
+            check("keep-alive", xhr.responseText === "1", xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/close", null, function(xhr) {
+            check("body delimited by close",
+                  xhr.responseText === "body until close", xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/cookies/set", null, function(xhr) {
+            check("Set-Cookie hidden", xhr.getResponseHeader("Set-Cookie") === null,
+                  xhr.getResponseHeader("Set-Cookie"));
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/cookies/echo", null, function(xhr) {
+            check("cookies sent", xhr.responseText === "a=1; h=2; gone=6",
+                  xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/cookies/deep/x", null, function(xhr) {
+            check("longer paths first",
+                  xhr.responseText === "deep=5; a=1; h=2; gone=6", xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        get("GET", "/cookies/expire", null, function() {
+            get("GET", "/cookies/echo", null, function(xhr) {
+                check("expired cookie removed", xhr.responseText === "a=1; h=2",
+                      xhr.responseText);
+                next();
+            });
+        });
+    },
+    function(next) {
+        get("GET", "/cookies/redirect", null, function(xhr) {
+            check("cookie set by a redirect",
+                  xhr.responseText === "a=1; h=2; r=7", xhr.responseText);
+            next();
+        });
+    },
+    function(next) {
+        // What document.cookie reads and writes.
+        jsrust_cookie(base + "/", "js=8");
+        jsrust_cookie(base + "/", "h=9");
+        check("script cookies skip HttpOnly",
+              jsrust_cookie(base + "/") === "a=1; r=7; js=8", jsrust_cookie(base + "/"));
+        jsrust_cookie("http://a.test/", "host=1");
+        jsrust_cookie("http://a.test/", "dom=2; Domain=a.test");
+        check("Domain naming the host reaches subdomains",
+              jsrust_cookie("http://sub.a.test/") === "dom=2" &&
+              jsrust_cookie("http://a.test/") === "host=1; dom=2",
+              jsrust_cookie("http://sub.a.test/"));
+        next();
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest();
+        check("method must be a token", throwsSyntaxError(function() {
+            xhr.open("GET /evil HTTP/1.1\r\nX:", base + "/echo", true);
+        }), "no SyntaxError");
+        xhr.open("GET", base + "/echo", true);
+        check("header value without CR LF", throwsSyntaxError(function() {
+            xhr.setRequestHeader("X-Test", "a\r\nHost: evil");
+        }), "no SyntaxError");
+        check("header name must be a token", throwsSyntaxError(function() {
+            xhr.setRequestHeader("X Test", "a");
+        }), "no SyntaxError");
+        // fetch() leaves the check to the host.
+        fetch(base + "/echo", {headers: {"X-Test": "a\r\nHost: evil"}}).then(function() {
+            check("host rejects header injection", false, "resolved");
+        }, function(e) {
+            check("host rejects header injection", e instanceof TypeError, e);
+        }).then(next);
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest();
+        xhr.open("GET", base + "/slow", true);
+        xhr.timeout = 300;
+        xhr.onload = function() { check("timeout", false, "loaded"); next(); };
+        xhr.ontimeout = function() {
+            check("timeout", xhr.readyState === 4 && xhr.status === 0,
+                  xhr.readyState + " " + xhr.status);
+            next();
+        };
+        xhr.send(null);
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest(), timedOut = false;
+        xhr.open("GET", base + "/plain", true);
+        xhr.timeout = 200;
+        xhr.ontimeout = function() { timedOut = true; };
+        xhr.send(null);
+        setTimeout(function() {
+            check("timeout dropped once loaded", xhr.status === 200 && !timedOut,
+                  xhr.status + " " + timedOut);
+            next();
+        }, 400);
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest(), events = [];
+        xhr.open("GET", base + "/slow", true);
+        xhr.onprogress = function() { xhr.abort(); };
+        xhr.onabort = function() { events.push("abort"); };
+        xhr.onloadend = function() { events.push("loadend"); };
+        xhr.onload = function() { events.push("load"); };
+        xhr.send(null);
+        setTimeout(function() {
+            check("abort", events.join() === "abort,loadend" &&
+                  xhr.readyState === 0, events.join() + " " + xhr.readyState);
+            next();
+        }, 2500);
+    },
+    function(next) {
+        var xhr = new XMLHttpRequest(), loaded = false;
+        xhr.open("GET", base + "/chunked", false);
+        xhr.onload = function() { loaded = true; };
+        xhr.send(null);
+        check("synchronous", loaded && xhr.readyState === 4 &&
+              xhr.responseText === "chunked body", xhr.responseText);
+        xhr = new XMLHttpRequest();
+        xhr.open("GET", "http://127.0.0.1:1/", false);
+        try {
+            xhr.send(null);
+            check("synchronous error throws", false, "no exception");
+        } catch (e) {
+            check("synchronous error throws", xhr.status === 0, e);
+        }
+        xhr = new XMLHttpRequest();
+        xhr.open("GET", base + "/slow", false);
+        xhr.timeout = 300;
+        var timedOut = false, start = Date.now();
+        xhr.ontimeout = function() { timedOut = true; };
+        try {
+            xhr.send(null);
+            check("synchronous timeout", false, "no exception");
+        } catch (e) {
+            check("synchronous timeout", timedOut && /^TimeoutError/.test(e.message) &&
+                  Date.now() - start < 1500, e + " " + (Date.now() - start));
+        }
+        next();
+    },
+    function(next) {
+        fetch(base + "/plain").then(function(response) {
+            check("fetch status", response.status === 200 && response.ok,
+                  response.status);
+            check("fetch headers", response.headers.get("x-test") === "yes",
+                  response.headers.get("x-test"));
+            return response.text();
+        }).then(function(text) {
+            check("fetch text", text === "plain body", text);
+            return fetch(base + "/echo", {method: "POST", body: "data"});
+        }).then(function(response) {
+            return response.text();
+        }).then(function(text) {
+            check("fetch POST", text.indexOf("POST data") === 0, text);
+            return fetch(base + "/missing");
+        }).then(function(response) {
+            check("fetch 404 is not ok", response.status === 404 && !response.ok,
+                  response.status);
+            return fetch("file:///httpserver.py");
+        }).then(function(response) {
+            return response.text();
+        }).then(function(text) {
+            check("fetch file:", text.indexOf("#!") === 0, text.slice(0, 20));
+            return fetch("http://127.0.0.1:1/");
+        }).then(function() {
+            check("fetch rejects on error", false, "resolved");
+        }, function(e) {
+            check("fetch rejects on error", e instanceof TypeError, e);
+        }).then(next);
+    },
+    function(next) {
+        var ws = new WebSocket("ws://127.0.0.1:8123/ws/echo", ["chat", "other"]),
+            got = [];
+        ws.onopen = function() {
+            check("WebSocket protocol", ws.protocol === "chat", ws.protocol);
+            ws.send("h\u00e9llo");
+            ws.send("a\u0000b");
+            ws.send(new Uint8Array([1, 2, 3, 250]));
+        };
+        ws.onmessage = function(e) {
+            got.push(typeof e.data === "string" ? e.data :
+                     Array.prototype.slice.call(new Uint8Array(e.data)).join(" "));
+            if (got.length === 3) ws.close(1000, "done");
+        };
+        ws.onclose = function(e) {
+            check("WebSocket echo", got.join() === "h\u00e9llo,a\u0000b,1 2 3 250",
+                  JSON.stringify(got));
+            check("WebSocket close", e.code === 1000 && e.wasClean &&
+                  ws.readyState === WebSocket.CLOSED, e.code + " " + e.wasClean);
+            next();
+        };
+    },
+    function(next) {
+        var ws = new WebSocket("ws://127.0.0.1:8123/ws/close");
+        ws.onclose = function(e) {
+            check("WebSocket closed by server", e.code === 4001 &&
+                  e.reason === "server bye" && e.wasClean, e.code + " " + e.reason);
+            next();
+        };
+    },
+    function(next) {
+        var url = "ws://127.0.0.1:8123/ws/echo";
+        check("subprotocol must be a token", throwsSyntaxError(function() {
+            new WebSocket(url, "chat\r\nX-Evil: 1");
+        }), "no SyntaxError");
+        check("subprotocols must differ", throwsSyntaxError(function() {
+            new WebSocket(url, ["chat", "chat"]);
+        }), "no SyntaxError");
+        next();
+    },
+    function(next) {
+        var ws = new WebSocket("ws://127.0.0.1:1/"), errors = 0;
+        ws.onerror = function() { errors++; };
+        ws.onclose = function(e) {
+            check("WebSocket refused", errors === 1 && e.code === 1006 &&
+                  !e.wasClean, errors + " " + e.code);
+            next();
+        };
+    },
+    function(next) {
+        // Nothing listens on port 1.
+        var xhr = new XMLHttpRequest();
+        xhr.open("GET", "http://127.0.0.1:1/", true);
+        xhr.onload = function() { check("connection refused", false, "loaded"); next(); };
+        xhr.onerror = function() {
+            check("connection refused", xhr.status === 0, xhr.status);
+            next();
+        };
+        xhr.send(null);
+    }
+];
+
+(function run() {
+    var step = steps.shift();
+    if (step) step(run);
+})();
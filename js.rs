@@ -8,8 +8,16 @@ import ptr::null;
 export new_runtime, get_thread_runtime, runtime, new_context, context, begin_request, end_request, set_options, set_version, new_class, class;
 export new_compartment_and_global_object, object, init_standard_classes, options;
 export null_principals, compile_script, script, execute_script, value_to_source;
+export value_to_string;
 export get_string_bytes, get_string, get_int, set_data_property, ext;
 export error_report, jsrust_message;
+export set_operation_callback, trigger_operation_callback;
+export gc, maybe_gc, gc_param, gc_status, gc_event, heap_stats;
+export set_gc_parameter, get_gc_parameter, get_heap_stats, get_runtime;
+export new_object, call_tracer, jsval, JSTracer;
+export compartment_call, enter_compartment, wrap_object, wrap_value;
+export transplant_object, get_global_object, get_global_for_object;
+export new_principals, system_principals, get_origin, subsumes, principals;
 
 /* Structures. */
 type JSClass = {
@@ -50,12 +58,26 @@ type error_report = {
 	flags: u32
 };
 
+type gc_event = {
+    status: u32,    /* one of gc_status */
+    bytes: u32,
+    number: u32
+};
+
+type heap_stats = {
+    bytes: u32,     /* bytes currently allocated in the GC heap */
+    number: u32     /* number of collections run so far */
+};
+
 type jsrust_message = {
 	level: u32,
 	message: str,
     tag: u32,
     timeout: u32,
-	pad: u32
+	pad: u32,
+    // Data sent with its length, such as a FILE_WRITE's contents or a
+    // MUTATIONS batch
+    bytes: [u8]
 };
 
 
@@ -75,6 +97,8 @@ enum JSPrincipals    { JSPrincipals(@JSPrincipals)       }
 enum JSRuntime       { JSRuntime(@JSRuntime)             }
 enum JSScript        { JSScript(@JSScript)               }
 enum JSString        { JSString(@JSString)               }
+enum JSTracer        { JSTracer(@JSTracer)               }
+enum JSRustTraceSet  { JSRustTraceSet(@JSRustTraceSet)   }
 enum JSCrossCompartmentCall {
     JSCrossCompartmentCall(@JSCrossCompartmentCall)
 }
@@ -87,21 +111,23 @@ type JSEnumerateOp = u64;
 type JSResolveOp = u64;
 type JSConvertOp = u64;
 type JSFinalizeOp = u64;
+type JSOperationCallback = u64;
+type JSTraceOp = u64;
+type JSCheckAccessOp = u64;
 
-enum JSCheckAccessOp     { JSCheckAccessOp(@JSCheckAccessOp)       }
 enum JSEqualityOp        { JSEqualityOp(@JSEqualityOp)             }
 enum JSHasInstanceOp     { JSHasInstanceOp(@JSHasInstanceOp)       }
 enum JSNative            { JSNative(@JSNative)                     }
 enum JSNewEnumerateOp    { JSNewEnumerateOp(@JSNewEnumerateOp)     }
 enum JSNewResolveOp      { JSNewResolveOp(@JSNewResolveOp)         }
 enum JSStringFinalizeOp  { JSStringFinalizeOp(@JSStringFinalizeOp) }
-enum JSTraceOp           { JSTraceOp(@JSTraceOp)                   }
 enum JSTraceNamePrinter  { JSTraceNamePrinter(@JSTraceNamePrinter) }
 enum JSTypeOfOp          { JSTypeOfOp(@JSTypeOfOp)                 }
 enum JSXDRObjectOp       { JSXDRObjectOp(@JSXDRObjectOp)           }
 
 /* Non-opaque types. */
 type JSProtoKey = uint;
+type JSGCParamKey = uint;
 type JSVersion = uint;
 type jsrefcount = uint;
 
@@ -124,6 +150,24 @@ mod options {
     const soften : u32                  = 0x80000u32;   // JS_BIT(19)
 }
 
+mod gc_param {
+    const max_bytes : uint          = 0u;
+    const max_malloc_bytes : uint   = 1u;
+    const bytes : uint              = 4u;   // read only
+    const number : uint             = 5u;   // read only
+    const mode : uint               = 7u;
+    const unused_chunks : uint      = 8u;   // read only
+    const total_chunks : uint       = 9u;   // read only
+    const mark_stack_limit : uint   = 10u;
+}
+
+mod gc_status {
+    const begin : u32           = 0u32;
+    const end : u32             = 1u32;
+    const mark_end : u32        = 2u32;
+    const finalize_end : u32    = 3u32;
+}
+
 #[link_name="mozjs"]
 native mod js {
     fn JS_Init(maxbytes : u32) -> *JSRuntime;
@@ -165,6 +209,12 @@ native mod js {
 
     fn JS_GetImplementationVersion() -> *u8;
 
+    fn JS_SetOperationCallback(cx : *JSContext,
+                               callback : JSOperationCallback)
+        -> JSOperationCallback;
+    fn JS_GetOperationCallback(cx : *JSContext) -> JSOperationCallback;
+    fn JS_TriggerOperationCallback(cx : *JSContext);
+
     // fn JS_SetCompartmentCallback(rt : *JSRuntime,
     //                                     callback : JSCompartmentCallback)
     //      -> JSCompartmentCallback;
@@ -210,6 +260,11 @@ native mod js {
     fn JS_InitReflect(cx : *JSContext, global : *JSObject)
         -> *JSObject;
 
+    fn JS_GC(cx : *JSContext);
+    fn JS_MaybeGC(cx : *JSContext);
+    fn JS_SetGCParameter(rt : *JSRuntime, key : JSGCParamKey, value : u32);
+    fn JS_GetGCParameter(rt : *JSRuntime, key : JSGCParamKey) -> u32;
+
     fn JS_AddValueRoot(cx : *JSContext, vp : *jsval) -> bool;
     fn JS_AddStringRoot(cx : *JSContext, rp : **JSString) -> bool;
     fn JS_AddObjectRoot(cx : *JSContext, rp : **JSObject) -> bool;
@@ -218,6 +273,7 @@ native mod js {
     /* TODO: Plenty more to add here. */
 
     fn JS_ValueToSource(cx : *JSContext, v : jsval) -> *JSString;
+    fn JS_ValueToString(cx : *JSContext, v : jsval) -> *JSString;
 
     /* TODO: Plenty more to add here. */
 
@@ -225,6 +281,8 @@ native mod js {
                                                clasp : *JSClass,
                                                principals : *JSPrincipals)
         -> *JSObject;
+    fn JS_NewObject(cx : *JSContext, clasp : *JSClass, proto : *JSObject,
+                    parent : *JSObject) -> *JSObject;
 
     /* TODO: Plenty more to add here. */
 
@@ -281,6 +339,55 @@ native mod jsrust {
         fn JSRust_GetThreadRuntime(maxbytes : u32) -> *JSRuntime;
         fn JSRust_Exit(code : c_int);
         fn JSRust_GetGlobalClassFlags() -> u32;
+
+        fn JSRust_SetScriptBudget(cx : *JSContext, budget_ms : u32);
+        fn JSRust_ArmWatchdog(cx : *JSContext);
+        fn JSRust_DisarmWatchdog(cx : *JSContext);
+
+        fn JSRust_SetSyncHook(cx : *JSContext, hook : *u8, data : *void);
+        fn JSRust_SetSyncResult(result : *void,
+                                head : str::sbuf, head_len : u32,
+                                body : str::sbuf, body_len : u32,
+                                error : str::sbuf, error_len : u32);
+        fn JSRust_SetCookieHook(cx : *JSContext, hook : *u8, data : *void);
+        fn JSRust_SetJobHook(cx : *JSContext, hook : *u8, data : *void);
+        fn JSRust_CallJob(cx : *JSContext, global : *JSObject, job : jsval) -> bool;
+        fn JSRust_SetUrlHook(hook : *u8);
+        fn JSRust_SetString(out : *void, s : str::sbuf, len : u32);
+
+        fn JSRust_AddGCListener(rt : *JSRuntime, chan : chan<gc_event>) -> u32;
+        fn JSRust_RemoveGCListener(rt : *JSRuntime, id : u32);
+
+        fn JSRust_GetTracedClassFlags() -> u32;
+        fn JSRust_GetTracedClassTraceHook() -> JSTraceOp;
+        fn JSRust_GetTracedClassFinalizeHook() -> JSFinalizeOp;
+        fn JSRust_NewTraceSet() -> *JSRustTraceSet;
+        fn JSRust_ReleaseTraceSet(ts : *JSRustTraceSet);
+        fn JSRust_TraceSetAdd(ts : *JSRustTraceSet, v : jsval) -> u32;
+        fn JSRust_TraceSetGet(ts : *JSRustTraceSet, index : u32) -> jsval;
+        fn JSRust_TraceSetRemove(ts : *JSRustTraceSet, index : u32);
+        fn JSRust_AttachTraceSet(cx : *JSContext, obj : *JSObject,
+                                 ts : *JSRustTraceSet) -> bool;
+        fn JSRust_AddRootSet(rt : *JSRuntime, ts : *JSRustTraceSet);
+        fn JSRust_RemoveRootSet(rt : *JSRuntime, ts : *JSRustTraceSet);
+        fn JSRust_SetExtraRootsTracer(rt : *JSRuntime, op : *u8, data : *void);
+        fn JSRust_CallValueTracer(trc : *JSTracer, v : jsval, name : *u8);
+
+        fn JSRust_NewPrincipals(origin : *u8) -> *JSPrincipals;
+        fn JSRust_GetSystemPrincipals() -> *JSPrincipals;
+        fn JSRust_GetPrincipalsOrigin(p : *JSPrincipals) -> str;
+        fn JSRust_Subsumes(a : *JSPrincipals, b : *JSPrincipals) -> bool;
+        fn JSRust_GetCheckAccessHook() -> JSCheckAccessOp;
+        fn JSRust_GetGlobalFinalizeHook() -> JSFinalizeOp;
+        fn JSRust_SetGlobalPrincipals(cx : *JSContext, global : *JSObject,
+                                      p : *JSPrincipals);
+        fn JSRust_DefineObject(cx : *JSContext, obj : *JSObject, name : *u8,
+                               target : *JSObject) -> bool;
+        fn JSRust_DefineHostFunctions(cx : *JSContext, obj : *JSObject,
+                                      whitelist : *u8) -> bool;
+
+        fn JSRust_SetFilePolicy(cx : *JSContext, root : *u8, max_bytes : u32);
+        fn JSRust_ResolvePath(cx : *JSContext, path : *u8) -> str;
 }
 
 resource runtime(_rt : *JSRuntime) {
@@ -326,6 +433,35 @@ fn new_context(rt : runtime, stack_chunk_size : size_t) -> context {
     ret context(jsrust::JSRust_NewContext(*rt, stack_chunk_size));
 }
 
+fn get_runtime(cx : context) -> runtime {
+    ret runtime(js::JS_GetRuntime(*cx));
+}
+
+/* Garbage collection */
+
+fn gc(cx : context) {
+    js::JS_GC(*cx);
+}
+
+fn maybe_gc(cx : context) {
+    js::JS_MaybeGC(*cx);
+}
+
+fn set_gc_parameter(rt : runtime, key : JSGCParamKey, value : u32) {
+    js::JS_SetGCParameter(*rt, key, value);
+}
+
+fn get_gc_parameter(rt : runtime, key : JSGCParamKey) -> u32 {
+    ret js::JS_GetGCParameter(*rt, key);
+}
+
+fn get_heap_stats(rt : runtime) -> heap_stats {
+    ret {
+        bytes: js::JS_GetGCParameter(*rt, gc_param::bytes),
+        number: js::JS_GetGCParameter(*rt, gc_param::number)
+    };
+}
+
 /* Options */
 
 fn get_options(cx : context) -> u32 {
@@ -340,6 +476,17 @@ fn set_version(cx : context, version : JSVersion) {
     let _ = js::JS_SetVersion(*cx, version);
 }
 
+/* Operation callbacks */
+
+fn set_operation_callback(cx : context, callback : JSOperationCallback)
+        -> JSOperationCallback {
+    ret js::JS_SetOperationCallback(*cx, callback);
+}
+
+fn trigger_operation_callback(cx : context) {
+    js::JS_TriggerOperationCallback(*cx);
+}
+
 /* Objects */
 
 fn new_compartment_and_global_object(cx : context, clas : @class,
@@ -348,6 +495,72 @@ fn new_compartment_and_global_object(cx : context, clas : @class,
     let jsobj = js::JS_NewCompartmentAndGlobalObject(*cx, jsclass,
                                                      *principals);
     if jsobj == null() { fail; }
+    jsrust::JSRust_SetGlobalPrincipals(*cx, jsobj, *principals);
+    ret object_priv(jsobj);
+}
+
+fn new_object(cx : context, clas : @class, proto : option<object>,
+              parent : option<object>) -> object {
+    let jsclass = ptr::addr_of(clas.jsclass);
+    let jsproto = alt proto { some(p) { *p } none { null() } };
+    let jsparent = alt parent { some(p) { *p } none { null() } };
+    let jsobj = js::JS_NewObject(*cx, jsclass, jsproto, jsparent);
+    if jsobj == null() { fail; }
+    ret object_priv(jsobj);
+}
+
+fn get_global_object(cx : context) -> object {
+    ret object_priv(js::JS_GetGlobalObject(*cx));
+}
+
+fn get_global_for_object(cx : context, obj : object) -> object {
+    ret object_priv(js::JS_GetGlobalForObject(*cx, *obj));
+}
+
+/* Compartments */
+
+/*
+ * While a compartment_call is alive, the context runs in the compartment
+ * of the object it was entered with. Objects and values from other
+ * compartments must be wrapped before they are used there.
+ */
+resource compartment_call(call : *JSCrossCompartmentCall) {
+    js::JS_LeaveCrossCompartmentCall(call);
+}
+
+fn enter_compartment(cx : context, target : object) -> compartment_call {
+    let call = js::JS_EnterCrossCompartmentCall(*cx, *target);
+    if call == null() { fail; }
+    ret compartment_call(call);
+}
+
+/* Wraps an object for use in the current compartment. */
+fn wrap_object(cx : context, obj : object) -> option<object> {
+    let jsobj = *obj;
+    if !js::JS_WrapObject(*cx, ptr::addr_of(jsobj)) {
+        ret none;
+    }
+    ret some(object_priv(jsobj));
+}
+
+/* Wraps a value for use in the current compartment. */
+fn wrap_value(cx : context, v : jsval) -> option<jsval> {
+    let rv = v;
+    if !js::JS_WrapValue(*cx, ptr::addr_of(rv)) {
+        ret none;
+    }
+    ret some(rv);
+}
+
+/*
+ * Swaps the identity of `orig` with `target`, which may live in another
+ * compartment; existing references to `orig` are redirected. Returns the
+ * object now standing in for `orig`.
+ */
+fn transplant_object(cx : context, orig : object, target : object)
+        -> object {
+    let jsobj = js::JS_TransplantObject(*cx, *orig, *target);
+    if jsobj == null() { fail; }
     ret object_priv(jsobj);
 }
 
@@ -357,11 +570,40 @@ fn null_principals() -> principals {
     ret principals_priv(null());
 }
 
+/* Principals for scripts from `origin`, e.g. "http://example.com:80". */
+fn new_principals(origin : str) -> principals {
+    ret principals_priv(str::as_buf(origin, { |buf|
+        jsrust::JSRust_NewPrincipals(buf)
+    }));
+}
+
+/* Principals that subsume all others, like null_principals. */
+fn system_principals() -> principals {
+    ret principals_priv(jsrust::JSRust_GetSystemPrincipals());
+}
+
+fn get_origin(p : principals) -> str {
+    ret jsrust::JSRust_GetPrincipalsOrigin(*p);
+}
+
+fn subsumes(a : principals, b : principals) -> bool {
+    ret jsrust::JSRust_Subsumes(*a, *b);
+}
+
 /* Classes */
 
 type class_spec = {
     name: str,
-    flags: u32
+    flags: u32,
+    /* Instances may own a trace set; see ext::attach_trace_set. */
+    traced: bool,
+    /*
+     * Deny the accesses that go through the checkAccess hook, such as
+     * __proto__, from globals whose principals do not subsume those of
+     * the instance's global. Other access from such globals is denied by
+     * the wrappers it goes through, whatever the class.
+     */
+    check_access: bool
     /* TODO: More to add here. */
 };
 
@@ -374,11 +616,23 @@ fn new_class(spec : class_spec) -> @class unsafe {
     // Root the name separately, and make the JSClass name point into it.
     let name = @spec.name;
     let x : *void = ptr::null();
+    let check_access = if spec.check_access {
+        jsrust::JSRust_GetCheckAccessHook()
+    } else {
+        0u64
+    };
+    let (flags, finalize, trace) = if spec.traced {
+        (spec.flags | jsrust::JSRust_GetTracedClassFlags(),
+         jsrust::JSRust_GetTracedClassFinalizeHook(),
+         jsrust::JSRust_GetTracedClassTraceHook())
+    } else {
+        (spec.flags, jsrust::JSRust_GetGlobalFinalizeHook(), 0u64)
+    };
     ret @{
         name: name,
         jsclass: {
             name: str::as_buf(*name, { |b| b }),
-            flags: spec.flags,
+            flags: flags,
 
             addProperty: jsrust::JSRust_GetPropertyStub(),
             delProperty: jsrust::JSRust_GetPropertyStub(),
@@ -387,15 +641,15 @@ fn new_class(spec : class_spec) -> @class unsafe {
             enumerate: jsrust::JSRust_GetEnumerateStub(),
             resolve: jsrust::JSRust_GetResolveStub(),
             convert: jsrust::JSRust_GetConvertStub(),
-            finalize: jsrust::JSRust_GetFinalizeStub(),
+            finalize: finalize,
 
             reserved0: unsafe::reinterpret_cast(0),
-            checkAccess: unsafe::reinterpret_cast(0),
+            checkAccess: check_access,
             call: unsafe::reinterpret_cast(0),
             construct: unsafe::reinterpret_cast(0),
             xdrObject: unsafe::reinterpret_cast(0),
             hasInstance: unsafe::reinterpret_cast(0),
-            trace: unsafe::reinterpret_cast(0),
+            trace: trace,
 
             reserved1: unsafe::reinterpret_cast(0),
             reserved: (x,x,x,x,x,x,x,x, x,x,x,x,x,x,x,x,    /* 16 */
@@ -437,12 +691,26 @@ fn execute_script(cx : context, object : object, script : script)
     ret some(rv);
 }
 
+/* Tracing */
+
+/*
+ * Reports a value held outside the JS heap to the collector. For use by
+ * trace hooks and extra roots tracers written in Rust.
+ */
+fn call_tracer(trc : *JSTracer, v : jsval, name : str) {
+    str::as_buf(name, { |buf| jsrust::JSRust_CallValueTracer(trc, v, buf) });
+}
+
 /* Value conversion */
 
 fn value_to_source(cx : context, v : jsval) -> string {
     ret string_priv(js::JS_ValueToSource(*cx, v));
 }
 
+fn value_to_string(cx : context, v : jsval) -> string {
+    ret string_priv(js::JS_ValueToString(*cx, v));
+}
+
 /* String conversion */
 
 fn get_string_bytes(cx : context, jsstr : string) -> [u8] unsafe {
@@ -488,20 +756,277 @@ fn set_data_property(cx : context, obj : object, value : str) {
 
 /** Rust extensions to the JavaScript language bindings. */
 mod ext {
-	fn set_msg_channel(cx : context, object : object, chan : chan<jsrust_message>) {
-		if !jsrust::JSRust_SetMessageChannel(*cx, *object, chan) { fail; }
-	}
-
-	fn init_rust_library(cx : context, object : object) {
-		if !jsrust::JSRust_InitRustLibrary(*cx, *object) { fail; }
-	}
-        
+        fn set_msg_channel(cx : context, object : object, chan : chan<jsrust_message>) {
+            if !jsrust::JSRust_SetMessageChannel(*cx, *object, chan) { fail; }
+        }
+
+        fn init_rust_library(cx : context, object : object) {
+            if !jsrust::JSRust_InitRustLibrary(*cx, *object) { fail; }
+        }
+
         fn get_global_class_flags() -> u32 {
             ret jsrust::JSRust_GetGlobalClassFlags();
         }
 
-	fn rust_exit_now(code : int) {
-		jsrust::JSRust_Exit(code as c_int);
-	}
+        fn rust_exit_now(code : int) {
+            jsrust::JSRust_Exit(code as c_int);
+        }
+
+        /*
+         * Sets the CPU time budget, in milliseconds, that each script run
+         * under `arm_watchdog` may use before it is terminated with an
+         * uncatchable error. A budget of 0 disables the watchdog.
+         */
+        fn set_script_budget(cx : context, budget_ms : u32) {
+            jsrust::JSRust_SetScriptBudget(*cx, budget_ms);
+        }
+
+        resource watchdog(cx : *JSContext) {
+            jsrust::JSRust_DisarmWatchdog(cx);
+        }
+
+        /* Starts the budget clock; it stops when the result is dropped. */
+        fn arm_watchdog(cx : context) -> watchdog {
+            jsrust::JSRust_ArmWatchdog(*cx);
+            ret watchdog(*cx);
+        }
+
+        /*
+         * Lets scripts make synchronous requests with
+         * jsrust_http_request_sync. `hook` is a crust fn taking `data`, the
+         * request as JSON (a pointer and a length) and a result, which it
+         * must fill in with `set_sync_result` before returning. The
+         * context's request is suspended while the hook runs.
+         */
+        fn set_sync_hook(cx : context, hook : *u8, data : *void) {
+            jsrust::JSRust_SetSyncHook(*cx, hook, data);
+        }
+
+        /* Answers a synchronous request with a head and body, or an error. */
+        fn set_sync_result(result : *void, head : str, body : str,
+                           error : str) {
+            str::as_buf(head) {|h|
+                str::as_buf(body) {|b|
+                    str::as_buf(error) {|e|
+                        jsrust::JSRust_SetSyncResult(
+                            result, h, str::len(head) as u32,
+                            b, str::len(body) as u32,
+                            e, str::len(error) as u32);
+                    }
+                }
+            };
+        }
+
+        /*
+         * Backs document.cookie with jsrust_cookie(url[, value]). `hook`
+         * is a crust fn taking `data`, the page's URL and the value to
+         * store (pointers and lengths; a null value to read) and a string
+         * to fill in with `set_string`.
+         */
+        fn set_cookie_hook(cx : context, hook : *u8, data : *void) {
+            jsrust::JSRust_SetCookieHook(*cx, hook, data);
+        }
+
+        /*
+         * Jobs, such as promise reactions, that scripts queue with
+         * jsrust_enqueue_job(fn). They are kept alive by a root set until
+         * `run_jobs` calls them, first queued first.
+         */
+        type job_queue = {set: trace_set, slots: std::deque::t<uint>};
+
+        fn new_job_queue(rt : runtime) -> @job_queue {
+            let q = @{set: new_trace_set(), slots: std::deque::create()};
+            add_root_set(rt, q.set);
+            ret q;
+        }
+
+        fn close_job_queue(rt : runtime, q : @job_queue) {
+            remove_root_set(rt, q.set);
+        }
+
+        /*
+         * Sends jsrust_enqueue_job to `hook`, a crust fn taking `data` and
+         * the job, which should pass them on to `enqueue_job`.
+         */
+        fn set_job_hook(cx : context, hook : *u8, data : *void) {
+            jsrust::JSRust_SetJobHook(*cx, hook, data);
+        }
+
+        fn enqueue_job(q : job_queue, job : jsval) {
+            q.slots.add_back(trace_set_add(q.set, job));
+        }
+
+        fn has_jobs(q : job_queue) -> bool {
+            ret q.slots.size() != 0u;
+        }
+
+        /*
+         * Calls the queued jobs in order, along with any they queue, until
+         * none are left. A job that throws is reported and the rest still
+         * run; each job gets the script budget to itself.
+         */
+        fn run_jobs(cx : context, global : object, q : job_queue) {
+            if !has_jobs(q) { ret; }
+            js::JS_BeginRequest(*cx);
+            while has_jobs(q) {
+                let slot = q.slots.pop_front();
+                let job = trace_set_get(q.set, slot);
+                {
+                    let _w = arm_watchdog(cx);
+                    jsrust::JSRust_CallJob(*cx, *global, job);
+                }
+                // The set keeps the job alive until it has returned.
+                trace_set_remove(q.set, slot);
+            }
+            js::JS_EndRequest(*cx);
+        }
+
+        /*
+         * Lets scripts parse URLs with jsrust_parse_url(input[, base]).
+         * `hook` is a crust fn taking the input and base (pointers and
+         * lengths; a null base for none) and a string to fill in with
+         * `set_string`. It serves every context.
+         */
+        fn set_url_hook(hook : *u8) {
+            jsrust::JSRust_SetUrlHook(hook);
+        }
+
+        fn set_string(out : *void, s : str) {
+            str::as_buf(s) {|buf|
+                jsrust::JSRust_SetString(out, buf, str::len(s) as u32);
+            };
+        }
+
+        /*
+         * Sends a gc_event to `chan` at every stage of every collection in
+         * the runtime. Returns an id for `remove_gc_listener`.
+         */
+        fn add_gc_listener(rt : runtime, chan : chan<gc_event>) -> u32 {
+            ret jsrust::JSRust_AddGCListener(*rt, chan);
+        }
+
+        fn remove_gc_listener(rt : runtime, id : u32) {
+            jsrust::JSRust_RemoveGCListener(*rt, id);
+        }
+
+        /*
+         * A set of JS values owned by Rust code. The values are kept alive
+         * while the set is registered as a root set of its runtime, or
+         * while a JS object that the set is attached to is reachable.
+         */
+        resource trace_set(ts : *JSRustTraceSet) {
+            jsrust::JSRust_ReleaseTraceSet(ts);
+        }
+
+        fn new_trace_set() -> trace_set {
+            ret trace_set(jsrust::JSRust_NewTraceSet());
+        }
+
+        /* Adds a value to the set, returning its slot. */
+        fn trace_set_add(ts : trace_set, v : jsval) -> uint {
+            ret jsrust::JSRust_TraceSetAdd(*ts, v) as uint;
+        }
+
+        fn trace_set_get(ts : trace_set, slot : uint) -> jsval {
+            ret jsrust::JSRust_TraceSetGet(*ts, slot as u32);
+        }
+
+        fn trace_set_remove(ts : trace_set, slot : uint) {
+            jsrust::JSRust_TraceSetRemove(*ts, slot as u32);
+        }
+
+        /*
+         * Makes the values in `ts` reachable from `obj`, which must be an
+         * instance of a class created with `traced: true`.
+         */
+        fn attach_trace_set(cx : context, obj : object, ts : trace_set) {
+            if !jsrust::JSRust_AttachTraceSet(*cx, *obj, *ts) { fail; }
+        }
+
+        fn add_root_set(rt : runtime, ts : trace_set) {
+            jsrust::JSRust_AddRootSet(*rt, *ts);
+        }
+
+        fn remove_root_set(rt : runtime, ts : trace_set) {
+            jsrust::JSRust_RemoveRootSet(*rt, *ts);
+        }
+
+        /*
+         * Sets a tracer for roots held outside of trace sets: a crust fn
+         * taking a JSTracer and `data`, which reports each value with
+         * `call_tracer`. A null `op` clears it. It runs alongside the root
+         * sets, which JS_SetExtraGCRootsTracer would not leave in place.
+         */
+        fn set_extra_roots_tracer(rt : runtime, op : *u8, data : *void) {
+            jsrust::JSRust_SetExtraRootsTracer(*rt, op, data);
+        }
+
+        /*
+         * Defines those Rust library functions ("print", "jsrust_read",
+         * "postMessage", "jsrust_connect", ...) named in `names` on `obj`.
+         */
+        fn define_host_functions(cx : context, obj : object, names : [str]) {
+            let whitelist = str::connect(names, ",");
+            if !str::as_buf(whitelist, { |buf|
+                jsrust::JSRust_DefineHostFunctions(*cx, *obj, buf)
+            }) { fail; }
+        }
+
+        /*
+         * Confines file access by scripts in `cx` to paths under `root`,
+         * of files no larger than `max_bytes` (0 for no limit).
+         */
+        fn set_file_policy(cx : context, root : str, max_bytes : u32) {
+            str::as_buf(root, { |buf|
+                jsrust::JSRust_SetFilePolicy(*cx, buf, max_bytes)
+            });
+        }
+
+        /* The host path for a script path, or "" if it is off limits. */
+        fn resolve_path(cx : context, path : str) -> str {
+            ret str::as_buf(path, { |buf|
+                jsrust::JSRust_ResolvePath(*cx, buf)
+            });
+        }
+
+        type sandbox = {
+            global: object,
+            principals: principals,
+            // Keeps the JSClass of the global alive.
+            clas: @class
+        };
+
+        /*
+         * Creates a global in a new compartment with principals for
+         * `origin`. Only the standard classes and the whitelisted host
+         * functions are defined on it, and its scripts cannot access
+         * objects of globals whose principals they do not subsume.
+         */
+        fn new_sandbox(cx : context, origin : str, host_functions : [str])
+                -> sandbox {
+            let clas = new_class({
+                name: "sandbox",
+                flags: get_global_class_flags(),
+                traced: false,
+                check_access: true });
+            let p = new_principals(origin);
+            let global = new_compartment_and_global_object(cx, clas, p);
+            let _c = enter_compartment(cx, global);
+            init_standard_classes(cx, global);
+            define_host_functions(cx, global, host_functions);
+            ret { global: global, principals: p, clas: clas };
+        }
+
+        /*
+         * Defines `name` on `obj` as `target`, wrapped for the scripts of
+         * `obj`'s global. If their principals do not subsume those of
+         * `target`'s global, they can hold it but not use it.
+         */
+        fn define_object(cx : context, obj : object, name : str,
+                         target : object) {
+            if !str::as_buf(name, { |buf|
+                jsrust::JSRust_DefineObject(*cx, *obj, buf, *target)
+            }) { fail; }
+        }
 }
 
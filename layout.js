@@ -1,4 +1,104 @@
+// Mutation records go to the host in batches, one per turn of the event
+// loop, in the binary format of jsrust_post_mutations (level 14). The host
+// calls _flushMutations at the end of every turn. With the "json"
+// protocol each record is posted on its own as a JSON string instead
+// (level 10: layout event).
+var _pendingMutations = [];
+
 document._setMutationHandler(function(mut) {
-    // 10: layout event
-    postMessage(10, JSON.stringify(mut));
+    if (_mutationProtocol === "json") {
+        postMessage(10, JSON.stringify(mut));
+    } else {
+        _pendingMutations.push(mut);
+    }
 });
+
+function _flushMutations() {
+    if (_pendingMutations.length) {
+        var batch = _pendingMutations;
+        _pendingMutations = [];
+        jsrust_post_mutations(batch);
+    }
+}
+
+// Serializes the document in the shape of the mutation records above, but
+// with full namespace URIs, so that the host can check its DOM mirror.
+function _mirrorSnapshot() {
+    const HTML_NAMESPACE = "http://www.w3.org/1999/xhtml";
+
+    // The binary records carry a lone surrogate as U+FFFD.
+    function text(s) {
+        return s.replace(/[\ud800-\udbff][\udc00-\udfff]|[\ud800-\udfff]/g,
+                         function(c) { return c.length === 2 ? c : "\ufffd"; });
+    }
+
+    function serialize(n) {
+        switch (n.nodeType) {
+        case 3: // TEXT_NODE
+            return text(n.data);
+        case 7: // PROCESSING_INSTRUCTION_NODE
+            return {pi: n.target, data: text(n.data)};
+        case 8: // COMMENT_NODE
+            return {comment: text(n.data)};
+        case 10: // DOCUMENT_TYPE_NODE
+            return {doctype: n.name};
+        }
+
+        var elt = {child: []};
+        if (n.nodeType === 9) { // DOCUMENT_NODE
+            elt.tag = "Document";
+            elt.ns = null;
+            elt.attr = [];
+        } else {
+            elt.ns = n.namespaceURI;
+            elt.tag = (n.namespaceURI === HTML_NAMESPACE && !n.prefix) ?
+                n.localName : n.tagName;
+            elt.attr = [];
+            for (var i = 0; i < n.attributes.length; i++) {
+                var a = n.attributes.item(i);
+                elt.attr.push({ns: a.namespaceURI, a: a.localName,
+                               v: text(a.value)});
+            }
+        }
+        for (var i = 0; i < n.childNodes.length; i++) {
+            elt.child.push(serialize(n.childNodes[i]));
+        }
+        return elt;
+    }
+
+    return serialize(document);
+}
+
+// Dispatches an event from the host at the node the mutation records call
+// nid, as if the user had caused it. props may set bubbles and cancelable,
+// a value to store in the target first (for input and change events), and
+// any other properties to copy onto the event, such as clientX.
+function _dispatchHostEvent(nid, type, props) {
+    const NON_BUBBLING = {focus: true, blur: true, load: true, unload: true,
+                          mouseenter: true, mouseleave: true};
+    const NON_CANCELABLE = {focus: true, blur: true, input: true,
+                            change: true, load: true, unload: true};
+
+    var init = {
+        bubbles: ("bubbles" in props) ? props.bubbles : !(type in NON_BUBBLING),
+        cancelable: ("cancelable" in props) ? props.cancelable :
+            !(type in NON_CANCELABLE)
+    };
+
+    // The first capturing listener to run sees the event before any page
+    // handler does.
+    function decorate(e) {
+        if ("value" in props) e.target.value = props.value;
+        for (var k in props) {
+            if (k !== "bubbles" && k !== "cancelable" && k !== "value")
+                e[k] = props[k];
+        }
+    }
+
+    document.addEventListener(type, decorate, true);
+    try {
+        document._dispatchRendererEvent(nid, type, init);
+    } finally {
+        document.removeEventListener(type, decorate, true);
+    }
+}
@@ -0,0 +1,569 @@
+/*
+ * Lays out the DOM mirror as CSS block and inline boxes.
+ *
+ * Text is set in a fixed-width font: each character is 0.6em wide and
+ * each line 1.2em high. Inline content wraps at spaces; inline elements
+ * take no margins or padding, and <br> ends a line. Vertical margins of
+ * adjacent blocks collapse. The result is a box tree whose rectangles are
+ * border boxes in px from the top left of the viewport.
+ *
+ * Relayout is incremental: mutation records mark the nodes they touch and
+ * their ancestors, and blocks that are unmarked and keep their containing
+ * width are moved into place rather than laid out again.
+ */
+
+use std;
+import std::map;
+import css::{ px, percent, auto };
+
+export rect, fragment, kind, block_box, anonymous_box, inline_box, text_box;
+export lbox, layout, new_layout, update, root_box, box_of, dump;
+export char_width, line_height;
+
+type rect = {x: float, y: float, w: float, h: float};
+
+// A run of text on one line.
+type fragment = {x: float, y: float, text: str, font_size: float,
+                 color: css::color};
+
+enum kind {
+    block_box,
+    anonymous_box,              // wraps inline content among blocks
+    inline_box,
+    text_box([fragment]),
+}
+
+// Anonymous boxes have nid 0.
+type lbox = {nid: uint, kind: kind, rect: rect, children: [lbox]};
+
+// A block's box tree, and where and how wide it was laid out.
+type cached = {cw: float, x: float, y: float, tree: lbox};
+
+type layout = @{
+    doc: @dom::document,
+    styles: css::resolver,
+    viewport_width: float,
+    mut root: option<lbox>,
+    mut cache: map::hashmap<uint, cached>,
+    // The resolver's sheets_generation when the cache was filled.
+    mut sheets_generation: uint,
+    // Filled in by the document's listener, which must not hold the
+    // layout itself.
+    changes: @mut [dom::change],
+    // Nodes to lay out again, and those whose descendants must be too.
+    mut dirty: map::hashmap<uint, ()>,
+    mut dirty_subtree: map::hashmap<uint, ()>,
+    // Blocks laid out and reused by the last update.
+    mut laid_out: uint,
+    mut reused: uint
+};
+
+
+fn char_width(font_size : float) -> float { ret font_size * 0.6; }
+
+fn line_height(font_size : float) -> float { ret font_size * 1.2; }
+
+
+fn new_layout(doc : @dom::document, styles : css::resolver,
+              viewport_width : float) -> layout {
+    let changes = @mut [];
+    dom::subscribe(doc, {|c| *changes += [c]; });
+    ret @{doc: doc, styles: styles, viewport_width: viewport_width,
+          mut root: option::none, mut cache: map::new_uint_hash(),
+          mut sheets_generation: 0u,
+          changes: changes, mut dirty: map::new_uint_hash(),
+          mut dirty_subtree: map::new_uint_hash(),
+          mut laid_out: 0u, mut reused: 0u};
+}
+
+
+fn mark(l : layout, nid : uint, subtree : bool) {
+    if subtree { l.dirty_subtree.insert(nid, ()); }
+    let n = nid;
+    while n != 0u {
+        l.dirty.insert(n, ());
+        n = dom::parent_of(l.doc, n);
+    }
+}
+
+
+// Turns the mutations seen since the last update into marks. A change to
+// a style sheet can restyle anything, so it throws the cache away.
+fn mark_changes(l : layout) {
+    let changes = *l.changes;
+    *l.changes = [];
+    for c in changes {
+        let (nid, subtree) = alt c {
+            dom::value_changed(n) { (dom::parent_of(l.doc, n), false) }
+            dom::attr_changed(n, _, _) | dom::attr_removed(n, _, _) {
+                (n, true)
+            }
+            dom::node_removed(_, p) { (p, false) }
+            dom::node_moved(n, old, _) {
+                mark(l, old, false);
+                (n, true)
+            }
+            dom::node_inserted(n, _) { (n, true) }
+        };
+        mark(l, nid, subtree);
+    }
+    let generation = css::sheets_generation(l.styles);
+    if generation != l.sheets_generation {
+        l.cache = map::new_uint_hash();
+        l.sheets_generation = generation;
+    }
+}
+
+
+fn resolve(len : css::length, cw : float) -> float {
+    ret alt len {
+        px(n) { n }
+        percent(n) { cw * n / 100.0 }
+        auto { 0.0 }
+    };
+}
+
+
+fn is_auto(len : css::length) -> bool {
+    ret alt len { auto { true } _ { false } };
+}
+
+
+fn max(a : float, b : float) -> float { ret if a > b { a } else { b }; }
+
+fn min(a : float, b : float) -> float { ret if a < b { a } else { b }; }
+
+
+fn translate(b : lbox, dx : float, dy : float) -> lbox {
+    let k = alt b.kind {
+        text_box(frags) {
+            text_box(vec::map(frags, {|f| {x: f.x + dx, y: f.y + dy with f} }))
+        }
+        k { k }
+    };
+    ret {kind: k,
+         rect: {x: b.rect.x + dx, y: b.rect.y + dy with b.rect},
+         children: vec::map(b.children, {|c| translate(c, dx, dy) })
+         with b};
+}
+
+
+fn style_of(l : layout, nid : uint) -> css::style {
+    ret alt css::computed_style(l.styles, nid) {
+        option::some(s) { s }
+        option::none { css::text_style(l.styles, nid) }
+    };
+}
+
+
+enum flow {
+    block_flow(uint),
+    inline_flow([uint]),
+}
+
+
+// Splits the children of a block into blocks and runs of inline content.
+fn flows(l : layout, nid : uint) -> [flow] {
+    let out = [], run = [];
+    for child in dom::children_of(l.doc, nid) {
+        alt dom::get_node(l.doc, child) {
+            dom::element(_) {
+                alt style_of(l, child).display {
+                    css::display_none { }
+                    css::block {
+                        if vec::len(run) > 0u { out += [inline_flow(run)]; run = []; }
+                        out += [block_flow(child)];
+                    }
+                    css::inline { run += [child]; }
+                }
+            }
+            dom::text(_, _) { run += [child]; }
+            _ { }
+        }
+    }
+    if vec::len(run) > 0u { out += [inline_flow(run)]; }
+    ret out;
+}
+
+
+/*
+ * Lays out the block `nid` with its border box's top left corner at
+ * (x, y) less its left margin, in a containing block `cw` wide.
+ */
+fn layout_block(l : layout, nid : uint, x : float, y : float, cw : float,
+                force : bool) -> lbox {
+    let force = force || l.dirty_subtree.contains_key(nid);
+    if !force && !l.dirty.contains_key(nid) {
+        alt l.cache.find(nid) {
+            option::some(c) {
+                if c.cw == cw {
+                    l.reused += 1u;
+                    ret translate(c.tree, x - c.x, y - c.y);
+                }
+            }
+            option::none { }
+        }
+    }
+    l.laid_out += 1u;
+
+    let s = style_of(l, nid),
+        pl = resolve(s.padding.left, cw), pr = resolve(s.padding.right, cw),
+        pt = resolve(s.padding.top, cw), pb = resolve(s.padding.bottom, cw),
+        ml = resolve(s.margin.left, cw), mr = resolve(s.margin.right, cw);
+
+    let w = if is_auto(s.width) {
+        max(0.0, cw - ml - mr - pl - pr)
+    } else {
+        let w = resolve(s.width, cw);
+        if is_auto(s.margin.left) && is_auto(s.margin.right) {
+            ml = max(0.0, (cw - w - pl - pr) / 2.0);
+        } else if is_auto(s.margin.left) {
+            ml = max(0.0, cw - w - pl - pr - mr);
+        }
+        w
+    };
+
+    let bx = x + ml,
+        (children, content_h) = layout_children(l, nid, bx + pl, y + pt, w, force);
+    let h = alt s.height {
+        px(n) { n }
+        _ { content_h }
+    };
+    let b = {nid: nid, kind: block_box,
+             rect: {x: bx, y: y, w: w + pl + pr, h: h + pt + pb},
+             children: children};
+    l.cache.insert(nid, {cw: cw, x: x, y: y, tree: b});
+    ret b;
+}
+
+
+// Lays out the contents of a block; returns the child boxes and the
+// height they take.
+fn layout_children(l : layout, nid : uint, x : float, y : float, w : float,
+                   force : bool) -> ([lbox], float) {
+    let boxes = [], cursor = y, prev_mb = 0.0, first = true,
+        fs = flows(l, nid);
+    for f in fs {
+        alt f {
+            block_flow(child) {
+                let s = style_of(l, child),
+                    mt = resolve(s.margin.top, w),
+                    mb = resolve(s.margin.bottom, w);
+                // Adjacent vertical margins collapse to the larger.
+                cursor += if first { mt } else { max(mt, prev_mb) - prev_mb };
+                let b = layout_block(l, child, x, cursor, w, force);
+                cursor = b.rect.y + b.rect.h + mb;
+                prev_mb = mb;
+                boxes += [b];
+            }
+            inline_flow(run) {
+                let (children, h) = layout_inline(l, run, x, cursor, w);
+                if h > 0.0 {
+                    if vec::len(fs) == 1u {
+                        boxes += children;
+                    } else {
+                        boxes += [{nid: 0u, kind: anonymous_box,
+                                   rect: {x: x, y: cursor, w: w, h: h},
+                                   children: children}];
+                    }
+                    cursor += h;
+                    prev_mb = 0.0;
+                }
+            }
+        }
+        first = false;
+    }
+    ret (boxes, cursor - y);
+}
+
+
+// A word, or a forced line break, with the text node it came from.
+type atom = @{
+    nid: uint,
+    text: str,
+    brk: bool,
+    space_before: bool,
+    font_size: float,
+    color: css::color,
+    mut x: float,
+    mut y: float
+};
+
+
+fn is_space(c : char) -> bool {
+    ret c == ' ' || c == '\t' || c == '\n' || c == '\r' || c == '\x0c';
+}
+
+
+fn collect_atoms(l : layout, nid : uint, atoms : @mut [atom],
+                 space : @mut bool) {
+    alt dom::get_node(l.doc, nid) {
+        dom::text(data, _) {
+            let s = css::text_style(l.styles, nid), word = "";
+            for c in str::chars(data) {
+                if is_space(c) {
+                    if !str::is_empty(word) {
+                        *atoms += [@{nid: nid, text: word, brk: false,
+                                    space_before: *space,
+                                    font_size: s.font_size, color: s.color,
+                                    mut x: 0.0, mut y: 0.0}];
+                        word = "";
+                        *space = false;
+                    }
+                    *space = true;
+                } else {
+                    str::push_char(word, c);
+                }
+            }
+            if !str::is_empty(word) {
+                *atoms += [@{nid: nid, text: word, brk: false,
+                            space_before: *space,
+                            font_size: s.font_size, color: s.color,
+                            mut x: 0.0, mut y: 0.0}];
+                *space = false;
+            }
+        }
+        dom::element(e) {
+            let s = style_of(l, nid);
+            alt s.display { css::display_none { ret; } _ { } }
+            if str::eq(e.tag, "br") && str::eq(e.ns, dom::HTML_NAMESPACE) {
+                *atoms += [@{nid: nid, text: "", brk: true, space_before: false,
+                            font_size: s.font_size, color: s.color,
+                            mut x: 0.0, mut y: 0.0}];
+                *space = false;
+                ret;
+            }
+            for child in dom::children_of(l.doc, nid) {
+                collect_atoms(l, child, atoms, space);
+            }
+        }
+        _ { }
+    }
+}
+
+
+// Sets the position of each atom, wrapping lines at `w`; returns the
+// height of the lines.
+fn set_lines(atoms : [atom], x : float, y : float, w : float) -> float {
+    let line_y = y, cx = 0.0, line_h = 0.0, start = 0u, i = 0u,
+        n = vec::len(atoms);
+    while i < n {
+        let a = atoms[i],
+            aw = char_width(a.font_size) * (str::char_len(a.text) as float),
+            gap = if a.space_before && cx > 0.0 { char_width(a.font_size) } else { 0.0 };
+        if !a.brk && cx > 0.0 && cx + gap + aw > w {
+            line_y += line_h;
+            cx = 0.0; line_h = 0.0; gap = 0.0;
+        }
+        a.x = x + cx + gap;
+        a.y = line_y;
+        cx += gap + aw;
+        line_h = max(line_h, line_height(a.font_size));
+        if a.brk {
+            line_y += line_h;
+            cx = 0.0; line_h = 0.0;
+        }
+        i += 1u;
+    }
+    ret line_y + line_h - y;
+}
+
+
+fn union(a : option<rect>, b : rect) -> option<rect> {
+    ret alt a {
+        option::none { option::some(b) }
+        option::some(r) {
+            let x0 = min(r.x, b.x), y0 = min(r.y, b.y),
+                x1 = max(r.x + r.w, b.x + b.w), y1 = max(r.y + r.h, b.y + b.h);
+            option::some({x: x0, y: y0, w: x1 - x0, h: y1 - y0})
+        }
+    };
+}
+
+
+// Builds the box for an inline node from the positioned atoms.
+fn inline_tree(l : layout, nid : uint, atoms : [atom]) -> option<lbox> {
+    alt dom::get_node(l.doc, nid) {
+        dom::text(_, _) {
+            // Words on the same line join into one fragment.
+            let frags = [], bounds = option::none;
+            for a in atoms {
+                if a.nid != nid { cont; }
+                let aw = char_width(a.font_size) * (str::char_len(a.text) as float),
+                    r = {x: a.x, y: a.y, w: aw, h: line_height(a.font_size)};
+                let n = vec::len(frags);
+                if n > 0u && frags[n - 1u].y == a.y {
+                    let last = frags[n - 1u],
+                        sep = if a.space_before { " " } else { "" },
+                        text = last.text + sep + a.text;
+                    frags = vec::slice(frags, 0u, n - 1u) + [{text: text with last}];
+                } else {
+                    frags += [{x: a.x, y: a.y, text: a.text,
+                               font_size: a.font_size, color: a.color}];
+                }
+                bounds = union(bounds, r);
+            }
+            ret alt bounds {
+                option::some(r) {
+                    option::some({nid: nid, kind: text_box(frags), rect: r,
+                                  children: []})
+                }
+                option::none { option::none }
+            };
+        }
+        dom::element(_) {
+            alt style_of(l, nid).display { css::display_none { ret option::none; } _ { } }
+            let children = [], bounds = option::none;
+            for a in atoms {
+                if a.nid == nid {
+                    bounds = union(bounds, {x: a.x, y: a.y, w: 0.0,
+                                            h: line_height(a.font_size)});
+                }
+            }
+            for child in dom::children_of(l.doc, nid) {
+                alt inline_tree(l, child, atoms) {
+                    option::some(b) {
+                        bounds = union(bounds, b.rect);
+                        children += [b];
+                    }
+                    option::none { }
+                }
+            }
+            ret alt bounds {
+                option::some(r) {
+                    option::some({nid: nid, kind: inline_box, rect: r,
+                                  children: children})
+                }
+                option::none { option::none }
+            };
+        }
+        _ { ret option::none; }
+    }
+}
+
+
+fn layout_inline(l : layout, run : [uint], x : float, y : float, w : float)
+        -> ([lbox], float) {
+    let atoms = @mut [], space = @mut false;
+    for nid in run {
+        collect_atoms(l, nid, atoms, space);
+    }
+    if vec::len(*atoms) == 0u { ret ([], 0.0); }
+    let h = set_lines(*atoms, x, y, w);
+    let boxes = [];
+    for nid in run {
+        alt inline_tree(l, nid, *atoms) {
+            option::some(b) { boxes += [b]; }
+            option::none { }
+        }
+    }
+    ret (boxes, h);
+}
+
+
+/* Lays out whatever has changed since the last update. */
+fn update(l : layout) {
+    mark_changes(l);
+    l.laid_out = 0u;
+    l.reused = 0u;
+    let root = vec::foldl(0u, dom::children_of(l.doc, 1u)) {|found, nid|
+        if found == 0u && option::is_some(css::computed_style(l.styles, nid)) {
+            nid
+        } else {
+            found
+        }
+    };
+    l.root = if root == 0u {
+        option::none
+    } else {
+        alt style_of(l, root).display {
+            css::display_none { option::none }
+            _ {
+                option::some(layout_block(l, root, 0.0, 0.0, l.viewport_width,
+                                          false))
+            }
+        }
+    };
+    l.dirty = map::new_uint_hash();
+    l.dirty_subtree = map::new_uint_hash();
+}
+
+
+/* The box of the root element, after an update. */
+fn root_box(l : layout) -> option<lbox> {
+    ret l.root;
+}
+
+
+/* The rectangle of a node's box, if it has one. */
+fn box_of(l : layout, nid : uint) -> option<rect> {
+    fn find(b : lbox, nid : uint) -> option<rect> {
+        if b.nid == nid { ret option::some(b.rect); }
+        for c in b.children {
+            alt find(c, nid) {
+                option::some(r) { ret option::some(r); }
+                option::none { }
+            }
+        }
+        ret option::none;
+    }
+    ret alt l.root {
+        option::some(b) { find(b, nid) }
+        option::none { option::none }
+    };
+}
+
+
+fn px_str(f : float) -> str {
+    ret int::str(if f < 0.0 { (f - 0.5) as int } else { (f + 0.5) as int });
+}
+
+
+fn rect_str(r : rect) -> str {
+    ret #fmt("(%s,%s %sx%s)", px_str(r.x), px_str(r.y), px_str(r.w), px_str(r.h));
+}
+
+
+/*
+ * The box tree as indented text, one box per line, with rectangles
+ * rounded to whole px. Text boxes list their fragments.
+ */
+fn dump(l : layout) -> str {
+    fn name(l : layout, b : lbox) -> str {
+        ret alt dom::get_node(l.doc, b.nid) {
+            dom::element(e) { e.tag }
+            _ { "" }
+        };
+    }
+    fn walk(l : layout, b : lbox, indent : str, out : @mut str) {
+        alt b.kind {
+            block_box {
+                *out += #fmt("%sblock %s #%u %s\n", indent, name(l, b), b.nid,
+                             rect_str(b.rect));
+            }
+            anonymous_box {
+                *out += #fmt("%sanonymous %s\n", indent, rect_str(b.rect));
+            }
+            inline_box {
+                *out += #fmt("%sinline %s #%u %s\n", indent, name(l, b), b.nid,
+                             rect_str(b.rect));
+            }
+            text_box(frags) {
+                *out += #fmt("%stext #%u %s\n", indent, b.nid, rect_str(b.rect));
+                for f in frags {
+                    *out += #fmt("%s  \"%s\" at %s,%s\n", indent, f.text,
+                                 px_str(f.x), px_str(f.y));
+                }
+            }
+        }
+        for c in b.children {
+            walk(l, c, indent + "  ", out);
+        }
+    }
+    let out = @mut "";
+    alt l.root {
+        option::some(b) { walk(l, b, "", out); }
+        option::none { }
+    }
+    ret *out;
+}
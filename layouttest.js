@@ -0,0 +1,14 @@
+// Removes a <style> after the first layout; see "make check-layout".
+
+var style = document.createElement("style");
+style.appendChild(document.createTextNode("p { width: 100px }"));
+document.head.appendChild(style);
+
+var p = document.createElement("p");
+p.appendChild(document.createTextNode("styled"));
+document.body.appendChild(p);
+
+// The next turn, so that the first batch has been laid out with the rule.
+setTimeout(function() {
+    document.head.removeChild(style);
+}, 0);
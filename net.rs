@@ -0,0 +1,132 @@
+/*
+ * Blocking TCP sockets for the host's networking tasks, optionally over
+ * TLS.
+ *
+ * Every call may wait on the network, so callers run on a scheduler of
+ * their own rather than on an actor's.
+ */
+
+import ctypes::{ size_t, c_int, void };
+import result::{ ok, err };
+
+export socket, tls_config, connect, connect_tls, write, read, close;
+
+#[link_args="-L."]
+#[link_name="spidermonkeyrustext"]
+native mod jsrust {
+    fn JSRust_NetConnect(host : *u8, port : u32) -> c_int;
+    fn JSRust_NetWrite(fd : c_int, buf : *u8, len : size_t) -> c_int;
+    fn JSRust_NetRead(fd : c_int, buf : *u8, len : size_t, timeout_ms : u32)
+        -> c_int;
+    fn JSRust_NetClose(fd : c_int);
+    fn JSRust_NetTimedOut() -> c_int;
+    fn JSRust_NetErrorString(code : c_int) -> str;
+
+    fn JSRust_TlsStart(fd : c_int, host : *u8, ca_file : *u8,
+                       trusted_cert : *u8) -> *void;
+    fn JSRust_TlsWrite(ssl : *void, buf : *u8, len : size_t) -> c_int;
+    fn JSRust_TlsRead(ssl : *void, fd : c_int, buf : *u8, len : size_t,
+                      timeout_ms : u32) -> c_int;
+    fn JSRust_TlsClose(ssl : *void);
+    fn JSRust_TlsError() -> str;
+}
+
+// `tls` is null for a plain connection.
+type socket = {fd: c_int, tls: *void};
+
+/*
+ * How to check servers' certificates: against the CAs in `ca_file`, or
+ * the system's when it is empty, and also against `trusted_cert` when
+ * one is given, such as a test server's self-signed certificate.
+ */
+type tls_config = {ca_file: str, trusted_cert: str};
+
+
+fn error_string(code : c_int) -> str {
+    ret jsrust::JSRust_NetErrorString(code);
+}
+
+
+fn connect(host : str, port : uint) -> result::t<socket, str> {
+    let fd = str::as_buf(host, { |buf|
+        jsrust::JSRust_NetConnect(buf, port as u32)
+    });
+    if fd < 0 as c_int {
+        ret err(#fmt("cannot connect to %s:%u: %s", host, port,
+                     error_string(fd)));
+    }
+    ret ok({fd: fd, tls: ptr::null()});
+}
+
+
+/* Connects and starts TLS, checking the certificate is valid for host. */
+fn connect_tls(host : str, port : uint, conf : tls_config)
+        -> result::t<socket, str> {
+    let s = alt connect(host, port) { ok(s) { s } err(e) { ret err(e); } };
+    let ssl = str::as_buf(host) {|h|
+        str::as_buf(conf.ca_file) {|ca|
+            str::as_buf(conf.trusted_cert) {|cert|
+                jsrust::JSRust_TlsStart(s.fd, h, ca, cert)
+            }
+        }
+    };
+    if ssl == ptr::null() {
+        close(s);
+        ret err(#fmt("cannot start TLS with %s:%u: %s", host, port,
+                     jsrust::JSRust_TlsError()));
+    }
+    ret ok({tls: ssl with s});
+}
+
+
+fn write(s : socket, data : [u8]) -> result::t<(), str> unsafe {
+    let p = vec::unsafe::to_ptr(data), len = vec::len(data) as size_t;
+    if s.tls != ptr::null() {
+        if jsrust::JSRust_TlsWrite(s.tls, p, len) < 0 as c_int {
+            ret err(jsrust::JSRust_TlsError());
+        }
+        ret ok(());
+    }
+    let n = jsrust::JSRust_NetWrite(s.fd, p, len);
+    if n < 0 as c_int {
+        ret err(error_string(n));
+    }
+    ret ok(());
+}
+
+
+/*
+ * Reads at most `max` bytes, waiting up to `timeout_ms` (0 for no limit).
+ * Gives none if the time ran out first, and an empty result if the peer
+ * closed the connection.
+ */
+fn read(s : socket, max : uint, timeout_ms : uint)
+        -> result::t<option<[u8]>, str> unsafe {
+    let buf = vec::init_elt(max, 0u8);
+    let n = if s.tls != ptr::null() {
+        jsrust::JSRust_TlsRead(s.tls, s.fd, vec::unsafe::to_ptr(buf),
+                               max as size_t, timeout_ms as u32)
+    } else {
+        jsrust::JSRust_NetRead(s.fd, vec::unsafe::to_ptr(buf),
+                               max as size_t, timeout_ms as u32)
+    };
+    if n == jsrust::JSRust_NetTimedOut() {
+        ret ok(option::none);
+    }
+    if n < 0 as c_int {
+        ret err(if s.tls != ptr::null() {
+            jsrust::JSRust_TlsError()
+        } else {
+            error_string(n)
+        });
+    }
+    ret ok(option::some(vec::slice(buf, 0u, n as uint)));
+}
+
+
+fn close(s : socket) {
+    if s.tls != ptr::null() {
+        jsrust::JSRust_TlsClose(s.tls);
+    }
+    jsrust::JSRust_NetClose(s.fd);
+}
@@ -0,0 +1,6 @@
+// Sends a lone surrogate through the binary mutation records; see
+// "make check-protocol".
+
+var p = document.createElement("p");
+p.appendChild(document.createTextNode("\ud800"));
+document.body.appendChild(p);
@@ -0,0 +1,174 @@
+/*
+ * Draws a laid out document without a window: as a grid of characters
+ * for terminals, or as a PPM image.
+ *
+ * The grid has one cell per character of medium (16px) text, and text of
+ * other sizes is placed by its position alone. The image is rasterized in
+ * software: block boxes are outlined and each character is drawn as a bar
+ * of ink in the text's color, which shows where text falls and how it
+ * wraps without needing a font.
+ */
+
+use std;
+import std::io;
+
+export to_text, to_ppm, write_ppm;
+
+const CELL_FONT_SIZE : float = 16.0;
+
+
+fn visit(b : layout::lbox, f : fn(layout::lbox)) {
+    f(b);
+    for c in b.children {
+        visit(c, f);
+    }
+}
+
+
+// The size of the drawing: the viewport's width and the document's height.
+fn extent(l : layout::layout) -> (float, float) {
+    let w = l.viewport_width, h = 0.0;
+    alt layout::root_box(l) {
+        option::some(root) {
+            visit(root) {|b|
+                if b.rect.y + b.rect.h > h { h = b.rect.y + b.rect.h; }
+            };
+        }
+        option::none { }
+    }
+    ret (w, h);
+}
+
+
+fn cell(v : float, size : float) -> int {
+    ret (v / size + 0.5) as int;
+}
+
+
+/* The text of the document, placed on a character grid. */
+fn to_text(l : layout::layout) -> str {
+    let cw = layout::char_width(CELL_FONT_SIZE),
+        lh = layout::line_height(CELL_FONT_SIZE),
+        (w, h) = extent(l),
+        cols = cell(w, cw) as uint,
+        rows = cell(h, lh) as uint + 1u,
+        grid = vec::init_elt_mut(cols * rows, ' ');
+
+    alt layout::root_box(l) {
+        option::some(root) {
+            visit(root) {|b|
+                alt b.kind {
+                    layout::text_box(frags) {
+                        for f in frags {
+                            let row = cell(f.y, lh), col = cell(f.x, cw);
+                            for c in str::chars(f.text) {
+                                if row >= 0 && (row as uint) < rows &&
+                                   col >= 0 && (col as uint) < cols {
+                                    grid[row as uint * cols + col as uint] = c;
+                                }
+                                col += 1;
+                            }
+                        }
+                    }
+                    _ { }
+                }
+            };
+        }
+        option::none { }
+    }
+
+    let lines = [], r = 0u;
+    while r < rows {
+        let line = "", c = 0u;
+        while c < cols {
+            str::push_char(line, grid[r * cols + c]);
+            c += 1u;
+        }
+        lines += [str::trim_right(line)];
+        r += 1u;
+    }
+    while vec::len(lines) > 0u && str::is_empty(vec::last_total(lines)) {
+        lines = vec::slice(lines, 0u, vec::len(lines) - 1u);
+    }
+    ret str::connect(lines, "\n") + "\n";
+}
+
+
+type canvas = {w: uint, h: uint, pixels: [mut u8]};
+
+
+fn fill(cv : canvas, x : float, y : float, w : float, h : float,
+        c : css::color) {
+    let x0 = if x < 0.0 { 0u } else { x as uint },
+        y0 = if y < 0.0 { 0u } else { y as uint },
+        x1 = if x + w < 0.0 { 0u } else { (x + w) as uint },
+        y1 = if y + h < 0.0 { 0u } else { (y + h) as uint };
+    if x1 > cv.w { x1 = cv.w; }
+    if y1 > cv.h { y1 = cv.h; }
+    let py = y0;
+    while py < y1 {
+        let px = x0;
+        while px < x1 {
+            let i = (py * cv.w + px) * 3u;
+            cv.pixels[i] = c.r;
+            cv.pixels[i + 1u] = c.g;
+            cv.pixels[i + 2u] = c.b;
+            px += 1u;
+        }
+        py += 1u;
+    }
+}
+
+
+fn outline(cv : canvas, r : layout::rect, c : css::color) {
+    fill(cv, r.x, r.y, r.w, 1.0, c);
+    fill(cv, r.x, r.y + r.h - 1.0, r.w, 1.0, c);
+    fill(cv, r.x, r.y, 1.0, r.h, c);
+    fill(cv, r.x + r.w - 1.0, r.y, 1.0, r.h, c);
+}
+
+
+/* The document as a binary PPM (P6) image on a white background. */
+fn to_ppm(l : layout::layout) -> [u8] {
+    let (w, h) = extent(l),
+        cv = {w: w as uint, h: if h < 1.0 { 1u } else { h as uint },
+              pixels: vec::init_elt_mut((w as uint) * (h as uint + 1u) * 3u, 255u8)},
+        box_color = {r: 208u8, g: 208u8, b: 208u8};
+
+    alt layout::root_box(l) {
+        option::some(root) {
+            visit(root) {|b|
+                alt b.kind {
+                    layout::block_box { outline(cv, b.rect, box_color); }
+                    layout::text_box(frags) {
+                        for f in frags {
+                            let cw = layout::char_width(f.font_size),
+                                lh = layout::line_height(f.font_size),
+                                x = f.x;
+                            for c in str::chars(f.text) {
+                                if c != ' ' {
+                                    fill(cv, x + cw * 0.1, f.y + lh * 0.25,
+                                         cw * 0.8, lh * 0.55, f.color);
+                                }
+                                x += cw;
+                            }
+                        }
+                    }
+                    _ { }
+                }
+            };
+        }
+        option::none { }
+    }
+
+    let header = str::bytes(#fmt("P6\n%u %u\n255\n", cv.w, cv.h));
+    ret header + vec::slice(cv.pixels, 0u, cv.w * cv.h * 3u);
+}
+
+
+fn write_ppm(l : layout::layout, path : str) -> result::t<(), str> {
+    alt io::file_writer(path, [io::create, io::truncate]) {
+        result::ok(w) { w.write(to_ppm(l)); ret result::ok(()); }
+        result::err(e) { ret result::err(e); }
+    }
+}
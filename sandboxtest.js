@@ -0,0 +1,28 @@
+// Exercises a sandboxed global; see "make check-sandbox", which runs this
+// with --sandbox=http://sandbox.test.
+
+function check(name, ok, detail) {
+    print((ok ? "ok " : "FAIL ") + name + (ok ? "" : ": " + detail));
+}
+
+check("sandbox runs scripts", sandbox.eval("1 + 1") === 2, "");
+
+var read = sandbox.eval("try { String(parent.document); } catch (e) { 'denied: ' + e.message; }");
+check("sandbox cannot read the parent's objects",
+      read === "denied: Permission denied: http://sandbox.test may not access [System]",
+      read);
+
+var written = sandbox.eval("try { parent.leaked = 1; 'written'; } catch (e) { 'denied'; }");
+check("sandbox cannot write the parent's objects",
+      written === "denied" && typeof leaked === "undefined", written);
+
+var called = sandbox.eval("try { parent.print('FAIL called'); 'called'; } catch (e) { 'denied'; }");
+check("sandbox cannot call the parent's functions", called === "denied", called);
+
+check("sandbox has only its whitelisted host functions",
+      sandbox.eval("typeof print + ' ' + typeof jsrust_read + ' ' + typeof postMessage") ===
+          "function undefined undefined",
+      sandbox.eval("typeof print + ' ' + typeof jsrust_read + ' ' + typeof postMessage"));
+
+check("parent can read the sandbox's objects",
+      sandbox.eval("var shared = {a: 1}; shared").a === 1, "");
@@ -0,0 +1,421 @@
+/*
+ * Finds elements in the DOM mirror by id, tag name or CSS selector.
+ *
+ * The selector subset is type and universal selectors, #id, .class,
+ * attribute selectors ([a], [a=v], [a~=v], [a|=v], [a^=v], [a$=v],
+ * [a*=v]), the descendant and child combinators, and comma-separated
+ * groups. Results are nids in document order.
+ */
+
+import result::{ ok, err };
+
+export selector, parse, matches, split, specificity;
+export get_element_by_id, get_elements_by_tag_name;
+export query_selector, query_selector_all;
+
+enum attr_op {
+    exists,
+    equals,
+    includes,       // ~=, one of a whitespace-separated list
+    dash_match,     // |=, equal or followed by "-"
+    prefix,         // ^=
+    suffix,         // $=
+    substring,      // *=
+}
+
+enum simple {
+    type_sel(str),          // lowercased; "*" is the universal selector
+    id_sel(str),
+    class_sel(str),
+    attr_sel(str, attr_op, str),
+}
+
+enum combinator {
+    descendant,
+    child,
+}
+
+// A compound selector and the combinator joining it to the one on its
+// left. The leftmost step's combinator is unused.
+type step = {comb: combinator, simple: [simple]};
+
+// A comma-separated group; each complex selector is its steps from left
+// to right.
+type selector = [[step]];
+
+
+fn is_name_byte(b : u8) -> bool {
+    ret (b >= 'a' as u8 && b <= 'z' as u8) || (b >= 'A' as u8 && b <= 'Z' as u8) ||
+        (b >= '0' as u8 && b <= '9' as u8) || b == '-' as u8 || b == '_' as u8 ||
+        b >= 0x80u8;
+}
+
+
+fn is_space(b : u8) -> bool {
+    ret b == ' ' as u8 || b == '\t' as u8 || b == '\n' as u8 ||
+        b == '\r' as u8 || b == 0x0cu8;
+}
+
+
+type parser = {src: str, mut pos: uint};
+
+
+fn peek_byte(p : parser) -> u8 {
+    ret if p.pos < str::len(p.src) { p.src[p.pos] } else { 0u8 };
+}
+
+
+fn skip_space(p : parser) -> bool {
+    let start = p.pos;
+    while p.pos < str::len(p.src) && is_space(p.src[p.pos]) {
+        p.pos += 1u;
+    }
+    ret p.pos > start;
+}
+
+
+fn parse_name(p : parser) -> result::t<str, str> {
+    let start = p.pos;
+    while p.pos < str::len(p.src) && is_name_byte(p.src[p.pos]) {
+        p.pos += 1u;
+    }
+    if p.pos == start {
+        ret err(#fmt("expected a name at offset %u of \"%s\"", start, p.src));
+    }
+    ret ok(str::slice(p.src, start, p.pos));
+}
+
+
+fn parse_value(p : parser) -> result::t<str, str> {
+    let q = peek_byte(p);
+    if q != '"' as u8 && q != '\'' as u8 {
+        ret parse_name(p);
+    }
+    let start = p.pos + 1u;
+    alt str::index_from(p.src, q, start, str::len(p.src)) {
+        -1 { ret err(#fmt("unterminated string in \"%s\"", p.src)); }
+        end {
+            p.pos = end as uint + 1u;
+            ret ok(str::slice(p.src, start, end as uint));
+        }
+    }
+}
+
+
+fn parse_attr(p : parser) -> result::t<simple, str> {
+    // Just past the "[".
+    skip_space(p);
+    let name = alt parse_name(p) { ok(n) { str::to_lower(n) } err(e) { ret err(e); } };
+    skip_space(p);
+    let op = alt peek_byte(p) as char {
+        ']' { p.pos += 1u; ret ok(attr_sel(name, exists, "")); }
+        '=' { equals }
+        '~' { includes }
+        '|' { dash_match }
+        '^' { prefix }
+        '$' { suffix }
+        '*' { substring }
+        _ { ret err(#fmt("bad attribute selector in \"%s\"", p.src)); }
+    };
+    p.pos += 1u;
+    alt op {
+        equals { }
+        _ {
+            if peek_byte(p) != '=' as u8 {
+                ret err(#fmt("bad attribute selector in \"%s\"", p.src));
+            }
+            p.pos += 1u;
+        }
+    }
+    skip_space(p);
+    let value = alt parse_value(p) { ok(v) { v } err(e) { ret err(e); } };
+    skip_space(p);
+    if peek_byte(p) != ']' as u8 {
+        ret err(#fmt("expected ] at offset %u of \"%s\"", p.pos, p.src));
+    }
+    p.pos += 1u;
+    ret ok(attr_sel(name, op, value));
+}
+
+
+fn parse_compound(p : parser) -> result::t<[simple], str> {
+    let simple = [];
+    if peek_byte(p) == '*' as u8 {
+        p.pos += 1u;
+        simple += [type_sel("*")];
+    } else if is_name_byte(peek_byte(p)) {
+        alt parse_name(p) {
+            ok(n) { simple += [type_sel(str::to_lower(n))]; }
+            err(e) { ret err(e); }
+        }
+    }
+    while true {
+        let b = peek_byte(p);
+        if b == '#' as u8 || b == '.' as u8 {
+            p.pos += 1u;
+            let n = alt parse_name(p) { ok(n) { n } err(e) { ret err(e); } };
+            simple += [if b == '#' as u8 { id_sel(n) } else { class_sel(n) }];
+        } else if b == '[' as u8 {
+            p.pos += 1u;
+            alt parse_attr(p) {
+                ok(s) { simple += [s]; }
+                err(e) { ret err(e); }
+            }
+        } else {
+            break;
+        }
+    }
+    if vec::len(simple) == 0u {
+        ret err(#fmt("expected a selector at offset %u of \"%s\"", p.pos, p.src));
+    }
+    ret ok(simple);
+}
+
+
+fn parse_complex(p : parser) -> result::t<[step], str> {
+    let steps = [];
+    let comb = descendant;
+    while true {
+        alt parse_compound(p) {
+            ok(s) { steps += [{comb: comb, simple: s}]; }
+            err(e) { ret err(e); }
+        }
+        let spaced = skip_space(p);
+        let b = peek_byte(p);
+        if b == 0u8 || b == ',' as u8 {
+            break;
+        } else if b == '>' as u8 {
+            p.pos += 1u;
+            skip_space(p);
+            comb = child;
+        } else if spaced {
+            comb = descendant;
+        } else {
+            ret err(#fmt("unexpected '%c' at offset %u of \"%s\"",
+                         b as char, p.pos, p.src));
+        }
+    }
+    ret ok(steps);
+}
+
+
+/* Parses a selector group such as "ul > li.item, #main a[href^=http]". */
+fn parse(src : str) -> result::t<selector, str> {
+    let p = {src: src, mut pos: 0u};
+    let group = [];
+    while true {
+        skip_space(p);
+        alt parse_complex(p) {
+            ok(c) { group += [c]; }
+            err(e) { ret err(e); }
+        }
+        if peek_byte(p) == 0u8 { break; }
+        p.pos += 1u;    // ","
+    }
+    ret ok(group);
+}
+
+
+/* The selectors of a group, each as a group of its own. */
+fn split(sel : selector) -> [selector] {
+    ret vec::map(sel, {|steps| [steps] });
+}
+
+
+/*
+ * The CSS specificity of the most specific selector in the group, as
+ * ids * 65536 + (classes and attributes) * 256 + types.
+ */
+fn specificity(sel : selector) -> uint {
+    let best = 0u;
+    for steps in sel {
+        let ids = 0u, classes = 0u, types = 0u;
+        for step in steps {
+            for s in step.simple {
+                alt s {
+                    type_sel(t) { if !str::eq(t, "*") { types += 1u; } }
+                    id_sel(_) { ids += 1u; }
+                    class_sel(_) | attr_sel(_, _, _) { classes += 1u; }
+                }
+            }
+        }
+        let spec = ids * 65536u + classes * 256u + types;
+        if spec > best { best = spec; }
+    }
+    ret best;
+}
+
+
+// Element nodes other than the document itself.
+fn as_element(doc : @dom::document, nid : uint) -> option<dom::element> {
+    if nid <= 1u { ret option::none; }
+    ret alt dom::get_node(doc, nid) {
+        dom::element(e) { option::some(e) }
+        _ { option::none }
+    };
+}
+
+
+fn get_attr(e : dom::element, name : str) -> option<str> {
+    for a in *e.attr {
+        if str::is_empty(a.ns) && str::eq(a.name, name) {
+            ret option::some(a.value);
+        }
+    }
+    ret option::none;
+}
+
+
+fn words(s : str) -> [str] {
+    ret vec::filter(str::split_func(s, {|c| c == ' ' || c == '\t' || c == '\n' ||
+                                               c == '\r' || c == '\x0c' }),
+                    {|w| !str::is_empty(w) });
+}
+
+
+fn local_name(e : dom::element) -> str {
+    let (_prefix, local) = dom::split_qname(e.tag);
+    ret local;
+}
+
+
+fn matches_attr(value : str, op : attr_op, want : str) -> bool {
+    ret alt op {
+        exists { true }
+        equals { str::eq(value, want) }
+        includes { vec::any(words(value), {|w| str::eq(w, want) }) }
+        dash_match {
+            str::eq(value, want) || str::starts_with(value, want + "-")
+        }
+        prefix { !str::is_empty(want) && str::starts_with(value, want) }
+        suffix { !str::is_empty(want) && str::ends_with(value, want) }
+        substring { !str::is_empty(want) && str::find(value, want) >= 0 }
+    };
+}
+
+
+fn matches_simple(e : dom::element, s : simple) -> bool {
+    ret alt s {
+        type_sel(t) {
+            str::eq(t, "*") || str::eq(t, str::to_lower(local_name(e)))
+        }
+        id_sel(id) {
+            alt get_attr(e, "id") {
+                option::some(v) { str::eq(v, id) }
+                option::none { false }
+            }
+        }
+        class_sel(c) {
+            alt get_attr(e, "class") {
+                option::some(v) { vec::any(words(v), {|w| str::eq(w, c) }) }
+                option::none { false }
+            }
+        }
+        attr_sel(name, op, want) {
+            alt get_attr(e, name) {
+                option::some(v) { matches_attr(v, op, want) }
+                option::none { false }
+            }
+        }
+    };
+}
+
+
+fn matches_compound(doc : @dom::document, nid : uint, simple : [simple]) -> bool {
+    ret alt as_element(doc, nid) {
+        option::some(e) { vec::all(simple, {|s| matches_simple(e, s) }) }
+        option::none { false }
+    };
+}
+
+
+// Matches steps[0..n] with steps[n - 1] against `nid`, working leftwards
+// through its ancestors.
+fn matches_steps(doc : @dom::document, nid : uint, steps : [step], n : uint) -> bool {
+    let last = steps[n - 1u];
+    if !matches_compound(doc, nid, last.simple) { ret false; }
+    if n == 1u { ret true; }
+    let parent = dom::parent_of(doc, nid);
+    alt last.comb {
+        child { ret matches_steps(doc, parent, steps, n - 1u); }
+        descendant {
+            while parent > 1u {
+                if matches_steps(doc, parent, steps, n - 1u) { ret true; }
+                parent = dom::parent_of(doc, parent);
+            }
+            ret false;
+        }
+    }
+}
+
+
+/* Whether the element `nid` matches any selector in the group. */
+fn matches(doc : @dom::document, nid : uint, sel : selector) -> bool {
+    ret vec::any(sel, {|steps| matches_steps(doc, nid, steps, vec::len(steps)) });
+}
+
+
+// Calls `f` on each element below `root` in document order, stopping when
+// it returns false.
+fn each_descendant(doc : @dom::document, root : uint, f : fn(uint) -> bool) -> bool {
+    for child in dom::children_of(doc, root) {
+        if option::is_some(as_element(doc, child)) {
+            if !f(child) { ret false; }
+            if !each_descendant(doc, child, f) { ret false; }
+        }
+    }
+    ret true;
+}
+
+
+fn find_all(doc : @dom::document, root : uint, pred : fn(uint) -> bool) -> [uint] {
+    let found = [];
+    each_descendant(doc, root) {|nid|
+        if pred(nid) { found += [nid]; }
+        true
+    };
+    ret found;
+}
+
+
+fn find_first(doc : @dom::document, root : uint, pred : fn(uint) -> bool)
+        -> option<uint> {
+    let found = option::none;
+    each_descendant(doc, root) {|nid|
+        if pred(nid) { found = option::some(nid); false } else { true }
+    };
+    ret found;
+}
+
+
+/* The first element in the document whose id attribute is `id`. */
+fn get_element_by_id(doc : @dom::document, id : str) -> option<uint> {
+    ret find_first(doc, 1u) {|nid| matches_compound(doc, nid, [id_sel(id)]) };
+}
+
+
+/* The elements below `root` with the given tag name, or all for "*". */
+fn get_elements_by_tag_name(doc : @dom::document, root : uint, name : str) -> [uint] {
+    let name = str::to_lower(name);
+    ret find_all(doc, root) {|nid| matches_compound(doc, nid, [type_sel(name)]) };
+}
+
+
+/* The first element below `root` that matches the selector. */
+fn query_selector(doc : @dom::document, root : uint, src : str)
+        -> result::t<option<uint>, str> {
+    ret alt parse(src) {
+        ok(sel) { ok(find_first(doc, root) {|nid| matches(doc, nid, sel) }) }
+        err(e) { err(e) }
+    };
+}
+
+
+/* Every element below `root` that matches the selector. */
+fn query_selector_all(doc : @dom::document, root : uint, src : str)
+        -> result::t<[uint], str> {
+    ret alt parse(src) {
+        ok(sel) { ok(find_all(doc, root) {|nid| matches(doc, nid, sel) }) }
+        err(e) { err(e) }
+    };
+}
@@ -1,10 +1,31 @@
 #include <js/jsapi.h>
+#include <js/jswrapper.h>
 #include <cassert>
 #include <cstdlib>
+#include <climits>
 #include <cstring>
+#include <cstdio>
+#include <cstdarg>
 #include <stdint.h>
 #include <pthread.h>
+#include <signal.h>
+#include <time.h>
 #include <errno.h>
+#include <unistd.h>
+#include <netdb.h>
+#include <poll.h>
+#include <sys/types.h>
+#include <sys/stat.h>
+#include <sys/socket.h>
+#include <arpa/inet.h>
+#include <openssl/ssl.h>
+#include <openssl/err.h>
+#include <openssl/x509v3.h>
+#include <openssl/sha.h>
+#include <openssl/rand.h>
+#include <vector>
+#include <map>
+#include <string>
 
 /*
  * Rust API declarations.
@@ -59,6 +80,49 @@ public:
     }
 };
 
+/* A Rust [u8], for data that is not a C string. */
+class rust_bytes {
+private:
+    uintptr_t fill;
+    uintptr_t alloc;
+    uint8_t data[0];
+
+    rust_bytes() { /* Don't call me. */ }
+
+public:
+    static rust_bytes *make(const void *data, size_t len) {
+        size_t obj_len = sizeof(rust_bytes) + len;
+        rust_s_shared_malloc_args args = { 0, obj_len, NULL };
+        upcall_s_shared_malloc(&args);
+
+        rust_bytes *bytes = reinterpret_cast<rust_bytes *>(args.retval);
+        bytes->fill = len;
+        bytes->alloc = len;
+        if (len)
+            memcpy(bytes->data, data, len);
+        return bytes;
+    }
+
+    /* A string's UTF-8, NULs and all; NULL if it cannot be encoded. */
+    static rust_bytes *encode(JSContext *cx, JSString *str) {
+        size_t len = JS_GetStringEncodingLength(cx, str);
+        if (len == (size_t)-1)
+            return NULL;
+        rust_bytes *bytes = make(NULL, len);
+        JS_EncodeStringToBuffer(str, reinterpret_cast<char *>(bytes->data), len);
+        return bytes;
+    }
+};
+
+/*
+ * Strings cross between JS and Rust as UTF-8, in both directions.
+ * SpiderMonkey only honours this if it is set before the first runtime is
+ * made, so it is set when the library is loaded.
+ */
+static struct jsrust_utf8_init {
+    jsrust_utf8_init() { JS_SetCStringsAreUTF8(); }
+} utf8_init;
+
 /*
  * SpiderMonkey helpers, needed since Rust doesn't support C++ global
  * variables.
@@ -92,11 +156,59 @@ extern "C" JSFinalizeOp JSRust_GetFinalizeStub() {
 
 namespace {
 
+/* What a synchronous request hands back: the head of the response as
+ * JSON and its body, or else an error. */
+struct jsrust_sync_result {
+    std::string head;
+    std::string body;
+    std::string error;
+};
+
+typedef void (*jsrust_sync_hook)(void *data, const char *request,
+                                 size_t len, jsrust_sync_result *result);
+
+typedef void (*jsrust_job_hook)(void *data, jsval job);
+
+typedef void (*jsrust_cookie_hook)(void *data, const char *url,
+                                   size_t url_len, const char *value,
+                                   size_t value_len, std::string *result);
+
 struct jsrust_context_priv {
+    JSContext *cx;
     const type_desc *msg_tydesc;
     rust_chan_pkg msg_chan;
 
-    jsrust_context_priv() : msg_tydesc(NULL), msg_chan() {}
+    /* Script CPU budget, guarded by watchdog_mutex. */
+    uint32_t budget_ms;
+    bool armed;
+    bool expired;
+    clockid_t clock;
+    uint64_t deadline_ns;
+    jsrust_context_priv *next_armed;
+
+    /* Filesystem policy; see JSRust_SetFilePolicy. */
+    bool fs_restricted;
+    std::string fs_root;
+    std::string fs_real_root;   /* fs_root with symlinks resolved */
+    uint32_t fs_max_bytes;
+
+    /* Synchronous XMLHttpRequest; see JSRust_SetSyncHook. */
+    jsrust_sync_hook sync_hook;
+    void *sync_data;
+
+    /* The host's job queue; see JSRust_SetJobHook. */
+    jsrust_job_hook job_hook;
+    void *job_data;
+
+    /* document.cookie; see JSRust_SetCookieHook. */
+    jsrust_cookie_hook cookie_hook;
+    void *cookie_data;
+
+    jsrust_context_priv() : cx(NULL), msg_tydesc(NULL), msg_chan(),
+        budget_ms(0), armed(false), expired(false), clock(0),
+        deadline_ns(0), next_armed(NULL), fs_restricted(false),
+        fs_root(), fs_real_root(), fs_max_bytes(0), sync_hook(NULL), sync_data(NULL),
+        job_hook(NULL), job_data(NULL), cookie_hook(NULL), cookie_data(NULL) {}
 };
 
 struct jsrust_message {
@@ -105,6 +217,7 @@ struct jsrust_message {
     uint32_t tag;
     uint32_t timeout;
     uint32_t pad;
+    rust_bytes *bytes;
 };
 
 
@@ -169,10 +282,29 @@ enum IO_OP {
     SPAWN,
     CAST,
     TIME,
-    EXIT
+    EXIT,
+    LAYOUT,
+    FILE_READ,
+    FILE_WRITE,
+    FILE_ERROR,
+    MUTATIONS,
+    HTTP_REQUEST,
+    HTTP_HEADERS,
+    HTTP_DATA,
+    HTTP_DONE,
+    HTTP_ERROR,
+    HTTP_ABORT,
+    CLEAR_TIMEOUT,
+    WS_OPEN,
+    WS_SEND,
+    WS_CLOSE,
+    WS_OPENED,
+    WS_MESSAGE,
+    WS_CLOSED,
+    WS_ERROR
 };
 
-uint32_t jsrust_send_msg(JSContext *cx, enum IO_OP op, rust_str *data, uint32_t req_id, uint32_t timeout) {
+uint32_t jsrust_send_msg(JSContext *cx, enum IO_OP op, rust_str *data, uint32_t req_id, uint32_t timeout, uint32_t pad = 0, rust_bytes *bytes = NULL) {
     void *priv_p = JS_GetContextPrivate(cx);
     assert(priv_p && "No private data associated with context!");
     jsrust_context_priv *priv =
@@ -183,7 +315,9 @@ uint32_t jsrust_send_msg(JSContext *cx, enum IO_OP op, rust_str *data, uint32_t
         my_num = io_op_num++;
     }
 
-    jsrust_message evt = { op, data, my_num, timeout, 0 };
+    if (!bytes)
+        bytes = rust_bytes::make(NULL, 0);
+    jsrust_message evt = { op, data, my_num, timeout, pad, bytes };
 
     chan_id_send(priv->msg_tydesc, priv->msg_chan.task,
                  priv->msg_chan.port, &evt);
@@ -204,6 +338,198 @@ void jsrust_report_error(JSContext *cx, const char *c_message,
     jsrust_send_msg(cx, STDERR, message, 0, 0);
 }
 
+/*
+ * Throws a JS Error with the given message, rather than reporting it, so
+ * that scripts can catch it.
+ */
+JSBool jsrust_throw_error(JSContext *cx, const char *fmt, ...) {
+    char buf[1024];
+    va_list ap;
+    va_start(ap, fmt);
+    vsnprintf(buf, sizeof(buf), fmt, ap);
+    va_end(ap);
+
+    JSString *msg = JS_NewStringCopyZ(cx, buf);
+    if (!msg)
+        return JS_FALSE;
+
+    jsval ctor, err, arg = STRING_TO_JSVAL(msg);
+    JSObject *global = JS_GetGlobalForScopeChain(cx);
+    if (!global || !JS_GetProperty(cx, global, "Error", &ctor) ||
+        JSVAL_IS_PRIMITIVE(ctor)) {
+        JS_SetPendingException(cx, arg);
+        return JS_FALSE;
+    }
+
+    JSObject *errobj = JS_New(cx, JSVAL_TO_OBJECT(ctor), 1, &arg);
+    err = errobj ? OBJECT_TO_JSVAL(errobj) : arg;
+    JS_SetPendingException(cx, err);
+    return JS_FALSE;
+}
+
+/* Whether path is root or lies under it. */
+bool jsrust_path_within(const std::string &path, const std::string &root) {
+    if (path.compare(0, root.size(), root) != 0)
+        return false;
+    return path.size() == root.size() || root == "/" ||
+           path[root.size()] == '/';
+}
+
+/*
+ * Follows the symlinks in a resolved path, so that one under the root
+ * cannot lead out of it. A file that does not exist yet, as when writing,
+ * is looked for in its real directory, and may not be a dangling link.
+ */
+bool jsrust_real_path(jsrust_context_priv *priv, std::string &path) {
+    char buf[PATH_MAX];
+    if (realpath(path.c_str(), buf)) {
+        path = buf;
+        return jsrust_path_within(path, priv->fs_real_root);
+    }
+    if (errno != ENOENT)
+        return false;
+
+    std::string::size_type slash = path.rfind('/');
+    std::string dir = path.substr(0, slash ? slash : 1),
+        name = path.substr(slash + 1);
+    struct stat st;
+    if (!realpath(dir.c_str(), buf) || lstat(path.c_str(), &st) == 0)
+        return false;
+    path = buf;
+    if (path != "/")
+        path += "/";
+    path += name;
+    return jsrust_path_within(path, priv->fs_real_root);
+}
+
+/*
+ * Resolves a script-supplied path against the context's filesystem root.
+ * ".." is resolved lexically, and fails if it would leave the root;
+ * absolute paths are taken relative to the root. The result must still be
+ * under the root once symlinks are followed.
+ */
+bool jsrust_resolve_path(JSContext *cx, const char *path, std::string &out) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    if (!priv->fs_restricted) {
+        out = path;
+        return true;
+    }
+
+    std::vector<std::string> parts;
+    for (const char *p = path; *p; ) {
+        const char *end = strchr(p, '/');
+        std::string part(p, end ? size_t(end - p) : strlen(p));
+        if (part == "..") {
+            if (parts.empty())
+                return false;
+            parts.pop_back();
+        } else if (!part.empty() && part != ".") {
+            parts.push_back(part);
+        }
+        if (!end)
+            break;
+        p = end + 1;
+    }
+
+    out = priv->fs_root;
+    for (size_t i = 0; i < parts.size(); i++) {
+        out += "/";
+        out += parts[i];
+    }
+    return jsrust_real_path(priv, out);
+}
+
+/*
+ * Script watchdog.
+ *
+ * A single background thread watches every context whose CPU budget is
+ * armed. Once the thread running a context has used up its budget, the
+ * watchdog marks the context expired and triggers its operation
+ * callback, which terminates the running script.
+ */
+
+static pthread_mutex_t watchdog_mutex = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t watchdog_cond = PTHREAD_COND_INITIALIZER;
+static jsrust_context_priv *watchdog_armed = NULL;
+static bool watchdog_started = false;
+
+/* How often the watchdog samples the clocks of armed contexts. */
+static const long WATCHDOG_INTERVAL_NS = 5 * 1000 * 1000;
+
+uint64_t jsrust_clock_ns(clockid_t clock) {
+    struct timespec ts;
+    if (clock_gettime(clock, &ts) != 0)
+        return 0;
+    return uint64_t(ts.tv_sec) * 1000000000ull + uint64_t(ts.tv_nsec);
+}
+
+void *jsrust_watchdog_main(void *) {
+    pthread_mutex_lock(&watchdog_mutex);
+    for (;;) {
+        while (!watchdog_armed)
+            pthread_cond_wait(&watchdog_cond, &watchdog_mutex);
+
+        for (jsrust_context_priv *p = watchdog_armed; p; p = p->next_armed) {
+            if (!p->expired && jsrust_clock_ns(p->clock) >= p->deadline_ns) {
+                p->expired = true;
+                JS_TriggerOperationCallback(p->cx);
+            }
+        }
+
+        pthread_mutex_unlock(&watchdog_mutex);
+        struct timespec interval = { 0, WATCHDOG_INTERVAL_NS };
+        nanosleep(&interval, NULL);
+        pthread_mutex_lock(&watchdog_mutex);
+    }
+    return NULL;
+}
+
+void jsrust_unlink_armed(jsrust_context_priv *priv) {
+    jsrust_context_priv **pp = &watchdog_armed;
+    while (*pp) {
+        if (*pp == priv) {
+            *pp = priv->next_armed;
+            break;
+        }
+        pp = &(*pp)->next_armed;
+    }
+    priv->next_armed = NULL;
+    priv->armed = false;
+}
+
+JSBool jsrust_operation_callback(JSContext *cx) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    pthread_mutex_lock(&watchdog_mutex);
+    bool expired = priv->expired;
+    uint32_t budget_ms = priv->budget_ms;
+    if (expired)
+        jsrust_unlink_armed(priv);
+    priv->expired = false;
+    pthread_mutex_unlock(&watchdog_mutex);
+
+    if (!expired)
+        return JS_TRUE;
+
+    /*
+     * Returning false without a pending exception terminates the script
+     * with an uncatchable error, so report it on the stderr channel
+     * ourselves.
+     */
+    char buf[128];
+    snprintf(buf, sizeof(buf),
+             "script terminated: exceeded CPU budget of %u ms", budget_ms);
+    jsrust_send_msg(cx, STDERR, rust_str::make(buf), 0, 0);
+    return JS_FALSE;
+}
+
 }   /* end anonymous namespace */
 
 extern "C" JSContext *JSRust_NewContext(JSRuntime *rt, size_t size) {
@@ -212,10 +538,63 @@ extern "C" JSContext *JSRust_NewContext(JSRuntime *rt, size_t size) {
         return NULL;
 
     jsrust_context_priv *priv = new jsrust_context_priv();
+    priv->cx = cx;
     JS_SetContextPrivate(cx, priv);
     return cx;
 }
 
+extern "C" void JSRust_SetScriptBudget(JSContext *cx, uint32_t budget_ms) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    pthread_mutex_lock(&watchdog_mutex);
+    priv->budget_ms = budget_ms;
+    if (!watchdog_started && budget_ms) {
+        pthread_t thread;
+        pthread_create(&thread, NULL, jsrust_watchdog_main, NULL);
+        pthread_detach(thread);
+        watchdog_started = true;
+    }
+    pthread_mutex_unlock(&watchdog_mutex);
+
+    JS_SetOperationCallback(cx, jsrust_operation_callback);
+}
+
+extern "C" void JSRust_ArmWatchdog(JSContext *cx) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    pthread_mutex_lock(&watchdog_mutex);
+    if (priv->budget_ms && !priv->armed &&
+        pthread_getcpuclockid(pthread_self(), &priv->clock) == 0) {
+        priv->deadline_ns = jsrust_clock_ns(priv->clock) +
+            uint64_t(priv->budget_ms) * 1000000ull;
+        priv->expired = false;
+        priv->armed = true;
+        priv->next_armed = watchdog_armed;
+        watchdog_armed = priv;
+        pthread_cond_signal(&watchdog_cond);
+    }
+    pthread_mutex_unlock(&watchdog_mutex);
+}
+
+extern "C" void JSRust_DisarmWatchdog(JSContext *cx) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    pthread_mutex_lock(&watchdog_mutex);
+    if (priv->armed)
+        jsrust_unlink_armed(priv);
+    priv->expired = false;
+    pthread_mutex_unlock(&watchdog_mutex);
+}
+
 // stolen from js shell
 static JSBool JSRust_Print(JSContext *cx, uintN argc, jsval *vp) {
     jsval *argv;
@@ -242,7 +621,7 @@ static JSBool JSRust_Print(JSContext *cx, uintN argc, jsval *vp) {
 }
 
 static JSString *
-FileAsString(JSContext *cx, const char *pathname)
+FileAsString(JSContext *cx, const char *pathname, uint32_t max_bytes)
 {
     FILE *file;
     JSString *str = NULL;
@@ -251,23 +630,26 @@ FileAsString(JSContext *cx, const char *pathname)
 
     file = fopen(pathname, "rb");
     if (!file) {
-        JS_ReportError(cx, "can't open %s: %s", pathname, strerror(errno));
+        jsrust_throw_error(cx, "can't open %s: %s", pathname, strerror(errno));
         return NULL;
     }
 
     if (fseek(file, 0, SEEK_END) != 0) {
-        JS_ReportError(cx, "can't seek end of %s", pathname);
+        jsrust_throw_error(cx, "can't seek end of %s", pathname);
     } else {
         len = ftell(file);
-        if (fseek(file, 0, SEEK_SET) != 0) {
-            JS_ReportError(cx, "can't seek start of %s", pathname);
+        if (max_bytes && len > max_bytes) {
+            jsrust_throw_error(cx, "%s is larger than the limit of %u bytes",
+                               pathname, max_bytes);
+        } else if (fseek(file, 0, SEEK_SET) != 0) {
+            jsrust_throw_error(cx, "can't seek start of %s", pathname);
         } else {
             buf = (char*) JS_malloc(cx, len + 1);
             if (buf) {
                 cc = fread(buf, 1, len, file);
                 if (cc != len) {
-                    JS_ReportError(cx, "can't read %s: %s", pathname,
-                                   (ptrdiff_t(cc) < 0) ? strerror(errno) : "short read");
+                    jsrust_throw_error(cx, "can't read %s: %s", pathname,
+                                       (ptrdiff_t(cc) < 0) ? strerror(errno) : "short read");
                 } else {
                     jschar *ucbuf;
                     size_t uclen;
@@ -275,7 +657,9 @@ FileAsString(JSContext *cx, const char *pathname)
                     len = (size_t)cc;
 
                     if (!JS_DecodeUTF8(cx, buf, len, NULL, &uclen)) {
-                        JS_ReportError(cx, "Invalid UTF-8 in file '%s'", pathname);
+                        jsrust_throw_error(cx, "Invalid UTF-8 in file '%s'", pathname);
+                        JS_free(cx, buf);
+                        fclose(file);
                         return NULL;
                     }
 
@@ -299,7 +683,7 @@ JSRust_Read(JSContext *cx, uintN argc, jsval *vp)
     JSString *str;
 
     if (!argc)
-        return JS_FALSE;
+        return jsrust_throw_error(cx, "jsrust_read: no path given");
 
     str = JS_ValueToString(cx, JS_ARGV(cx, vp)[0]);
     if (!str)
@@ -308,17 +692,73 @@ JSRust_Read(JSContext *cx, uintN argc, jsval *vp)
     if (!filename)
         return JS_FALSE;
 
-    const char *pathname = filename.ptr();
+    std::string pathname;
+    if (!jsrust_resolve_path(cx, filename.ptr(), pathname))
+        return jsrust_throw_error(cx, "Permission denied: %s is outside "
+                                  "the script's root directory",
+                                  filename.ptr());
+
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(JS_GetContextPrivate(cx));
 
-    if (!(str = FileAsString(cx, pathname)))
+    if (!(str = FileAsString(cx, pathname.c_str(), priv->fs_max_bytes)))
         return JS_FALSE;
     *vp = STRING_TO_JSVAL(str);
     return JS_TRUE;
 }
 
+/* Parses URLs for jsrust_parse_url; see JSRust_SetUrlHook. */
+typedef void (*jsrust_url_hook)(const char *input, size_t input_len,
+                                const char *base, size_t base_len,
+                                std::string *result);
+static jsrust_url_hook url_hook = NULL;
+
+/*
+ * jsrust_parse_url(input[, base]) parses input, resolved against base if
+ * given, and returns the URL's parts as JSON, or {"error": ...}.
+ */
+static JSBool JSRust_ParseUrl(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *input, *base = NULL;
+
+    if (!JS_ConvertArguments(cx, argc, JS_ARGV(cx, vp), "S/S", &input, &base))
+        return JS_FALSE;
+    if (!url_hook) {
+        JS_ReportError(cx, "URL parsing is not available");
+        return JS_FALSE;
+    }
+
+    JSAutoByteString input_bytes(cx, input);
+    JSAutoByteString base_bytes;
+    if (base && !base_bytes.encode(cx, base))
+        return JS_FALSE;
+    if (!input_bytes)
+        return JS_FALSE;
+
+    std::string result;
+    url_hook(input_bytes.ptr(), strlen(input_bytes.ptr()),
+             base ? base_bytes.ptr() : NULL,
+             base ? strlen(base_bytes.ptr()) : 0, &result);
+
+    JSString *str = JS_NewStringCopyN(cx, result.data(), result.size());
+    if (!str)
+        return JS_FALSE;
+    JS_SET_RVAL(cx, vp, STRING_TO_JSVAL(str));
+    return JS_TRUE;
+}
+
+extern "C" void JSRust_SetUrlHook(jsrust_url_hook hook) {
+    url_hook = hook;
+}
+
+extern "C" void JSRust_SetString(std::string *out, const char *s,
+                                 uint32_t len) {
+    out->assign(s, len);
+}
+
 static JSFunctionSpec global_functions[] = {
     JS_FN("print", JSRust_Print, 0, 0),
     JS_FN("jsrust_read", JSRust_Read, 0, 0),
+    JS_FN("jsrust_parse_url", JSRust_ParseUrl, 2, 0),
     JS_FS_END
 };
 
@@ -425,6 +865,22 @@ JSBool JSRust_Timeout(JSContext *cx, uintN argc, jsval *vp) {
     return JS_TRUE;
 }
 
+/*
+ * jsrust_clear_timeout(id) drops timer id, made by jsrust_timeout, if it
+ * has not gone off yet.
+ */
+JSBool JSRust_ClearTimeout(JSContext *cx, uintN argc, jsval *vp) {
+    uint32_t req_id;
+
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "u", &req_id))
+        return JS_FALSE;
+
+    jsrust_send_msg(cx, CLEAR_TIMEOUT, rust_str::make(""), req_id, 0);
+
+    JS_SET_RVAL(cx, vp, JSVAL_VOID);
+    return JS_TRUE;
+}
+
 JSBool JSRust_Close(JSContext *cx, uintN argc, jsval *vp) {
     uint32_t req_id;
 
@@ -439,81 +895,1585 @@ JSBool JSRust_Close(JSContext *cx, uintN argc, jsval *vp) {
     return JS_TRUE;
 }
 
-JSBool JSRust_Exit(JSContext *cx, uintN argc, jsval *vp) {
+/*
+ * jsrust_read_async(path) and jsrust_write_async(path, data) ask the host
+ * to read or write a file under the actor's filesystem policy. The result
+ * arrives later through _resume, tagged with the returned request id.
+ */
+JSBool JSRust_ReadAsync(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *path;
+
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "S", &path))
+        return JS_FALSE;
+
+    JSAutoByteString path_bytes(cx, path);
+    if (!path_bytes)
+        return JS_FALSE;
+
+    uint32_t my_num = jsrust_send_msg(cx, FILE_READ,
+                                      rust_str::make(path_bytes.ptr()), 0, 0);
+
+    JS_SET_RVAL(cx, vp, INT_TO_JSVAL(my_num));
+    return JS_TRUE;
+}
+
+JSBool JSRust_WriteAsync(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *path;
+    JSString *data;
+
+    if (!JS_ConvertArguments(cx, 2, JS_ARGV(cx, vp), "SS", &path, &data))
+        return JS_FALSE;
+
+    JSAutoByteString path_bytes(cx, path);
+    if (!path_bytes)
+        return JS_FALSE;
+    /* The data goes with its length, as it may hold NULs. */
+    rust_bytes *data_bytes = rust_bytes::encode(cx, data);
+    if (!data_bytes)
+        return JS_FALSE;
+
+    uint32_t my_num = jsrust_send_msg(cx, FILE_WRITE,
+                                      rust_str::make(path_bytes.ptr()), 0, 0,
+                                      0, data_bytes);
+
+    JS_SET_RVAL(cx, vp, INT_TO_JSVAL(my_num));
+    return JS_TRUE;
+}
+
+/*
+ * jsrust_http_request(json) hands a request, described as JSON with
+ * method, url, headers and body, to the host's HTTP client. The response
+ * arrives later through _resume, tagged with the returned request id.
+ */
+JSBool JSRust_HttpRequest(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *request;
+
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "S", &request))
+        return JS_FALSE;
+
+    JSAutoByteString bytes(cx, request);
+    if (!bytes)
+        return JS_FALSE;
+
+    uint32_t my_num = jsrust_send_msg(cx, HTTP_REQUEST,
+                                      rust_str::make(bytes.ptr()), 0, 0);
+
+    JS_SET_RVAL(cx, vp, INT_TO_JSVAL(my_num));
+    return JS_TRUE;
+}
+
+/*
+ * jsrust_http_abort(id) asks the host to drop request id and close its
+ * connection. Nothing more arrives for the request afterwards, except
+ * for events already on their way.
+ */
+JSBool JSRust_HttpAbort(JSContext *cx, uintN argc, jsval *vp) {
     uint32_t req_id;
 
-    rust_str *nothing = rust_str::make("");
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "u", &req_id))
+        return JS_FALSE;
 
-    jsrust_send_msg(cx, EXIT, nothing, 0, 0);
+    jsrust_send_msg(cx, HTTP_ABORT, rust_str::make(""), req_id, 0);
 
-    JS_SET_RVAL(cx, vp, JSVAL_NULL);
+    JS_SET_RVAL(cx, vp, JSVAL_VOID);
     return JS_TRUE;
 }
 
-static JSFunctionSpec io_functions[] = {
-    JS_FN("jsrust_connect", JSRust_Connect, 1, 0),
-    JS_FN("jsrust_send", JSRust_Send, 2, 0),
-    JS_FN("jsrust_recv", JSRust_Recv, 2, 0),
-    JS_FN("jsrust_close", JSRust_Close, 1, 0),
-    JS_FN("jsrust_timeout", JSRust_Timeout, 2, 0),
-    JS_FN("jsrust_exit", JSRust_Exit, 0, 0),
-    JS_FS_END
-};
+/*
+ * jsrust_http_request_sync(json) makes a request like jsrust_http_request
+ * but waits for the whole response, returning [head, body, error]: the
+ * head as JSON and the body, or an error message. The context's request
+ * is suspended meanwhile, and the host's hook waits without holding up
+ * other actors.
+ */
+JSBool JSRust_HttpRequestSync(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *request;
+
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "S", &request))
+        return JS_FALSE;
 
-extern "C" JSBool JSRust_SetMessageChannel(JSContext *cx,
-                                         JSObject *global,
-                                         const rust_chan_pkg *channel,
-                                         const type_desc *tydesc) {
     void *priv_p = JS_GetContextPrivate(cx);
     assert(priv_p && "No private data associated with context!");
     jsrust_context_priv *priv =
         reinterpret_cast<jsrust_context_priv *>(priv_p);
 
-    priv->msg_tydesc = tydesc;
-    priv->msg_chan = *channel;
+    if (!priv->sync_hook) {
+        JS_ReportError(cx, "synchronous requests are not supported here");
+        return JS_FALSE;
+    }
 
-    JS_DefineFunctions(cx, global, postMessage_functions);
-    JS_DefineFunctions(cx, global, io_functions);
-    JS_SetErrorReporter(cx, jsrust_report_error);
+    /* Build the result first, so that it is rooted by the return value. */
+    JSObject *array = JS_NewArrayObject(cx, 0, NULL);
+    if (!array)
+        return JS_FALSE;
+    JS_SET_RVAL(cx, vp, OBJECT_TO_JSVAL(array));
 
+    JSAutoByteString bytes(cx, request);
+    if (!bytes)
+        return JS_FALSE;
+    jsrust_sync_result result;
+
+    /* Waiting is not running: the script's CPU budget stops too. */
+    pthread_mutex_lock(&watchdog_mutex);
+    bool armed = priv->armed;
+    pthread_mutex_unlock(&watchdog_mutex);
+    if (armed)
+        JSRust_DisarmWatchdog(cx);
+
+    jsrefcount depth = JS_SuspendRequest(cx);
+    priv->sync_hook(priv->sync_data, bytes.ptr(), strlen(bytes.ptr()), &result);
+    JS_ResumeRequest(cx, depth);
+
+    if (armed)
+        JSRust_ArmWatchdog(cx);
+
+    const std::string *parts[] = { &result.head, &result.body, &result.error };
+    for (jsint i = 0; i < 3; i++) {
+        JSString *str = JS_NewStringCopyN(cx, parts[i]->data(),
+                                          parts[i]->size());
+        if (!str)
+            return JS_FALSE;
+        jsval v = STRING_TO_JSVAL(str);
+        if (!JS_SetElement(cx, array, i, &v))
+            return JS_FALSE;
+    }
     return JS_TRUE;
 }
 
-extern "C" JSBool JSRust_Exit(int code) {
-    exit(code);
-}
+/*
+ * jsrust_ws_open(json) asks the host to open a WebSocket connection
+ * described by json ({url, protocols}) and returns its id. The host
+ * answers with WS_OPENED, then WS_MESSAGE for each message, then
+ * WS_CLOSED, with WS_ERROR before it if the connection failed.
+ */
+JSBool JSRust_WsOpen(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *request;
 
-extern "C" void JSRust_SetDataOnObject(JSContext *cx, JSObject *obj, const char *val, uint32_t vallen) {
-    JSString *valstr = JS_NewStringCopyN(cx, val, vallen);
-    jsval *jv = (jsval *)malloc(sizeof(jsval));
-    *jv = STRING_TO_JSVAL(valstr);
-    JS_SetProperty(cx, obj, "_data", jv);
-}
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "S", &request))
+        return JS_FALSE;
 
-static pthread_mutex_t get_runtime_mutex = PTHREAD_MUTEX_INITIALIZER;
-static pthread_key_t thread_runtime_key;
-static int initialized = 0;
+    JSAutoByteString bytes(cx, request);
+    if (!bytes)
+        return JS_FALSE;
 
-JSRuntime *jsrust_getthreadruntime(uint32_t max_bytes) {
-    pthread_mutex_lock(&get_runtime_mutex);
-    if (!initialized) {
-        pthread_key_create(&thread_runtime_key, NULL);
-        initialized = 1;
-    }
-    pthread_mutex_unlock(&get_runtime_mutex);
+    uint32_t my_num = jsrust_send_msg(cx, WS_OPEN, rust_str::make(bytes.ptr()),
+                                      0, 0);
 
-    JSRuntime *rt = (JSRuntime *)pthread_getspecific(thread_runtime_key);
-    if (rt == NULL) {
-        rt = JS_NewRuntime(max_bytes);
-        pthread_setspecific(thread_runtime_key, (const void *)rt);
-    }
-    return rt;
+    JS_SET_RVAL(cx, vp, INT_TO_JSVAL(my_num));
+    return JS_TRUE;
 }
 
-extern "C" JSRuntime *JSRust_GetThreadRuntime(uint32_t max_bytes) {
-    return jsrust_getthreadruntime(max_bytes);
+/*
+ * jsrust_ws_send(id, data, binary) sends a message on connection id; a
+ * binary message's data is base64. The data travels as bytes.
+ */
+JSBool JSRust_WsSend(JSContext *cx, uintN argc, jsval *vp) {
+    uint32_t req_id;
+    JSString *data;
+    JSBool binary = JS_FALSE;
+
+    if (!JS_ConvertArguments(cx, argc, JS_ARGV(cx, vp), "uS/b",
+                             &req_id, &data, &binary))
+        return JS_FALSE;
+
+    /* The data goes with its length, as text may hold NULs. */
+    rust_bytes *bytes = rust_bytes::encode(cx, data);
+    if (!bytes)
+        return JS_FALSE;
+
+    jsrust_send_msg(cx, WS_SEND, rust_str::make(""),
+                    req_id, 0, binary ? 1 : 0, bytes);
+
+    JS_SET_RVAL(cx, vp, JSVAL_VOID);
+    return JS_TRUE;
 }
 
-extern "C" uint32_t JSRust_GetGlobalClassFlags() {
-    return JSCLASS_GLOBAL_FLAGS;
+/*
+ * jsrust_ws_close(id, code, reason) starts closing connection id, or
+ * gives up on it if it has not opened yet. Code 0 sends none.
+ */
+JSBool JSRust_WsClose(JSContext *cx, uintN argc, jsval *vp) {
+    uint32_t req_id, code = 0;
+    JSString *reason = NULL;
+
+    if (!JS_ConvertArguments(cx, argc, JS_ARGV(cx, vp), "u/uS",
+                             &req_id, &code, &reason))
+        return JS_FALSE;
+
+    JSAutoByteString bytes;
+    if (reason && !bytes.encode(cx, reason))
+        return JS_FALSE;
+
+    jsrust_send_msg(cx, WS_CLOSE, rust_str::make(reason ? bytes.ptr() : ""),
+                    req_id, code);
+
+    JS_SET_RVAL(cx, vp, JSVAL_VOID);
+    return JS_TRUE;
+}
+
+/*
+ * jsrust_enqueue_job(fn) hands fn to the host's job queue, which calls it
+ * with no arguments once the current callback has returned, before any
+ * other timer or I/O callback.
+ */
+JSBool JSRust_EnqueueJob(JSContext *cx, uintN argc, jsval *vp) {
+    jsval job = argc ? JS_ARGV(cx, vp)[0] : JSVAL_VOID;
+    if (JSVAL_IS_PRIMITIVE(job) ||
+        !JS_ObjectIsFunction(cx, JSVAL_TO_OBJECT(job)))
+        return jsrust_throw_error(cx, "jsrust_enqueue_job needs a function");
+
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    if (!priv->job_hook) {
+        JS_ReportError(cx, "jobs are not supported here");
+        return JS_FALSE;
+    }
+    priv->job_hook(priv->job_data, job);
+    JS_SET_RVAL(cx, vp, JSVAL_VOID);
+    return JS_TRUE;
+}
+
+/*
+ * jsrust_cookie(url) returns the cookies a page at url can read, as
+ * document.cookie would; jsrust_cookie(url, value) stores a cookie for it.
+ */
+JSBool JSRust_Cookie(JSContext *cx, uintN argc, jsval *vp) {
+    JSString *url, *value = NULL;
+
+    if (!JS_ConvertArguments(cx, argc, JS_ARGV(cx, vp), "S/S", &url, &value))
+        return JS_FALSE;
+
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    if (!priv->cookie_hook) {
+        JS_SET_RVAL(cx, vp, JS_GetEmptyStringValue(cx));
+        return JS_TRUE;
+    }
+
+    JSAutoByteString url_bytes(cx, url);
+    JSAutoByteString value_bytes;
+    if (!url_bytes || (value && !value_bytes.encode(cx, value)))
+        return JS_FALSE;
+
+    std::string result;
+    priv->cookie_hook(priv->cookie_data, url_bytes.ptr(),
+                      strlen(url_bytes.ptr()),
+                      value ? value_bytes.ptr() : NULL,
+                      value ? strlen(value_bytes.ptr()) : 0, &result);
+
+    JSString *str = JS_NewStringCopyN(cx, result.data(), result.size());
+    if (!str)
+        return JS_FALSE;
+    JS_SET_RVAL(cx, vp, STRING_TO_JSVAL(str));
+    return JS_TRUE;
+}
+
+JSBool JSRust_Exit(JSContext *cx, uintN argc, jsval *vp) {
+    uint32_t req_id;
+
+    rust_str *nothing = rust_str::make("");
+
+    jsrust_send_msg(cx, EXIT, nothing, 0, 0);
+
+    JS_SET_RVAL(cx, vp, JSVAL_NULL);
+    return JS_TRUE;
+}
+
+/*
+ * Mutation batches.
+ *
+ * jsrust_post_mutations(records) encodes an array of dom.js mutation
+ * records in the binary format read by dom::apply_binary, and posts the
+ * whole batch to the host as one MUTATIONS message. This saves a JSON
+ * round trip per record.
+ */
+
+namespace {
+
+void put_u8(std::string &out, uint8_t v) {
+    out += char(v);
+}
+
+void put_u32(std::string &out, uint32_t v) {
+    for (int i = 0; i < 4; i++)
+        out += char((v >> (8 * i)) & 0xff);
+}
+
+/* Appends a JS string as a length-prefixed UTF-8 string. */
+bool put_jsstr(JSContext *cx, std::string &out, JSString *str) {
+    size_t len;
+    const jschar *chars = JS_GetStringCharsAndLength(cx, str, &len);
+    if (!chars)
+        return false;
+
+    std::string utf8;
+    for (size_t i = 0; i < len; i++) {
+        uint32_t c = chars[i];
+        if (c >= 0xD800 && c <= 0xDBFF && i + 1 < len &&
+            chars[i + 1] >= 0xDC00 && chars[i + 1] <= 0xDFFF) {
+            c = 0x10000 + ((c - 0xD800) << 10) + (chars[i + 1] - 0xDC00);
+            i++;
+        } else if (c >= 0xD800 && c <= 0xDFFF) {
+            /* A lone surrogate is not UTF-8; it becomes U+FFFD. */
+            c = 0xFFFD;
+        }
+        if (c < 0x80) {
+            utf8 += char(c);
+        } else if (c < 0x800) {
+            utf8 += char(0xC0 | (c >> 6));
+            utf8 += char(0x80 | (c & 0x3F));
+        } else if (c < 0x10000) {
+            utf8 += char(0xE0 | (c >> 12));
+            utf8 += char(0x80 | ((c >> 6) & 0x3F));
+            utf8 += char(0x80 | (c & 0x3F));
+        } else {
+            utf8 += char(0xF0 | (c >> 18));
+            utf8 += char(0x80 | ((c >> 12) & 0x3F));
+            utf8 += char(0x80 | ((c >> 6) & 0x3F));
+            utf8 += char(0x80 | (c & 0x3F));
+        }
+    }
+
+    put_u32(out, utf8.size());
+    out += utf8;
+    return true;
+}
+
+/* Appends a string property; null and undefined become "". */
+bool put_str_prop(JSContext *cx, std::string &out, JSObject *obj,
+                  const char *name) {
+    jsval v;
+    if (!JS_GetProperty(cx, obj, name, &v))
+        return false;
+    if (JSVAL_IS_NULL(v) || JSVAL_IS_VOID(v)) {
+        put_u32(out, 0);
+        return true;
+    }
+    JSString *str = JS_ValueToString(cx, v);
+    return str && put_jsstr(cx, out, str);
+}
+
+bool put_u32_prop(JSContext *cx, std::string &out, JSObject *obj,
+                  const char *name) {
+    jsval v;
+    uint32_t n;
+    if (!JS_GetProperty(cx, obj, name, &v) ||
+        !JS_ValueToECMAUint32(cx, v, &n))
+        return false;
+    put_u32(out, n);
+    return true;
+}
+
+bool has_prop(JSContext *cx, JSObject *obj, const char *name) {
+    JSBool found = JS_FALSE;
+    return JS_HasProperty(cx, obj, name, &found) && found;
+}
+
+/* Length of an array property, or 0 if it is absent. */
+bool get_array_prop(JSContext *cx, JSObject *obj, const char *name,
+                    JSObject **arr, jsuint *len) {
+    jsval v;
+    *arr = NULL;
+    *len = 0;
+    if (!JS_GetProperty(cx, obj, name, &v))
+        return false;
+    if (JSVAL_IS_PRIMITIVE(v))
+        return true;
+    *arr = JSVAL_TO_OBJECT(v);
+    return JS_GetArrayLength(cx, *arr, len);
+}
+
+/* Appends a subtree in the shape produced by DOMSTR.serialize. */
+bool put_tree(JSContext *cx, std::string &out, jsval v) {
+    if (JSVAL_IS_STRING(v)) {
+        put_u8(out, 'T');
+        return put_jsstr(cx, out, JSVAL_TO_STRING(v));
+    }
+    if (JSVAL_IS_PRIMITIVE(v)) {
+        jsrust_throw_error(cx, "bad node in mutation record");
+        return false;
+    }
+
+    JSObject *obj = JSVAL_TO_OBJECT(v);
+    if (has_prop(cx, obj, "comment")) {
+        put_u8(out, 'C');
+        return put_str_prop(cx, out, obj, "comment");
+    }
+    if (has_prop(cx, obj, "pi")) {
+        put_u8(out, 'P');
+        return put_str_prop(cx, out, obj, "pi") &&
+               put_str_prop(cx, out, obj, "data");
+    }
+    if (has_prop(cx, obj, "doctype")) {
+        put_u8(out, 'D');
+        return put_str_prop(cx, out, obj, "doctype");
+    }
+
+    put_u8(out, 'E');
+    if (has_prop(cx, obj, "html")) {
+        put_u32(out, 1);
+        out += 'h';
+        if (!put_str_prop(cx, out, obj, "html"))
+            return false;
+    } else if (!put_str_prop(cx, out, obj, "ns") ||
+               !put_str_prop(cx, out, obj, "tag")) {
+        return false;
+    }
+
+    JSObject *arr;
+    jsuint len;
+    if (!get_array_prop(cx, obj, "attr", &arr, &len))
+        return false;
+    put_u32(out, len);
+    for (jsuint i = 0; i < len; i++) {
+        jsval av;
+        if (!JS_GetElement(cx, arr, i, &av) || JSVAL_IS_PRIMITIVE(av))
+            return false;
+        JSObject *attr = JSVAL_TO_OBJECT(av);
+        if (!put_str_prop(cx, out, attr, "ns") ||
+            !put_str_prop(cx, out, attr, "a") ||
+            !put_str_prop(cx, out, attr, "v"))
+            return false;
+    }
+
+    if (!get_array_prop(cx, obj, "child", &arr, &len))
+        return false;
+    put_u32(out, len);
+    for (jsuint i = 0; i < len; i++) {
+        jsval cv;
+        if (!JS_GetElement(cx, arr, i, &cv) || !put_tree(cx, out, cv))
+            return false;
+    }
+    return true;
+}
+
+bool put_record(JSContext *cx, std::string &out, JSObject *rec) {
+    jsval tv;
+    uint32_t type;
+    if (!JS_GetProperty(cx, rec, "type", &tv) ||
+        !JS_ValueToECMAUint32(cx, tv, &type))
+        return false;
+
+    put_u8(out, type);
+    if (!put_u32_prop(cx, out, rec, "target"))
+        return false;
+
+    switch (type) {
+      case 1: /* MUTATE_VALUE */
+        return put_str_prop(cx, out, rec, "data");
+      case 2: /* MUTATE_ATTR */
+        return put_str_prop(cx, out, rec, "name") &&
+               put_str_prop(cx, out, rec, "ns") &&
+               put_str_prop(cx, out, rec, "value") &&
+               put_str_prop(cx, out, rec, "prefix");
+      case 3: /* MUTATE_REMOVE_ATTR */
+        return put_str_prop(cx, out, rec, "name") &&
+               put_str_prop(cx, out, rec, "ns");
+      case 4: /* MUTATE_REMOVE */
+        return true;
+      case 5: /* MUTATE_MOVE */
+        return put_u32_prop(cx, out, rec, "parent") &&
+               put_u32_prop(cx, out, rec, "index");
+      case 6: { /* MUTATE_INSERT */
+        jsval child;
+        return put_u32_prop(cx, out, rec, "index") &&
+               put_u32_prop(cx, out, rec, "nid") &&
+               JS_GetProperty(cx, rec, "child", &child) &&
+               put_tree(cx, out, child);
+      }
+      default:
+        jsrust_throw_error(cx, "unknown mutation record type %u", type);
+        return false;
+    }
+}
+
+}   /* end anonymous namespace */
+
+JSBool JSRust_PostMutations(JSContext *cx, uintN argc, jsval *vp) {
+    JSObject *records;
+    jsuint len;
+
+    if (!JS_ConvertArguments(cx, 1, JS_ARGV(cx, vp), "o", &records) ||
+        !records || !JS_GetArrayLength(cx, records, &len))
+        return jsrust_throw_error(cx, "jsrust_post_mutations: expected an array");
+
+    std::string out;
+    for (jsuint i = 0; i < len; i++) {
+        jsval rv;
+        if (!JS_GetElement(cx, records, i, &rv) || JSVAL_IS_PRIMITIVE(rv))
+            return jsrust_throw_error(cx, "bad mutation record %u", i);
+        if (!put_record(cx, out, JSVAL_TO_OBJECT(rv))) {
+            if (!JS_IsExceptionPending(cx))
+                jsrust_throw_error(cx, "bad mutation record %u", i);
+            return JS_FALSE;
+        }
+    }
+
+    jsrust_send_msg(cx, MUTATIONS, rust_str::make(""), 0, 0, 0,
+                    rust_bytes::make(out.data(), out.size()));
+
+    JS_SET_RVAL(cx, vp, JSVAL_VOID);
+    return JS_TRUE;
+}
+
+static JSFunctionSpec io_functions[] = {
+    JS_FN("jsrust_connect", JSRust_Connect, 1, 0),
+    JS_FN("jsrust_send", JSRust_Send, 2, 0),
+    JS_FN("jsrust_recv", JSRust_Recv, 2, 0),
+    JS_FN("jsrust_close", JSRust_Close, 1, 0),
+    JS_FN("jsrust_timeout", JSRust_Timeout, 2, 0),
+    JS_FN("jsrust_clear_timeout", JSRust_ClearTimeout, 1, 0),
+    JS_FN("jsrust_exit", JSRust_Exit, 0, 0),
+    JS_FN("jsrust_read_async", JSRust_ReadAsync, 1, 0),
+    JS_FN("jsrust_write_async", JSRust_WriteAsync, 2, 0),
+    JS_FN("jsrust_post_mutations", JSRust_PostMutations, 1, 0),
+    JS_FN("jsrust_http_request", JSRust_HttpRequest, 1, 0),
+    JS_FN("jsrust_http_abort", JSRust_HttpAbort, 1, 0),
+    JS_FN("jsrust_http_request_sync", JSRust_HttpRequestSync, 1, 0),
+    JS_FN("jsrust_cookie", JSRust_Cookie, 2, 0),
+    JS_FN("jsrust_enqueue_job", JSRust_EnqueueJob, 1, 0),
+    JS_FN("jsrust_ws_open", JSRust_WsOpen, 1, 0),
+    JS_FN("jsrust_ws_send", JSRust_WsSend, 3, 0),
+    JS_FN("jsrust_ws_close", JSRust_WsClose, 3, 0),
+    JS_FS_END
+};
+
+extern "C" JSBool JSRust_SetMessageChannel(JSContext *cx,
+                                         JSObject *global,
+                                         const rust_chan_pkg *channel,
+                                         const type_desc *tydesc) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    priv->msg_tydesc = tydesc;
+    priv->msg_chan = *channel;
+
+    JS_DefineFunctions(cx, global, postMessage_functions);
+    JS_DefineFunctions(cx, global, io_functions);
+    JS_SetErrorReporter(cx, jsrust_report_error);
+
+    return JS_TRUE;
+}
+
+/*
+ * Installs the host's hook for jsrust_http_request_sync. The hook is
+ * called with data, the request's JSON and a result to fill in with
+ * JSRust_SetSyncResult before it returns.
+ */
+extern "C" void JSRust_SetSyncHook(JSContext *cx, jsrust_sync_hook hook,
+                                   void *data) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    priv->sync_hook = hook;
+    priv->sync_data = data;
+}
+
+/*
+ * Installs the host's hook for jsrust_enqueue_job. The hook is called
+ * with data and the job, which it must keep alive until it runs it with
+ * JSRust_CallJob.
+ */
+extern "C" void JSRust_SetJobHook(JSContext *cx, jsrust_job_hook hook,
+                                  void *data) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    priv->job_hook = hook;
+    priv->job_data = data;
+}
+
+/* Calls a queued job. An exception it throws is reported, not rethrown. */
+extern "C" JSBool JSRust_CallJob(JSContext *cx, JSObject *global, jsval job) {
+    jsval rval;
+    if (JS_CallFunctionValue(cx, global, job, 0, NULL, &rval))
+        return JS_TRUE;
+    if (JS_IsExceptionPending(cx)) {
+        JS_ReportPendingException(cx);
+        JS_ClearPendingException(cx);
+    }
+    return JS_FALSE;
+}
+
+/*
+ * Installs the host's hook for jsrust_cookie. The hook is called with
+ * data, the page's URL, the value to store (NULL to read) and a string
+ * to fill in with JSRust_SetString.
+ */
+extern "C" void JSRust_SetCookieHook(JSContext *cx, jsrust_cookie_hook hook,
+                                     void *data) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    priv->cookie_hook = hook;
+    priv->cookie_data = data;
+}
+
+extern "C" void JSRust_SetSyncResult(jsrust_sync_result *result,
+                                     const char *head, uint32_t head_len,
+                                     const char *body, uint32_t body_len,
+                                     const char *error, uint32_t error_len) {
+    result->head.assign(head, head_len);
+    result->body.assign(body, body_len);
+    result->error.assign(error, error_len);
+}
+
+/*
+ * Confines jsrust_read and the async file ops to paths under root, with
+ * files of at most max_bytes (0 for no limit).
+ */
+extern "C" void JSRust_SetFilePolicy(JSContext *cx, const char *root,
+                                     uint32_t max_bytes) {
+    void *priv_p = JS_GetContextPrivate(cx);
+    assert(priv_p && "No private data associated with context!");
+    jsrust_context_priv *priv =
+        reinterpret_cast<jsrust_context_priv *>(priv_p);
+
+    priv->fs_restricted = true;
+    priv->fs_root = root;
+    while (priv->fs_root.size() > 1 &&
+           priv->fs_root[priv->fs_root.size() - 1] == '/')
+        priv->fs_root.erase(priv->fs_root.size() - 1);
+    char buf[PATH_MAX];
+    priv->fs_real_root = realpath(priv->fs_root.c_str(), buf) ? buf
+                                                              : priv->fs_root;
+    priv->fs_max_bytes = max_bytes;
+}
+
+/* Returns the host path for a script path, or "" if it is not allowed. */
+extern "C" rust_str *JSRust_ResolvePath(JSContext *cx, const char *path) {
+    std::string out;
+    if (!jsrust_resolve_path(cx, path, out))
+        return rust_str::make("");
+    return rust_str::make(out.c_str());
+}
+
+extern "C" JSBool JSRust_Exit(int code) {
+    exit(code);
+}
+
+/* val is UTF-8, as are all C strings once jsrust_utf8_init has run. */
+extern "C" void JSRust_SetDataOnObject(JSContext *cx, JSObject *obj, const char *val, uint32_t vallen) {
+    JSString *valstr = JS_NewStringCopyN(cx, val, vallen);
+    if (!valstr)
+        return;
+    jsval jv = STRING_TO_JSVAL(valstr);
+    JS_SetProperty(cx, obj, "_data", &jv);
+}
+
+static pthread_mutex_t get_runtime_mutex = PTHREAD_MUTEX_INITIALIZER;
+static pthread_key_t thread_runtime_key;
+static int initialized = 0;
+
+JSRuntime *jsrust_getthreadruntime(uint32_t max_bytes) {
+    pthread_mutex_lock(&get_runtime_mutex);
+    if (!initialized) {
+        pthread_key_create(&thread_runtime_key, NULL);
+        initialized = 1;
+    }
+    pthread_mutex_unlock(&get_runtime_mutex);
+
+    JSRuntime *rt = (JSRuntime *)pthread_getspecific(thread_runtime_key);
+    if (rt == NULL) {
+        rt = JS_NewRuntime(max_bytes);
+        pthread_setspecific(thread_runtime_key, (const void *)rt);
+    }
+    return rt;
+}
+
+extern "C" JSRuntime *JSRust_GetThreadRuntime(uint32_t max_bytes) {
+    return jsrust_getthreadruntime(max_bytes);
+}
+
+extern "C" uint32_t JSRust_GetGlobalClassFlags() {
+    return JSCLASS_GLOBAL_FLAGS;
+}
+
+/* Garbage collector listeners */
+
+namespace {
+
+struct jsrust_gc_event {
+    uint32_t status;
+    uint32_t bytes;
+    uint32_t number;
+};
+
+struct jsrust_gc_listener {
+    uint32_t id;
+    const type_desc *tydesc;
+    rust_chan_pkg chan;
+};
+
+struct jsrust_trace_set;
+
+struct jsrust_runtime_priv {
+    std::vector<jsrust_gc_listener> gc_listeners;
+    uint32_t next_listener_id;
+    JSGCCallback prev_gc_callback;
+    std::vector<jsrust_trace_set *> root_sets;
+    bool roots_tracer_set;
+    /* Another extra roots tracer, called after the root sets are traced;
+     * see JSRust_SetExtraRootsTracer. */
+    JSTraceDataOp other_roots_tracer;
+    void *other_roots_data;
+    /* SpiderMonkey's own wrapper maker, used for permitted access; see
+     * jsrust_wrap_object. */
+    JSWrapObjectCallback prev_wrap_callback;
+
+    jsrust_runtime_priv() : gc_listeners(), next_listener_id(1),
+        prev_gc_callback(NULL), root_sets(), roots_tracer_set(false),
+        other_roots_tracer(NULL), other_roots_data(NULL),
+        prev_wrap_callback(NULL) {}
+};
+
+jsrust_runtime_priv *jsrust_get_runtime_priv(JSRuntime *rt) {
+    jsrust_runtime_priv *priv =
+        reinterpret_cast<jsrust_runtime_priv *>(JS_GetRuntimePrivate(rt));
+    if (!priv) {
+        priv = new jsrust_runtime_priv();
+        JS_SetRuntimePrivate(rt, priv);
+    }
+    return priv;
+}
+
+JSBool jsrust_gc_callback(JSContext *cx, JSGCStatus status) {
+    JSRuntime *rt = JS_GetRuntime(cx);
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(rt);
+
+    jsrust_gc_event evt = {
+        status,
+        JS_GetGCParameter(rt, JSGC_BYTES),
+        JS_GetGCParameter(rt, JSGC_NUMBER)
+    };
+
+    for (size_t i = 0; i < priv->gc_listeners.size(); i++) {
+        jsrust_gc_listener &l = priv->gc_listeners[i];
+        chan_id_send(l.tydesc, l.chan.task, l.chan.port, &evt);
+    }
+
+    if (priv->prev_gc_callback)
+        return priv->prev_gc_callback(cx, status);
+    return JS_TRUE;
+}
+
+}   /* end anonymous namespace */
+
+extern "C" uint32_t JSRust_AddGCListener(JSRuntime *rt,
+                                         const rust_chan_pkg *channel,
+                                         const type_desc *tydesc) {
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(rt);
+
+    if (priv->gc_listeners.empty()) {
+        JSGCCallback prev = JS_SetGCCallbackRT(rt, jsrust_gc_callback);
+        if (prev != jsrust_gc_callback)
+            priv->prev_gc_callback = prev;
+    }
+
+    jsrust_gc_listener l = { priv->next_listener_id++, tydesc, *channel };
+    priv->gc_listeners.push_back(l);
+    return l.id;
+}
+
+extern "C" void JSRust_RemoveGCListener(JSRuntime *rt, uint32_t id) {
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(rt);
+
+    std::vector<jsrust_gc_listener> &v = priv->gc_listeners;
+    for (size_t i = 0; i < v.size(); i++) {
+        if (v[i].id == id) {
+            v.erase(v.begin() + i);
+            break;
+        }
+    }
+
+    if (v.empty()) {
+        JS_SetGCCallbackRT(rt, priv->prev_gc_callback);
+        priv->prev_gc_callback = NULL;
+    }
+}
+
+
+/*
+ * Rust-owned sets of traced values.
+ *
+ * A trace set holds JS values on behalf of Rust code. It keeps them alive
+ * either as extra roots of its runtime, or only while a JS object of a
+ * traced class that owns it is itself reachable. Sets are reference
+ * counted, since the Rust owner and the JS owner may go away in either
+ * order.
+ */
+
+namespace {
+
+struct jsrust_trace_set {
+    std::vector<jsval> vals;    /* JSVAL_VOID marks a free slot */
+    uint32_t refcount;
+
+    jsrust_trace_set() : vals(), refcount(1) {}
+};
+
+void jsrust_trace_set_release(jsrust_trace_set *ts) {
+    if (--ts->refcount == 0)
+        delete ts;
+}
+
+void jsrust_trace_values(JSTracer *trc, jsrust_trace_set *ts,
+                         const char *name) {
+    for (size_t i = 0; i < ts->vals.size(); i++) {
+        jsval v = ts->vals[i];
+        if (JSVAL_IS_TRACEABLE(v)) {
+            JS_SET_TRACING_INDEX(trc, name, i);
+            JS_CallTracer(trc, JSVAL_TO_TRACEABLE(v), JSVAL_TRACE_KIND(v));
+        }
+    }
+}
+
+void jsrust_extra_roots_tracer(JSTracer *trc, void *data) {
+    jsrust_runtime_priv *priv = reinterpret_cast<jsrust_runtime_priv *>(data);
+    for (size_t i = 0; i < priv->root_sets.size(); i++)
+        jsrust_trace_values(trc, priv->root_sets[i], "jsrust root");
+    if (priv->other_roots_tracer)
+        priv->other_roots_tracer(trc, priv->other_roots_data);
+}
+
+/*
+ * A runtime has a single extra roots tracer, so ours stays installed
+ * once set, and chains to any other.
+ */
+void jsrust_install_roots_tracer(JSRuntime *rt, jsrust_runtime_priv *priv) {
+    if (!priv->roots_tracer_set) {
+        JS_SetExtraGCRootsTracer(rt, jsrust_extra_roots_tracer, priv);
+        priv->roots_tracer_set = true;
+    }
+}
+
+void jsrust_traced_class_trace(JSTracer *trc, JSObject *obj) {
+    jsrust_trace_set *ts = reinterpret_cast<jsrust_trace_set *>(
+        JS_GetPrivate(trc->context, obj));
+    if (ts)
+        jsrust_trace_values(trc, ts, "jsrust value");
+}
+
+void jsrust_forget_global(JSContext *cx, JSObject *obj);
+
+void jsrust_traced_class_finalize(JSContext *cx, JSObject *obj) {
+    jsrust_trace_set *ts =
+        reinterpret_cast<jsrust_trace_set *>(JS_GetPrivate(cx, obj));
+    if (ts)
+        jsrust_trace_set_release(ts);
+    jsrust_forget_global(cx, obj);
+}
+
+}   /* end anonymous namespace */
+
+extern "C" uint32_t JSRust_GetTracedClassFlags() {
+    return JSCLASS_HAS_PRIVATE | JSCLASS_MARK_IS_TRACE;
+}
+
+extern "C" JSTraceOp JSRust_GetTracedClassTraceHook() {
+    return jsrust_traced_class_trace;
+}
+
+extern "C" JSFinalizeOp JSRust_GetTracedClassFinalizeHook() {
+    return jsrust_traced_class_finalize;
+}
+
+extern "C" jsrust_trace_set *JSRust_NewTraceSet() {
+    return new jsrust_trace_set();
+}
+
+extern "C" void JSRust_ReleaseTraceSet(jsrust_trace_set *ts) {
+    jsrust_trace_set_release(ts);
+}
+
+extern "C" uint32_t JSRust_TraceSetAdd(jsrust_trace_set *ts, jsval v) {
+    for (size_t i = 0; i < ts->vals.size(); i++) {
+        if (JSVAL_IS_VOID(ts->vals[i])) {
+            ts->vals[i] = v;
+            return i;
+        }
+    }
+    ts->vals.push_back(v);
+    return ts->vals.size() - 1;
+}
+
+extern "C" jsval JSRust_TraceSetGet(jsrust_trace_set *ts, uint32_t index) {
+    if (index >= ts->vals.size())
+        return JSVAL_VOID;
+    return ts->vals[index];
+}
+
+extern "C" void JSRust_TraceSetRemove(jsrust_trace_set *ts, uint32_t index) {
+    if (index < ts->vals.size())
+        ts->vals[index] = JSVAL_VOID;
+}
+
+extern "C" JSBool JSRust_AttachTraceSet(JSContext *cx, JSObject *obj,
+                                        jsrust_trace_set *ts) {
+    if (JS_GET_CLASS(cx, obj)->trace != jsrust_traced_class_trace) {
+        JS_ReportError(cx, "object's class is not a traced class");
+        return JS_FALSE;
+    }
+
+    jsrust_trace_set *old =
+        reinterpret_cast<jsrust_trace_set *>(JS_GetPrivate(cx, obj));
+    ts->refcount++;
+    if (!JS_SetPrivate(cx, obj, ts)) {
+        ts->refcount--;
+        return JS_FALSE;
+    }
+    if (old)
+        jsrust_trace_set_release(old);
+    return JS_TRUE;
+}
+
+extern "C" void JSRust_AddRootSet(JSRuntime *rt, jsrust_trace_set *ts) {
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(rt);
+
+    jsrust_install_roots_tracer(rt, priv);
+    ts->refcount++;
+    priv->root_sets.push_back(ts);
+}
+
+extern "C" void JSRust_RemoveRootSet(JSRuntime *rt, jsrust_trace_set *ts) {
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(rt);
+
+    std::vector<jsrust_trace_set *> &v = priv->root_sets;
+    for (size_t i = 0; i < v.size(); i++) {
+        if (v[i] == ts) {
+            v.erase(v.begin() + i);
+            jsrust_trace_set_release(ts);
+            break;
+        }
+    }
+}
+
+/*
+ * Sets another extra roots tracer for rt, or clears it with NULL. Use
+ * this rather than JS_SetExtraGCRootsTracer, which would replace the one
+ * that traces the root sets.
+ */
+extern "C" void JSRust_SetExtraRootsTracer(JSRuntime *rt, JSTraceDataOp op,
+                                           void *data) {
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(rt);
+
+    jsrust_install_roots_tracer(rt, priv);
+    priv->other_roots_tracer = op;
+    priv->other_roots_data = data;
+}
+
+extern "C" void JSRust_CallValueTracer(JSTracer *trc, jsval v,
+                                       const char *name) {
+    if (JSVAL_IS_TRACEABLE(v)) {
+        JS_SET_TRACING_NAME(trc, name);
+        JS_CallTracer(trc, JSVAL_TO_TRACEABLE(v), JSVAL_TRACE_KIND(v));
+    }
+}
+
+
+/*
+ * Principals.
+ *
+ * Rust-defined principals carry an origin string. The system principals
+ * (and globals created without principals) subsume everything; otherwise
+ * principals subsume each other only when their origins are equal.
+ *
+ * Scripts reach the objects of another compartment only through the
+ * wrappers made when a value crosses over, so that is where access is
+ * checked: an object whose global's principals are not subsumed by those
+ * of the global it is wrapped for gets a wrapper that refuses every
+ * property access and call. The checkAccess class hook only covers the
+ * few accesses SpiderMonkey still routes through it, such as __proto__.
+ */
+
+namespace {
+
+const char SYSTEM_ORIGIN[] = "[System]";
+
+struct jsrust_principals : public JSPrincipals {
+    std::string origin;
+};
+
+void jsrust_principals_destroy(JSContext *cx, JSPrincipals *p) {
+    delete static_cast<jsrust_principals *>(p);
+}
+
+JSBool jsrust_principals_subsume(JSPrincipals *a, JSPrincipals *b) {
+    if (!a)
+        return JS_TRUE;
+    if (!b)
+        return JS_FALSE;
+    const std::string &ao = static_cast<jsrust_principals *>(a)->origin;
+    const std::string &bo = static_cast<jsrust_principals *>(b)->origin;
+    return ao == SYSTEM_ORIGIN || ao == bo;
+}
+
+static pthread_mutex_t global_principals_mutex = PTHREAD_MUTEX_INITIALIZER;
+static std::map<JSObject *, JSPrincipals *> global_principals;
+
+JSPrincipals *jsrust_principals_for_global(JSObject *global) {
+    JSPrincipals *p = NULL;
+    pthread_mutex_lock(&global_principals_mutex);
+    std::map<JSObject *, JSPrincipals *>::iterator i =
+        global_principals.find(global);
+    if (i != global_principals.end())
+        p = i->second;
+    pthread_mutex_unlock(&global_principals_mutex);
+    return p;
+}
+
+void jsrust_forget_global(JSContext *cx, JSObject *obj) {
+    if (!(JS_GET_CLASS(cx, obj)->flags & JSCLASS_IS_GLOBAL))
+        return;
+
+    JSPrincipals *p = NULL;
+    pthread_mutex_lock(&global_principals_mutex);
+    std::map<JSObject *, JSPrincipals *>::iterator i =
+        global_principals.find(obj);
+    if (i != global_principals.end()) {
+        p = i->second;
+        global_principals.erase(i);
+    }
+    pthread_mutex_unlock(&global_principals_mutex);
+    if (p)
+        JSPRINCIPALS_DROP(cx, p);
+}
+
+/* Finalizes globals of classes that are not traced. */
+void jsrust_global_finalize(JSContext *cx, JSObject *obj) {
+    jsrust_forget_global(cx, obj);
+}
+
+/* Whether subject's scripts may use target's objects, reporting if not. */
+bool jsrust_may_access(JSContext *cx, JSObject *subject, JSObject *target) {
+    JSPrincipals *sp = jsrust_principals_for_global(subject);
+    JSPrincipals *tp = jsrust_principals_for_global(target);
+    if (jsrust_principals_subsume(sp, tp))
+        return true;
+
+    JS_ReportError(cx, "Permission denied: %s may not access %s",
+                   sp ? static_cast<jsrust_principals *>(sp)->origin.c_str()
+                      : SYSTEM_ORIGIN,
+                   tp ? static_cast<jsrust_principals *>(tp)->origin.c_str()
+                      : SYSTEM_ORIGIN);
+    return false;
+}
+
+JSBool jsrust_check_access(JSContext *cx, JSObject *obj, jsid id,
+                           JSAccessMode mode, jsval *vp) {
+    JSObject *subject = JS_GetGlobalForScopeChain(cx);
+    JSObject *target = JS_GetGlobalForObject(cx, obj);
+    if (!subject || subject == target)
+        return JS_TRUE;
+    return jsrust_may_access(cx, subject, target);
+}
+
+/* A cross-compartment wrapper through which nothing may be done. */
+class jsrust_denying_wrapper : public JSCrossCompartmentWrapper {
+  public:
+    jsrust_denying_wrapper() : JSCrossCompartmentWrapper(0) {}
+
+    virtual bool enter(JSContext *cx, JSObject *wrapper, jsid id,
+                       Action act) {
+        /* The wrapper's parent is the global it was made for. The error
+         * is made there too, or its scripts could not read it. */
+        JSObject *subject = JS_GetGlobalForObject(cx, wrapper);
+        JSAutoEnterCompartment ac;
+        if (!ac.enter(cx, subject))
+            return false;
+        return jsrust_may_access(cx, subject,
+                                 JS_GetGlobalForObject(cx,
+                                     JSWrapper::wrappedObject(wrapper)));
+    }
+
+    static jsrust_denying_wrapper singleton;
+};
+
+jsrust_denying_wrapper jsrust_denying_wrapper::singleton;
+
+/*
+ * Wraps obj for use by the scripts of global parent: with a denying
+ * wrapper if parent's principals do not subsume those of obj's global,
+ * or else as SpiderMonkey would.
+ */
+JSObject *jsrust_wrap_object(JSContext *cx, JSObject *obj, JSObject *proto,
+                             JSObject *parent, uintN flags) {
+    JSPrincipals *sp = jsrust_principals_for_global(parent);
+    JSPrincipals *tp =
+        jsrust_principals_for_global(JS_GetGlobalForObject(cx, obj));
+    if (!jsrust_principals_subsume(sp, tp))
+        return JSWrapper::New(cx, obj, proto, parent,
+                              &jsrust_denying_wrapper::singleton);
+
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(JS_GetRuntime(cx));
+    return priv->prev_wrap_callback(cx, obj, proto, parent, flags);
+}
+
+bool jsrust_name_listed(const char *name, const char *list) {
+    size_t len = strlen(name);
+    for (const char *p = list; *p; ) {
+        const char *end = strchr(p, ',');
+        size_t n = end ? size_t(end - p) : strlen(p);
+        if (n == len && !strncmp(p, name, n))
+            return true;
+        if (!end)
+            break;
+        p = end + 1;
+    }
+    return false;
+}
+
+JSBool jsrust_define_listed(JSContext *cx, JSObject *obj,
+                            JSFunctionSpec *fs, const char *list) {
+    for (; fs->name; fs++) {
+        if (!jsrust_name_listed(fs->name, list))
+            continue;
+        if (!JS_DefineFunction(cx, obj, fs->name, (JSNative)fs->call,
+                               fs->nargs, fs->flags))
+            return JS_FALSE;
+    }
+    return JS_TRUE;
+}
+
+}   /* end anonymous namespace */
+
+extern "C" JSPrincipals *JSRust_NewPrincipals(const char *origin) {
+    jsrust_principals *p = new jsrust_principals();
+    p->codebase = NULL;
+    p->getPrincipalArray = NULL;
+    p->globalPrivilegesEnabled = NULL;
+    p->refcount = 0;
+    p->destroy = jsrust_principals_destroy;
+    p->subsume = jsrust_principals_subsume;
+    p->origin = origin;
+    return p;
+}
+
+extern "C" JSPrincipals *JSRust_GetSystemPrincipals() {
+    static JSPrincipals *system = NULL;
+    pthread_mutex_lock(&global_principals_mutex);
+    if (!system) {
+        system = JSRust_NewPrincipals(SYSTEM_ORIGIN);
+        system->refcount = 1;   /* never destroyed */
+    }
+    pthread_mutex_unlock(&global_principals_mutex);
+    return system;
+}
+
+extern "C" rust_str *JSRust_GetPrincipalsOrigin(JSPrincipals *p) {
+    if (!p)
+        return rust_str::make(SYSTEM_ORIGIN);
+    return rust_str::make(static_cast<jsrust_principals *>(p)->origin.c_str());
+}
+
+extern "C" JSBool JSRust_Subsumes(JSPrincipals *a, JSPrincipals *b) {
+    return jsrust_principals_subsume(a, b);
+}
+
+extern "C" JSCheckAccessOp JSRust_GetCheckAccessHook() {
+    return jsrust_check_access;
+}
+
+extern "C" JSFinalizeOp JSRust_GetGlobalFinalizeHook() {
+    return jsrust_global_finalize;
+}
+
+/*
+ * Records the principals of a new global, whose class must finalize it
+ * with the global or traced class finalize hook, and checks access
+ * between the runtime's compartments from then on.
+ */
+extern "C" void JSRust_SetGlobalPrincipals(JSContext *cx, JSObject *global,
+                                           JSPrincipals *p) {
+    jsrust_runtime_priv *priv = jsrust_get_runtime_priv(JS_GetRuntime(cx));
+    if (!priv->prev_wrap_callback)
+        priv->prev_wrap_callback = JS_SetWrapObjectCallbacks(
+            JS_GetRuntime(cx), jsrust_wrap_object, NULL);
+
+    if (!p)
+        return;
+    JSPRINCIPALS_HOLD(cx, p);
+    pthread_mutex_lock(&global_principals_mutex);
+    global_principals[global] = p;
+    pthread_mutex_unlock(&global_principals_mutex);
+}
+
+/*
+ * Defines name on obj as target, wrapped for obj's compartment, so that
+ * obj's scripts can reach target as far as their principals allow.
+ */
+extern "C" JSBool JSRust_DefineObject(JSContext *cx, JSObject *obj,
+                                      const char *name, JSObject *target) {
+    JSAutoEnterCompartment ac;
+    if (!ac.enter(cx, obj))
+        return JS_FALSE;
+
+    jsval v = OBJECT_TO_JSVAL(target);
+    return JS_WrapValue(cx, &v) &&
+           JS_DefineProperty(cx, obj, name, v, NULL, NULL, JSPROP_ENUMERATE);
+}
+
+extern "C" JSBool JSRust_DefineHostFunctions(JSContext *cx, JSObject *obj,
+                                             const char *whitelist) {
+    return jsrust_define_listed(cx, obj, global_functions, whitelist) &&
+           jsrust_define_listed(cx, obj, postMessage_functions, whitelist) &&
+           jsrust_define_listed(cx, obj, io_functions, whitelist);
+}
+
+/*
+ * Sockets.
+ *
+ * Blocking TCP helpers for the host's networking tasks, which run on
+ * their own threads so that waiting here never stalls an actor. Failures
+ * are returned as negative errno values (or EAI_ codes offset by
+ * JSRUST_EAI_BASE for name lookups); JSRust_NetErrorString describes them.
+ */
+
+#define JSRUST_EAI_BASE 100000
+
+extern "C" int JSRust_NetConnect(const char *host, uint32_t port) {
+    char service[16];
+    snprintf(service, sizeof service, "%u", port);
+
+    struct addrinfo hints, *res;
+    memset(&hints, 0, sizeof hints);
+    hints.ai_family = AF_UNSPEC;
+    hints.ai_socktype = SOCK_STREAM;
+    int rv = getaddrinfo(host, service, &hints, &res);
+    if (rv != 0)
+        return -(JSRUST_EAI_BASE + abs(rv));
+
+    int err = ECONNREFUSED;
+    for (struct addrinfo *ai = res; ai; ai = ai->ai_next) {
+        int fd = socket(ai->ai_family, ai->ai_socktype, ai->ai_protocol);
+        if (fd < 0) {
+            err = errno;
+            continue;
+        }
+        if (connect(fd, ai->ai_addr, ai->ai_addrlen) == 0) {
+            freeaddrinfo(res);
+            return fd;
+        }
+        err = errno;
+        close(fd);
+    }
+    freeaddrinfo(res);
+    return -err;
+}
+
+extern "C" int JSRust_NetWrite(int fd, const uint8_t *buf, size_t len) {
+    size_t done = 0;
+    while (done < len) {
+        ssize_t n = send(fd, buf + done, len - done, MSG_NOSIGNAL);
+        if (n < 0) {
+            if (errno == EINTR)
+                continue;
+            return -errno;
+        }
+        done += n;
+    }
+    return (int)done;
+}
+
+/* Reads up to len bytes, waiting at most timeout_ms (0 waits forever).
+ * Returns 0 at end of stream. */
+extern "C" int JSRust_NetRead(int fd, uint8_t *buf, size_t len,
+                              uint32_t timeout_ms) {
+    struct pollfd pfd = { fd, POLLIN, 0 };
+    int rv;
+    do {
+        rv = poll(&pfd, 1, timeout_ms ? (int)timeout_ms : -1);
+    } while (rv < 0 && errno == EINTR);
+    if (rv < 0)
+        return -errno;
+    if (rv == 0)
+        return -ETIMEDOUT;
+
+    ssize_t n;
+    do {
+        n = recv(fd, buf, len, 0);
+    } while (n < 0 && errno == EINTR);
+    return n < 0 ? -errno : (int)n;
+}
+
+/* What JSRust_NetRead returns when the time runs out. */
+extern "C" int JSRust_NetTimedOut() {
+    return -ETIMEDOUT;
+}
+
+extern "C" void JSRust_NetClose(int fd) {
+    close(fd);
+}
+
+extern "C" rust_str *JSRust_NetErrorString(int code) {
+    if (code <= -JSRUST_EAI_BASE) {
+        /* EAI_ codes are negative on some systems and positive on others. */
+        int eai = -code - JSRUST_EAI_BASE;
+        return rust_str::make(gai_strerror(EAI_NONAME < 0 ? -eai : eai));
+    }
+    return rust_str::make(strerror(-code));
+}
+
+/*
+ * TLS over a connected socket, with OpenSSL. A failed call leaves a
+ * message for JSRust_TlsError in the calling thread.
+ */
+static pthread_once_t tls_once = PTHREAD_ONCE_INIT;
+static __thread char tls_error[256];
+
+static void jsrust_tls_init() {
+    SSL_library_init();
+    SSL_load_error_strings();
+}
+
+static void jsrust_tls_set_error(const char *what) {
+    unsigned long e = ERR_get_error();
+    if (e)
+        snprintf(tls_error, sizeof tls_error, "%s: %s", what,
+                 ERR_error_string(e, NULL));
+    else if (errno)
+        snprintf(tls_error, sizeof tls_error, "%s: %s", what,
+                 strerror(errno));
+    else
+        snprintf(tls_error, sizeof tls_error, "%s", what);
+    ERR_clear_error();
+}
+
+/*
+ * OpenSSL writes to its socket with write(), which raises SIGPIPE once
+ * the peer has reset the connection, and SIGPIPE ends the process. The
+ * plain socket calls avoid it with MSG_NOSIGNAL; TLS calls are made
+ * while one of these holds SIGPIPE back for the calling thread, and any
+ * it raised is discarded, so the call fails with EPIPE instead.
+ */
+class jsrust_no_sigpipe {
+    sigset_t pipe_set, old_mask;
+    bool was_pending;
+
+    bool pending() {
+        sigset_t set;
+        sigpending(&set);
+        return sigismember(&set, SIGPIPE);
+    }
+
+public:
+    jsrust_no_sigpipe() {
+        sigemptyset(&pipe_set);
+        sigaddset(&pipe_set, SIGPIPE);
+        was_pending = pending();
+        pthread_sigmask(SIG_BLOCK, &pipe_set, &old_mask);
+    }
+
+    ~jsrust_no_sigpipe() {
+        int saved = errno;
+        if (!was_pending && pending()) {
+            struct timespec zero = { 0, 0 };
+            while (sigtimedwait(&pipe_set, NULL, &zero) < 0 && errno == EINTR)
+                ;
+        }
+        pthread_sigmask(SIG_SETMASK, &old_mask, NULL);
+        errno = saved;
+    }
+};
+
+/*
+ * Starts TLS on fd and checks that the server's certificate is valid for
+ * host: it must chain to a CA in ca_file (the system's CAs when empty),
+ * or to trusted_cert when one is given, such as a test server's
+ * self-signed certificate. Host names are also sent with SNI. Returns
+ * NULL on failure.
+ */
+extern "C" SSL *JSRust_TlsStart(int fd, const char *host,
+                                const char *ca_file,
+                                const char *trusted_cert) {
+    pthread_once(&tls_once, jsrust_tls_init);
+    errno = 0;
+
+    SSL_CTX *ctx = SSL_CTX_new(SSLv23_client_method());
+    if (!ctx) {
+        jsrust_tls_set_error("cannot create a TLS context");
+        return NULL;
+    }
+    SSL_CTX_set_options(ctx, SSL_OP_NO_SSLv2 | SSL_OP_NO_SSLv3);
+    SSL_CTX_set_verify(ctx, SSL_VERIFY_PEER, NULL);
+    int ok = *ca_file ? SSL_CTX_load_verify_locations(ctx, ca_file, NULL)
+                      : SSL_CTX_set_default_verify_paths(ctx);
+    if (ok && *trusted_cert)
+        ok = SSL_CTX_load_verify_locations(ctx, trusted_cert, NULL);
+    if (!ok) {
+        jsrust_tls_set_error("cannot load CA certificates");
+        SSL_CTX_free(ctx);
+        return NULL;
+    }
+
+    /* The connection holds its own reference to the context. */
+    SSL *ssl = SSL_new(ctx);
+    SSL_CTX_free(ctx);
+    if (!ssl) {
+        jsrust_tls_set_error("cannot create a TLS connection");
+        return NULL;
+    }
+
+    X509_VERIFY_PARAM *param = SSL_get0_param(ssl);
+    unsigned char addr[16];
+    if (inet_pton(AF_INET, host, addr) == 1 ||
+        inet_pton(AF_INET6, host, addr) == 1) {
+        /* SNI is for names only. */
+        X509_VERIFY_PARAM_set1_ip_asc(param, host);
+    } else {
+        SSL_set_tlsext_host_name(ssl, host);
+        X509_VERIFY_PARAM_set1_host(param, host, 0);
+    }
+
+    SSL_set_fd(ssl, fd);
+    jsrust_no_sigpipe guard;
+    if (SSL_connect(ssl) != 1) {
+        long v = SSL_get_verify_result(ssl);
+        if (v != X509_V_OK) {
+            snprintf(tls_error, sizeof tls_error,
+                     "certificate verification failed: %s",
+                     X509_verify_cert_error_string(v));
+            ERR_clear_error();
+        } else {
+            jsrust_tls_set_error("TLS handshake failed");
+        }
+        SSL_free(ssl);
+        return NULL;
+    }
+    return ssl;
+}
+
+extern "C" int JSRust_TlsWrite(SSL *ssl, const uint8_t *buf, size_t len) {
+    jsrust_no_sigpipe guard;
+    size_t done = 0;
+    errno = 0;
+    while (done < len) {
+        int n = SSL_write(ssl, buf + done, (int)(len - done));
+        if (n <= 0) {
+            jsrust_tls_set_error("TLS write failed");
+            return -1;
+        }
+        done += n;
+    }
+    return (int)done;
+}
+
+/* Like JSRust_NetRead, but -1 means see JSRust_TlsError. */
+extern "C" int JSRust_TlsRead(SSL *ssl, int fd, uint8_t *buf, size_t len,
+                              uint32_t timeout_ms) {
+    if (!SSL_pending(ssl)) {
+        struct pollfd pfd = { fd, POLLIN, 0 };
+        int rv;
+        do {
+            rv = poll(&pfd, 1, timeout_ms ? (int)timeout_ms : -1);
+        } while (rv < 0 && errno == EINTR);
+        if (rv < 0) {
+            snprintf(tls_error, sizeof tls_error, "%s", strerror(errno));
+            return -1;
+        }
+        if (rv == 0)
+            return -ETIMEDOUT;
+    }
+
+    /* Reading can write too, such as an answer to a key update. */
+    jsrust_no_sigpipe guard;
+    errno = 0;
+    int n = SSL_read(ssl, buf, (int)len);
+    if (n > 0)
+        return n;
+    switch (SSL_get_error(ssl, n)) {
+      case SSL_ERROR_ZERO_RETURN:
+        return 0;
+      case SSL_ERROR_WANT_READ:
+      case SSL_ERROR_WANT_WRITE:
+        /* Only part of a record has arrived. */
+        return -ETIMEDOUT;
+      case SSL_ERROR_SYSCALL:
+        /* Many servers close without a close_notify. */
+        if (ERR_peek_error() == 0 && errno == 0)
+            return 0;
+        /* fall through */
+      default:
+        jsrust_tls_set_error("TLS read failed");
+        return -1;
+    }
+}
+
+extern "C" void JSRust_TlsClose(SSL *ssl) {
+    jsrust_no_sigpipe guard;
+    SSL_shutdown(ssl);
+    SSL_free(ssl);
+}
+
+extern "C" rust_str *JSRust_TlsError() {
+    return rust_str::make(tls_error);
+}
+
+/* For the WebSocket handshake: its accept key and random nonces. */
+extern "C" void JSRust_Sha1(const uint8_t *buf, size_t len, uint8_t *out) {
+    SHA1(buf, len, out);
+}
+
+extern "C" void JSRust_RandomBytes(uint8_t *buf, size_t len) {
+    if (RAND_bytes(buf, len) != 1)
+        abort();
+}
+
+/*
+ * An alarm for an actor's timer task to wait on. JSRust_WaitAlarm
+ * returns after ms milliseconds, or as soon as the alarm rings; a ring
+ * while nobody waits ends the next wait at once, so none is lost.
+ */
+struct jsrust_alarm {
+    pthread_mutex_t mutex;
+    pthread_cond_t cond;
+    bool rung;
+};
+
+extern "C" jsrust_alarm *JSRust_NewAlarm() {
+    jsrust_alarm *alarm = new jsrust_alarm;
+    pthread_mutex_init(&alarm->mutex, NULL);
+    pthread_cond_init(&alarm->cond, NULL);
+    alarm->rung = false;
+    return alarm;
+}
+
+extern "C" void JSRust_WaitAlarm(jsrust_alarm *alarm, uint32_t ms) {
+    uint64_t until = jsrust_clock_ns(CLOCK_REALTIME) + uint64_t(ms) * 1000000ull;
+    struct timespec ts = { time_t(until / 1000000000ull),
+                           long(until % 1000000000ull) };
+    pthread_mutex_lock(&alarm->mutex);
+    while (!alarm->rung) {
+        if (pthread_cond_timedwait(&alarm->cond, &alarm->mutex, &ts) == ETIMEDOUT)
+            break;
+    }
+    alarm->rung = false;
+    pthread_mutex_unlock(&alarm->mutex);
+}
+
+extern "C" void JSRust_RingAlarm(jsrust_alarm *alarm) {
+    pthread_mutex_lock(&alarm->mutex);
+    alarm->rung = true;
+    pthread_cond_signal(&alarm->cond);
+    pthread_mutex_unlock(&alarm->mutex);
+}
+
+extern "C" void JSRust_FreeAlarm(jsrust_alarm *alarm) {
+    pthread_cond_destroy(&alarm->cond);
+    pthread_mutex_destroy(&alarm->mutex);
+    delete alarm;
 }
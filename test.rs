@@ -1,37 +1,15 @@
 
 use spidermonkey;
-import spidermonkey::js;
+import spidermonkey::{ js, dom, html, select, css, layout, render, fspolicy, net, url, cookie, http, websocket, timer };
 
 use std;
-import std::{ io, json, map, os, treemap, uv };
+import std::{ io, json, map, os, time, treemap, uv };
 
-import ctypes::size_t;
-import comm::{ port, chan, recv, send, select2 };
+import ctypes::{ size_t, void };
+import comm::{ port, chan, recv, send, select2, peek };
 import core::error;
 
 
-type element = {
-    mut tag: str,
-    mut attr: option::t<treemap::treemap<str, str>>,
-    mut parent: uint,
-    mut child: @mut[uint]
-};
-
-
-enum node {
-    doctype(str, str, str, uint),
-    procinst(str, uint),
-    text(str, uint),
-    element(element),
-    nonode,
-}
-
-
-type document = {
-    mut nodes: [mut node],
-};
-
-
 enum out_msg {
     stdout(str),
     stderr(str),
@@ -41,15 +19,56 @@ enum out_msg {
 }
 
 
+type config = {
+    maxbytes: u32,
+    // CPU time budget per script run, in milliseconds (0 = unlimited)
+    budget_ms: u32,
+    fs: fspolicy::policy,
+    // How layout.js sends mutation records: "binary" or "json"
+    protocol: str,
+    // Selectors to look up in the DOM mirror when the script finishes
+    queries: [str],
+    // Events to dispatch, in order, once the page has nothing left to do
+    events: [host_event],
+    // Viewport width for laying out the mirror, in px (0 = no layout)
+    layout_width: uint,
+    // Where to draw the document at the end: "" for nowhere, "-" for a
+    // character grid on stdout, or a .ppm file
+    render: str,
+    // How https requests check certificates
+    tls: net::tls_config,
+    // Whether all actors keep their cookies in one jar
+    shared_cookies: bool,
+    // The origin of a sandbox to give the page as `sandbox` ("" for none)
+    sandbox: str
+};
+
+// An event for the host to dispatch at the first element matching
+// `target`. `props` is a JSON object of event properties.
+type host_event = {event: str, target: str, props: str};
+
+
+// Time spent keeping the DOM mirror up to date, for comparing protocols.
+type mutation_stats = {
+    mut batches: uint,
+    mut records: uint,
+    mut bytes: uint,
+    mut apply_ns: u64
+};
+
+
 enum ctl_msg {
     io_cb(u32, u32, u32, u32, str),
     load_url(str),
-    load_script(str)
+    load_script(str),
+    dispatch_event(uint, str, str),     // nid, type, JSON properties
+    request_done(uint)                  // the request's thread has finished
 }
 
 
-fn make_context(maxbytes : u32) -> (js::context, js::object) {
-    let rt = js::get_thread_runtime(maxbytes),
+fn make_context(conf : config) -> (js::context, js::object) {
+    let maxbytes = conf.maxbytes,
+        rt = js::get_thread_runtime(maxbytes),
         cx = js::new_context(rt, maxbytes as size_t);
 
     js::set_version(cx, 185u);
@@ -58,13 +77,17 @@ fn make_context(maxbytes : u32) -> (js::context, js::object) {
 
     let globclass = js::new_class({
         name: "global",
-        flags: js::ext::get_global_class_flags() });
+        flags: js::ext::get_global_class_flags(),
+        traced: false,
+        check_access: true });
 
     let global = js::new_compartment_and_global_object(
         cx, globclass, js::null_principals());
 
     js::init_standard_classes(cx, global);
     js::ext::init_rust_library(cx, global);
+    js::ext::set_script_budget(cx, conf.budget_ms);
+    fspolicy::install(cx, conf.fs);
 
     ret (cx, global);
 }
@@ -75,6 +98,7 @@ fn run_script(cx : js::context, global : js::object, filename : str) {
         result::ok(file) {
             let script = js::compile_script(
                 cx, global, file, filename, 0u);
+            let _w = js::ext::arm_watchdog(cx);
             js::execute_script(cx, global, script);
         }
         _ { fail #fmt("error reading file %s", filename) }
@@ -82,7 +106,7 @@ fn run_script(cx : js::context, global : js::object, filename : str) {
 }
 
 
-fn on_js_msg(myid : int, out : chan<out_msg>, m : js::jsrust_message, childid : int, doc : @document) -> int {
+fn on_js_msg(myid : int, out : chan<out_msg>, m : js::jsrust_message, childid : int, doc : @dom::document, stats : mutation_stats, cx : js::context, conf : config, ctl : chan<ctl_msg>, client : http::client, requests : map::hashmap<uint, chan<()>>, sockets : map::hashmap<uint, chan<websocket::command>>, timers : timer::timers<ctl_msg>) -> int {
     // messages from javascript
     alt m.level{
         0u32 { } // CONNECT
@@ -106,15 +130,194 @@ fn on_js_msg(myid : int, out : chan<out_msg>, m : js::jsrust_message, childid :
             ret childid + 1;
         }
         7u32 { } // cast
-        8u32 { } // SETTIMEOUT
+        8u32 { // SETTIMEOUT
+            timer::schedule(timers, m.tag as uint, m.timeout as uint,
+                            io_cb(8u32, m.tag, 0u32, 0u32, ""));
+        }
         9u32 { ret -1; } // exit
         10u32 { // layout event
             //std::io::println(m.message);
-            alt json::from_str(m.message) {
-                result::ok(v) {
-                    on_layout_msg(doc, v);
+            let start = time::precise_time_ns();
+            dom::apply_json(doc, m.message);
+            stats.apply_ns += time::precise_time_ns() - start;
+            stats.batches += 1u;
+            stats.records += 1u;
+            stats.bytes += str::len(m.message);
+        }
+        11u32 { // FILE_READ
+            alt fspolicy::read(cx, conf.fs, m.message) {
+                result::ok(data) {
+                    send(ctl, io_cb(11u32, m.tag, 0u32, 0u32, data));
+                }
+                result::err(e) {
+                    send(ctl, io_cb(13u32, m.tag, 0u32, 0u32, e));
+                }
+            }
+        }
+        12u32 { // FILE_WRITE
+            // The path is the message, and the data its bytes.
+            alt fspolicy::write(cx, conf.fs, m.message, m.bytes) {
+                result::ok(_) {
+                    send(ctl, io_cb(12u32, m.tag, 0u32, 0u32, ""));
+                }
+                result::err(e) {
+                    send(ctl, io_cb(13u32, m.tag, 0u32, 0u32, e));
                 }
-                _ { fail }
+            }
+        }
+        14u32 { // MUTATIONS
+            let start = time::precise_time_ns();
+            stats.records += dom::apply_binary(doc, m.bytes);
+            stats.apply_ns += time::precise_time_ns() - start;
+            stats.batches += 1u;
+            stats.bytes += vec::len(m.bytes);
+        }
+        15u32 { // HTTP_REQUEST
+            alt http::request_from_json(m.message) {
+                result::ok(req) {
+                    // The request blocks on the network, so it gets a
+                    // thread of its own. The response comes back as
+                    // HTTP_HEADERS, then HTTP_DATA for each piece of the
+                    // body, then HTTP_DONE, or else as HTTP_ERROR. The
+                    // thread hands back a channel for aborting it.
+                    let tag = m.tag, reply = port();
+                    let reply_chan = chan(reply);
+                    task::spawn_sched(task::single_threaded) {||
+                        let cancel = port::<()>();
+                        send(reply_chan, chan(cancel));
+                        let text = http::new_decoder();
+                        let r = http::fetch(client, req, {|| peek(cancel) }) {|url, e|
+                            alt e {
+                                http::head(status, reason, headers) {
+                                    send(ctl, io_cb(16u32, tag, 0u32, 0u32,
+                                        http::head_to_json(status, reason,
+                                                           headers, url)));
+                                }
+                                http::data(bytes) {
+                                    send(ctl, io_cb(17u32, tag,
+                                                    vec::len(bytes) as u32, 0u32,
+                                                    http::decode(text, bytes)));
+                                }
+                                http::end {
+                                    // With whatever text was held back.
+                                    send(ctl, io_cb(18u32, tag, 0u32, 0u32,
+                                                    http::flush(text)));
+                                }
+                            }
+                        };
+                        alt r {
+                            result::ok(_) { }
+                            result::err(e) {
+                                // An aborted request has already been
+                                // settled by the page.
+                                if !peek(cancel) {
+                                    send(out, stderr(#fmt("[ERROR %d] %s: %s",
+                                                          myid, req.url, e)));
+                                    send(ctl, io_cb(19u32, tag, 0u32, 0u32, e));
+                                }
+                            }
+                        }
+                        // Last, so the client outlives every request on it.
+                        send(ctl, request_done(tag as uint));
+                    };
+                    requests.insert(tag as uint, recv(reply));
+                }
+                result::err(e) {
+                    send(out, stderr(#fmt("[ERROR %d] %s", myid, e)));
+                    send(ctl, io_cb(19u32, m.tag, 0u32, 0u32, e));
+                }
+            }
+        }
+        20u32 { // HTTP_ABORT
+            alt requests.find(m.tag as uint) {
+                option::some(cancel) {
+                    // Forgotten once its thread has finished.
+                    send(cancel, ());
+                }
+                option::none { }
+            }
+        }
+        21u32 { // CLEAR_TIMEOUT
+            timer::cancel(timers, m.tag as uint);
+        }
+        22u32 { // WS_OPEN
+            alt websocket::request_from_json(m.message) {
+                result::ok((u, protocols)) {
+                    // Like an HTTP request, the connection gets a thread
+                    // of its own, which hands back a channel for what the
+                    // page sends. Its events come back as WS_OPENED, then
+                    // WS_MESSAGE for each message, then WS_CLOSED, with
+                    // WS_ERROR before it if the connection failed.
+                    let tag = m.tag, reply = port();
+                    let reply_chan = chan(reply);
+                    task::spawn_sched(task::single_threaded) {||
+                        let commands = port::<websocket::command>();
+                        send(reply_chan, chan(commands));
+                        websocket::run(client, u, protocols, commands) {|e|
+                            send(ctl, alt e {
+                                websocket::opened(protocol) {
+                                    io_cb(25u32, tag, 0u32, 0u32, protocol)
+                                }
+                                websocket::message(binary, payload) {
+                                    if binary {
+                                        io_cb(26u32, tag, 1u32, 0u32,
+                                              websocket::base64_encode(payload))
+                                    } else {
+                                        io_cb(26u32, tag, 0u32, 0u32,
+                                              str::from_bytes(payload))
+                                    }
+                                }
+                                websocket::failed(why) {
+                                    send(out, stderr(#fmt("[ERROR %d] %s: %s", myid,
+                                                          url::to_str(u), why)));
+                                    io_cb(28u32, tag, 0u32, 0u32, why)
+                                }
+                                websocket::closed(code, reason, clean) {
+                                    io_cb(27u32, tag, code as u32, 0u32,
+                                          #fmt("{\"code\":%u,\"reason\":%s,\"wasClean\":%b}",
+                                               code, json::to_str(json::string(reason)),
+                                               clean))
+                                }
+                            });
+                        };
+                    };
+                    sockets.insert(tag as uint, recv(reply));
+                }
+                result::err(e) {
+                    send(out, stderr(#fmt("[ERROR %d] %s", myid, e)));
+                    send(ctl, io_cb(28u32, m.tag, 0u32, 0u32, e));
+                    send(ctl, io_cb(27u32, m.tag, 1006u32, 0u32,
+                                    "{\"code\":1006,\"reason\":\"\",\"wasClean\":false}"));
+                }
+            }
+        }
+        23u32 { // WS_SEND
+            alt sockets.find(m.tag as uint) {
+                option::some(commands) {
+                    // The data is in the bytes, which the engine encoded
+                    // as UTF-8.
+                    let data = str::from_bytes(m.bytes);
+                    if m.pad == 1u32 {
+                        alt websocket::base64_decode(data) {
+                            result::ok(b) { send(commands, websocket::send_binary(b)); }
+                            result::err(e) {
+                                send(out, stderr(#fmt("[ERROR %d] %s", myid, e)));
+                            }
+                        }
+                    } else {
+                        send(commands, websocket::send_text(data));
+                    }
+                }
+                option::none { }
+            }
+        }
+        24u32 { // WS_CLOSE
+            alt sockets.find(m.tag as uint) {
+                option::some(commands) {
+                    // Forgotten once WS_CLOSED is on its way.
+                    send(commands, websocket::close(m.timeout as uint, m.message));
+                }
+                option::none { }
             }
         }
         _ { fail "unexpected case" }
@@ -123,11 +326,36 @@ fn on_js_msg(myid : int, out : chan<out_msg>, m : js::jsrust_message, childid :
 }
 
 
-fn on_ctl_msg(cx : js::context, global : js::object, msg : ctl_msg, checkwait : js::script, loadurl : js::script) {
+// Drops the channel of a request or connection whose thread is done with
+// the client. Host-only messages are not passed on to the page.
+fn forget_finished(msg : ctl_msg, requests : map::hashmap<uint, chan<()>>,
+                   sockets : map::hashmap<uint, chan<websocket::command>>) -> bool {
+    alt msg {
+        request_done(tag) {
+            requests.remove(tag);
+            ret false;
+        }
+        io_cb(27u32, tag, _, _, _) { // WS_CLOSED, the connection's last event
+            sockets.remove(tag as uint);
+        }
+        _ { }
+    }
+    ret true;
+}
+
+
+// Runs a host callback. The jobs it queues run before the page is checked
+// for being done, and before any other callback.
+fn on_ctl_msg(cx : js::context, global : js::object, msg : ctl_msg, checkwait : js::script, loadurl : js::script, checkresume : js::script, jobs : @js::ext::job_queue) {
     alt msg {
         load_url(x) {
             js::set_data_property(cx, global, x);
-            js::execute_script(cx, global, loadurl);
+            {
+                let _w = js::ext::arm_watchdog(cx);
+                js::execute_script(cx, global, loadurl);
+            }
+            js::ext::run_jobs(cx, global, *jobs);
+            js::execute_script(cx, global, checkresume);
         }
         load_script(script) {
             alt std::io::read_whole_file(script) {
@@ -138,7 +366,11 @@ fn on_ctl_msg(cx : js::context, global : js::object, msg : ctl_msg, checkwait :
                             #fmt("try { %s } catch (e) { print(e + '\\n' + e.stack); }",
                             str::from_bytes(file))),
                             script, 0u);
-                    js::execute_script(cx, global, script);
+                    {
+                        let _w = js::ext::arm_watchdog(cx);
+                        js::execute_script(cx, global, script);
+                    }
+                    js::ext::run_jobs(cx, global, *jobs);
                     js::execute_script(cx, global, checkwait);
                 }
                 _ {
@@ -147,225 +379,531 @@ fn on_ctl_msg(cx : js::context, global : js::object, msg : ctl_msg, checkwait :
                 }
             }
         }
-        io_cb(level, tag, timeout, _p, buf) {
+        dispatch_event(nid, event, props) {
+            let code = #fmt("try { _dispatchHostEvent(%u, %s, %s); } catch (e) { print(e + '\\n' + e.stack); }",
+                            nid, json::to_str(json::string(event)), props);
+            let script = js::compile_script(cx, global, str::bytes(code), "event", 0u);
+            {
+                let _w = js::ext::arm_watchdog(cx);
+                js::execute_script(cx, global, script);
+            }
+            js::ext::run_jobs(cx, global, *jobs);
+            js::execute_script(cx, global, checkwait);
+        }
+        io_cb(level, tag, arg, _p, buf) {
             js::begin_request(*cx);
             js::set_data_property(cx, global, buf);
-            let code = #fmt("try { _resume(%u, _data, %u); } catch (e) { print(e + '\\n' + e.stack); }; _data = undefined;", level as uint, tag as uint);
+            let code = #fmt("try { _resume(%u, _data, %u, %u); } catch (e) { print(e + '\\n' + e.stack); }; _data = undefined;", level as uint, tag as uint, arg as uint);
             let script = js::compile_script(cx, global, str::bytes(code), "io", 0u);
-            js::execute_script(cx, global, script);
+            {
+                let _w = js::ext::arm_watchdog(cx);
+                js::execute_script(cx, global, script);
+            }
             js::end_request(*cx);
+            js::ext::run_jobs(cx, global, *jobs);
+            js::execute_script(cx, global, checkresume);
         }
         _ { fail "unexpected case" }
     }
 }
 
-fn on_layout_msg(doc: @document, msg_j : json::json) {
-    let msg = alt msg_j {
-            json::dict(x) { x }
-            _ { fail }
-        },
-        typ = alt msg.get("type") {
-            json::num(x) { x }
-            _ { fail }
-        },
-        target = alt msg.get("target") {
-            json::num(x) { x as uint }
-            _ { fail }
-        };
+// How long a synchronous request with no timeout of its own may take.
+const SYNC_TIMEOUT_MS : uint = 60000u;
 
-    alt typ {
-        1. { // MUTATE VALUE
-            let data = msg.get("data");
-            std::io::println(#fmt("mutate %? %?", target, data));
-        }
-        2. { // MUTATE ATTR
-            std::io::println(#fmt("mutate attr %?", target));
-        }
-        3. { // REMOVE ATTR
-            std::io::println(#fmt("remove attr %?", target));
-        }
-        4. { // REMOVE
-            let parent = alt doc.nodes[target - 1u] {
-                doctype(x, y, z, p) { p }
-                procinst(x, p) { p }
-                text(x, p) { p }
-                element(x) { x.parent }
-                _ { fail }
-            };
-            doc.nodes[target - 1u] = nonode;
-            alt doc.nodes[parent] {
-                element(x) {
-                    *x.child = vec::filter(copy *x.child, {|id|
-                        id != target
-                    });
-                    std::io::println(#fmt("remove %?", target));
-                }
-                _ { fail }
+/* The request's timeout in milliseconds, or SYNC_TIMEOUT_MS for none. */
+fn sync_timeout(json : str) -> uint {
+    alt json::from_str(json) {
+        result::ok(json::dict(m)) {
+            alt m.find("timeout") {
+                option::some(json::num(n)) if n >= 1.0 { ret n as uint; }
+                _ { }
             }
         }
-        5. { // MOVE
-            std::io::println(#fmt("move %?", target));
-        }
-        6. { // INSERT
-            let nid = alt msg.get("nid") {
-                    json::num(x) { x as uint }
-                    _ { fail }
-                },
-                index = alt msg.get("index") {
-                    json::num(x) { x as uint }
-                    _ { fail }
-                },
-                child = msg.get("child"),
-                parent = doc.nodes[target - 1u];
-
-            std::io::println(#fmt(
-                "insert %? into %? at index %?: %s",
-                nid, target, index, json::to_str(child)));
-
-            let elt = alt child {
-                json::string(s) { text(s, target) }
-                json::dict(m) { 
-                    alt m.find("html") {
-                        option::some(json::string(tn)) {
-                            element({
-                                mut tag: tn,
-                                mut attr: option::none,
-                                mut parent: target,
-                                mut child: @mut[]})
-                        }
-                        _ {
-                            alt m.find("doctype") {
-                                option::some(json::string(dt)) {
-                                    doctype(dt, "", "", target)
-                                }
-                                _ {
-                                    fail
-                                }
-                            }
+        _ { }
+    }
+    ret SYNC_TIMEOUT_MS;
+}
+
+fn past(deadline : u64) -> bool {
+    ret time::precise_time_ns() >= deadline;
+}
+
+/*
+ * Makes a synchronous XMLHttpRequest while the actor's script waits in
+ * jsrust_http_request_sync. The fetch runs on a thread of its own and
+ * only this task waits for it, so other actors keep running. It gives up
+ * with the error "timeout" once the request's timeout has passed.
+ */
+crust fn sync_request(client : *http::client, request : *u8, len : size_t,
+                      out : *void) unsafe {
+    let json = str::unsafe::from_bytes(vec::unsafe::from_buf(request, len as uint));
+    alt http::request_from_json(json) {
+        result::ok(req) {
+            let p = port(), c = chan(p), client = *client;
+            let deadline = time::precise_time_ns() +
+                (sync_timeout(json) as u64) * 1000000u64;
+            task::spawn_sched(task::single_threaded) {||
+                let head = "", body = "", text = http::new_decoder();
+                let r = http::fetch(client, req, {|| past(deadline) }) {|url, e|
+                    alt e {
+                        http::head(status, reason, headers) {
+                            head = http::head_to_json(status, reason, headers, url);
                         }
+                        http::data(bytes) { body += http::decode(text, bytes); }
+                        http::end { body += http::flush(text); }
                     }
-                }
-                _ { fail }
+                };
+                send(c, alt r {
+                    result::ok(_) { (head, body, "") }
+                    result::err(_) if past(deadline) { ("", "", "timeout") }
+                    result::err(e) { ("", "", e) }
+                });
             };
+            let (head, body, error) = recv(p);
+            js::ext::set_sync_result(out, head, body, error);
+        }
+        result::err(e) {
+            js::ext::set_sync_result(out, "", "", e);
+        }
+    }
+}
 
-            if nid - 1u == vec::len(doc.nodes) {
-                doc.nodes += [mut elt];
-            } else {
-                doc.nodes[nid - 1u] = elt;
-            }
+/* Reads or sets document.cookie for a page, in the actor's cookie jar. */
+crust fn cookie_access(client : *http::client, page : *u8, page_len : size_t,
+                       value : *u8, value_len : size_t, out : *void) unsafe {
+    fn bytes_to_str(p : *u8, len : size_t) -> str unsafe {
+        ret str::unsafe::from_bytes(vec::unsafe::from_buf(p, len as uint));
+    }
+    let value = if value == ptr::null() {
+        option::none
+    } else {
+        option::some(bytes_to_str(value, value_len))
+    };
+    alt url::parse(bytes_to_str(page, page_len), option::none) {
+        result::ok(u) {
+            js::ext::set_string(out, cookie::script_cookies((*client).cookies, u, value));
+        }
+        result::err(_) { }
+    }
+}
 
-            alt parent {
-                element(e) {
-                    *e.child = (
-                        vec::slice(*e.child, 0u, index)
-                        + [nid - 1u]
-                        + vec::slice(*e.child, index, vec::len(*e.child)));
-                }
-                nonode { 
-                    
-                }
-                _ { log(error, parent); fail }
-            }
+/* Queues a job for jsrust_enqueue_job in the actor's job queue. */
+crust fn enqueue_job(jobs : *js::ext::job_queue, job : js::jsval) unsafe {
+    js::ext::enqueue_job(*jobs, job);
+}
 
+/* Parses a URL for jsrust_parse_url, answering with its parts as JSON. */
+crust fn parse_url(input : *u8, input_len : size_t, base : *u8,
+                   base_len : size_t, out : *void) unsafe {
+    fn bytes_to_str(p : *u8, len : size_t) -> str unsafe {
+        ret str::unsafe::from_bytes(vec::unsafe::from_buf(p, len as uint));
+    }
+    let base = if base == ptr::null() {
+        option::none
+    } else {
+        alt url::parse(bytes_to_str(base, base_len), option::none) {
+            result::ok(b) { option::some(b) }
+            result::err(e) {
+                js::ext::set_string(out, "{\"error\":" + json::to_str(json::string(e)) + "}");
+                ret;
+            }
         }
-        _y {
-            log(error, _y);
+    };
+    alt url::parse(bytes_to_str(input, input_len), base) {
+        result::ok(u) { js::ext::set_string(out, url::to_json(u)); }
+        result::err(e) {
+            js::ext::set_string(out, "{\"error\":" + json::to_str(json::string(e)) + "}");
         }
     }
 }
 
+// Finds the target of the first scripted event that matches an element,
+// skipping those that do not, and returns it with the events left over.
+fn next_event(myid : int, doc : @dom::document, events : [host_event])
+        -> option<(ctl_msg, [host_event])> {
+    let i = 0u, n = vec::len(events);
+    while i < n {
+        let e = events[i];
+        i += 1u;
+        alt select::query_selector(doc, 1u, e.target) {
+            result::ok(option::some(nid)) {
+                ret option::some((dispatch_event(nid, e.event, e.props),
+                                  vec::slice(events, i, n)));
+            }
+            result::ok(option::none) {
+                std::io::println(#fmt("[Actor %d] no element matches %s for %s",
+                                      myid, e.target, e.event));
+            }
+            result::err(err) {
+                std::io::println(#fmt("[Actor %d] bad selector: %s", myid, err));
+            }
+        }
+    }
+    ret option::none;
+}
 
-fn run_actor(myid : int, myurl : str, maxbytes : u32, out : chan<out_msg>, sendchan : chan<(int, chan<ctl_msg>)>) {
+fn run_actor(myid : int, myurl : str, conf : config, shared_jar : option<cookie::jar>, out : chan<out_msg>, sendchan : chan<(int, chan<ctl_msg>)>) {
     let msg_port = port::<ctl_msg>(),
     msg_chan = chan(msg_port);
 
     send(sendchan, (myid, msg_chan));
 
-    let js_port = port::<js::jsrust_message>();
+    let jar = alt shared_jar { option::some(j) { j } option::none { cookie::new_jar() } };
+    let js_port = port::<js::jsrust_message>(),
+        client = http::new_client(conf.tls, jar),
+        requests : map::hashmap<uint, chan<()>> = map::new_uint_hash(),
+        sockets : map::hashmap<uint, chan<websocket::command>> = map::new_uint_hash(),
+        timers = timer::new_timers(msg_chan);
 
-    let (cx, global) = make_context(maxbytes);
+    let (cx, global) = make_context(conf);
+    let jobs = js::ext::new_job_queue(js::get_runtime(cx));
     js::ext::set_msg_channel(cx, global, chan(js_port));
+    // The hooks borrow the client, which outlives the context's scripts.
+    unsafe {
+        js::ext::set_sync_hook(cx, sync_request,
+                               unsafe::reinterpret_cast(ptr::addr_of(client)));
+        js::ext::set_cookie_hook(cx, cookie_access,
+                                 unsafe::reinterpret_cast(ptr::addr_of(client)));
+        js::ext::set_job_hook(cx, enqueue_job,
+                              unsafe::reinterpret_cast(ptr::addr_of(*jobs)));
+    }
+
+    let protocol = js::compile_script(cx, global, str::bytes(#fmt("var _mutationProtocol = '%s';", conf.protocol)), "config", 0u);
+    js::execute_script(cx, global, protocol);
 
     run_script(cx, global, "xmlhttprequest.js");
     run_script(cx, global, "dom.js");
     run_script(cx, global, "layout.js");
 
-    let checkwait = js::compile_script(cx, global, str::bytes("if (XMLHttpRequest.requests_outstanding === 0) jsrust_exit();"), "io", 0u),
-        loadurl = js::compile_script(cx, global, str::bytes("try { _resume(9, _data, 0) } catch (e) { print(e + '\\n' + e.stack) } _data = undefined;"), "io", 0u);
+    // The sandbox sees the page's global as `parent`, but may not use it.
+    let _sandbox = if str::is_empty(conf.sandbox) { option::none } else {
+        let sb = js::ext::new_sandbox(cx, conf.sandbox, ["print"]);
+        js::ext::define_object(cx, sb.global, "parent", global);
+        js::ext::define_object(cx, global, "sandbox", sb.global);
+        option::some(sb)
+    };
 
-    if str::len(myurl) > 4u && (
-        str::eq(str::slice(myurl, 0u, 4u), "http") ||
-        str::eq(str::slice(myurl, 0u, 4u), "file")) {
-        send(msg_chan, load_url(myurl));
-    } else {
-        let strlen = str::len(myurl);
-        if str::eq(str::slice(myurl, strlen - 5u, strlen), ".html") {
-            // hack: file urls aren't exactly the right format yet
-            send(msg_chan, load_url(#fmt("file:%s", myurl)))
-        } else {
-            send(msg_chan, load_script(myurl));
+    let checkwait = js::compile_script(cx, global, str::bytes("if (XMLHttpRequest.requests_outstanding === 0) jsrust_exit();"), "io", 0u),
+        // Like checkwait, but only after a callback that _resume ran.
+        checkresume = js::compile_script(cx, global, str::bytes("if (_resumed && XMLHttpRequest.requests_outstanding === 0) jsrust_exit(); _resumed = false;"), "io", 0u),
+        loadurl = js::compile_script(cx, global, str::bytes("try { _resume(9, _data, 0) } catch (e) { print(e + '\\n' + e.stack) } _data = undefined;"), "io", 0u),
+        flush = js::compile_script(cx, global, str::bytes("_flushMutations();"), "io", 0u);
+
+    // Pages are named by URL or by a path under the filesystem policy's
+    // root, which file: URLs name as "/"; anything else is a script.
+    let root = result::get(url::parse("file:///", option::none));
+    alt url::parse(myurl, option::none) {
+        result::ok(u) { send(msg_chan, load_url(url::to_str(u))); }
+        result::err(_) {
+            if str::ends_with(myurl, ".html") {
+                alt url::parse(myurl, option::some(root)) {
+                    result::ok(u) { send(msg_chan, load_url(url::to_str(u))); }
+                    result::err(e) { fail #fmt("bad page path %s: %s", myurl, e); }
+                }
+            } else {
+                send(msg_chan, load_script(myurl));
+            }
         }
     }
 
     let exit = false,
         childid = 0,
-        doc : @document = @{
-            mut nodes: [
-                mut element({
-                    mut tag: "Document",
-                    mut attr: option::none,
-                    mut parent: 0u,
-                    mut child: @mut[2u, 3u]}),
-                doctype("", "", "", 0u),
-                element({
-                    mut tag: "html",
-                    mut attr: option::none,
-                    mut parent: 0u,
-                    mut child: @mut[]})]};
+        doc = dom::new_document(),
+        stats = { mut batches: 0u, mut records: 0u, mut bytes: 0u,
+                  mut apply_ns: 0u64 },
+        styles = css::new_resolver(doc),
+        lay = layout::new_layout(doc, styles,
+            if conf.layout_width != 0u { conf.layout_width as float } else { 800.0 }),
+        layout_ns = 0u64,
+        started = time::precise_time_ns(),
+        events = conf.events;
 
 
     while !exit {
+        // Nothing waits while jobs are queued.
+        js::ext::run_jobs(cx, global, *jobs);
         alt select2(js_port, msg_port) {
             either::left(m) {
-                childid = on_js_msg(myid, out, m, childid, doc);
-                if childid == -1 {
-                    send(out, exitproc);
-                    exit = true;
+                let r = on_js_msg(myid, out, m, childid, doc, stats, cx, conf, msg_chan, client, requests, sockets, timers);
+                if conf.layout_width != 0u && (m.level == 10u32 || m.level == 14u32) {
+                    let start = time::precise_time_ns();
+                    layout::update(lay);
+                    layout_ns += time::precise_time_ns() - start;
+                }
+                if r != -1 {
+                    childid = r;
+                } else {
+                    // The page has settled; bring the mirror up to date and
+                    // dispatch the next scripted event instead of exiting.
+                    js::execute_script(cx, global, flush);
+                    while peek(js_port) {
+                        let r = on_js_msg(myid, out, recv(js_port), childid, doc, stats, cx, conf, msg_chan, client, requests, sockets, timers);
+                        if r != -1 { childid = r; }
+                    }
+                    alt next_event(myid, doc, events) {
+                        option::some((msg, rest)) {
+                            events = rest;
+                            send(msg_chan, msg);
+                        }
+                        option::none {
+                            send(out, exitproc);
+                            exit = true;
+                        }
+                    }
                 }
             }
             either::right(msg) {
-                on_ctl_msg(cx, global, msg, checkwait, loadurl);
+                if forget_finished(msg, requests, sockets) {
+                    on_ctl_msg(cx, global, msg, checkwait, loadurl, checkresume, jobs);
+                    js::execute_script(cx, global, flush);
+                    js::maybe_gc(cx);
+                }
             }
         }
     }
-    std::io::println(#fmt("%?", doc.nodes));
+
+    // Mutations flushed after the script asked to exit are still queued.
+    while peek(js_port) {
+        let m = recv(js_port);
+        if m.level == 10u32 || m.level == 14u32 {
+            on_js_msg(myid, out, m, childid, doc, stats, cx, conf, msg_chan, client, requests, sockets, timers);
+        }
+    }
+    // Requests and connections still open are given up, and their threads
+    // waited for, as they may yet need the client and the cookie jar.
+    requests.values {|cancel| send(cancel, ()); };
+    sockets.values {|commands| send(commands, websocket::close(1001u, "")); };
+    while requests.size() > 0u || sockets.size() > 0u {
+        forget_finished(recv(msg_port), requests, sockets);
+    }
+    timer::close_timers(timers);
+    http::close_client(client);
+    if option::is_none(shared_jar) { cookie::close_jar(jar); }
+    js::ext::close_job_queue(js::get_runtime(cx), jobs);
+
+    let html = html::serialize_document(doc);
+    std::io::println(html);
+    std::io::println(#fmt("[Actor %d] %s mutations: %u records in %u batches, %u bytes, applied in %u us, run took %u us",
+        myid, conf.protocol, stats.records, stats.batches, stats.bytes,
+        (stats.apply_ns / 1000u64) as uint,
+        ((time::precise_time_ns() - started) / 1000u64) as uint));
+
+    let snapshot = js::compile_script(cx, global, str::bytes("JSON.stringify(_mirrorSnapshot())"), "mirror", 0u);
+    alt js::execute_script(cx, global, snapshot) {
+        option::some(v) {
+            alt json::from_str(js::get_string(cx, js::value_to_string(cx, v))) {
+                result::ok(j) {
+                    let errs = dom::check_mirror(doc, j);
+                    if vec::len(errs) == 0u {
+                        std::io::println(#fmt("[Actor %d] DOM mirror matches dom.js", myid));
+                    }
+                    for e in errs {
+                        std::io::println(#fmt("[Actor %d] DOM mirror differs: %s", myid, e));
+                    }
+                }
+                _ { log(error, "unreadable DOM snapshot"); }
+            }
+        }
+        option::none { }
+    }
+
+    if conf.layout_width != 0u || !str::is_empty(conf.render) {
+        let start = time::precise_time_ns();
+        layout::update(lay);
+        layout_ns += time::precise_time_ns() - start;
+    }
+    if conf.layout_width != 0u {
+        std::io::print(layout::dump(lay));
+        std::io::println(#fmt("[Actor %d] layout took %u us in all; the last update laid out %u blocks and reused %u",
+            myid, (layout_ns / 1000u64) as uint, lay.laid_out, lay.reused));
+    }
+    if str::eq(conf.render, "-") {
+        std::io::print(render::to_text(lay));
+    } else if !str::is_empty(conf.render) {
+        let path = render_path(conf.render, myid);
+        alt render::write_ppm(lay, path) {
+            result::ok(_) {
+                std::io::println(#fmt("[Actor %d] rendered to %s", myid, path));
+            }
+            result::err(e) {
+                std::io::println(#fmt("[Actor %d] cannot render to %s: %s", myid, path, e));
+            }
+        }
+    }
+
+    for q in conf.queries {
+        alt select::query_selector_all(doc, 1u, q) {
+            result::ok(nids) {
+                std::io::println(#fmt("[Actor %d] %s: %u matches", myid, q, vec::len(nids)));
+                for nid in nids {
+                    std::io::println(html::serialize_node(doc, nid));
+                    alt css::computed_style(styles, nid) {
+                        option::some(st) { std::io::println("  " + css::to_str(st)); }
+                        option::none { }
+                    }
+                }
+            }
+            result::err(e) {
+                std::io::println(#fmt("[Actor %d] bad selector: %s", myid, e));
+            }
+        }
+    }
+
+    let markup = js::compile_script(cx, global, str::bytes("document.innerHTML"), "markup", 0u);
+    alt js::execute_script(cx, global, markup) {
+        option::some(v) {
+            if str::eq(js::get_string(cx, js::value_to_string(cx, v)), html) {
+                std::io::println(#fmt("[Actor %d] serialized mirror matches dom.js", myid));
+            } else {
+                std::io::println(#fmt("[Actor %d] serialized mirror differs from dom.js", myid));
+            }
+        }
+        option::none { }
+    }
+
+    let stats = js::get_heap_stats(js::get_runtime(cx));
+    std::io::println(#fmt("[Actor %d] heap: %u bytes after %u gcs",
+        myid, stats.bytes as uint, stats.number as uint));
+}
+
+
+// --event=TYPE:SELECTOR or --event=TYPE:SELECTOR:{"prop": value, ...}
+fn parse_event(arg : str) -> host_event {
+    let spec = str::slice(arg, 8u, str::len(arg)),
+        len = str::len(spec);
+    let colon = alt str::index(spec, ':' as u8) {
+        -1 { fail #fmt("bad event, expected TYPE:SELECTOR: %s", arg) }
+        i { i as uint }
+    };
+    let rest = str::slice(spec, colon + 1u, len),
+        (target, props) = alt str::find(rest, ":{") {
+            -1 { (rest, "{}") }
+            i {
+                let i = i as uint;
+                (str::slice(rest, 0u, i),
+                 str::slice(rest, i + 1u, str::len(rest)))
+            }
+        };
+    alt json::from_str(props) {
+        result::ok(json::dict(_)) { }
+        _ { fail #fmt("bad event properties, expected a JSON object: %s", props) }
+    }
+    ret {event: str::slice(spec, 0u, colon), target: target, props: props};
+}
+
+
+fn parse_options(args : [str]) -> (config, [str]) {
+    let conf = {
+            maxbytes: 32u32 * 1024u32 * 1024u32,
+            budget_ms: 0u32,
+            fs: {
+                root: os::getcwd(),
+                mode: fspolicy::read_only,
+                max_bytes: 16u * 1024u * 1024u
+            },
+            protocol: "binary",
+            queries: [],
+            events: [],
+            layout_width: 0u,
+            render: "",
+            tls: {ca_file: "", trusted_cert: ""},
+            shared_cookies: false,
+            sandbox: ""
+        },
+        rest = [];
+
+    for arg in args {
+        if str::starts_with(arg, "--budget=") {
+            alt uint::from_str(str::slice(arg, 9u, str::len(arg))) {
+                option::some(ms) { conf = { budget_ms: ms as u32 with conf }; }
+                option::none { fail #fmt("bad budget: %s", arg) }
+            }
+        } else if str::starts_with(arg, "--fs-root=") {
+            let root = str::slice(arg, 10u, str::len(arg));
+            conf = { fs: { root: root with conf.fs } with conf };
+        } else if str::starts_with(arg, "--protocol=") {
+            let protocol = str::slice(arg, 11u, str::len(arg));
+            if !str::eq(protocol, "binary") && !str::eq(protocol, "json") {
+                fail #fmt("unknown mutation protocol: %s", protocol);
+            }
+            conf = { protocol: protocol with conf };
+        } else if str::starts_with(arg, "--query=") {
+            let q = str::slice(arg, 8u, str::len(arg));
+            conf = { queries: conf.queries + [q] with conf };
+        } else if str::starts_with(arg, "--event=") {
+            conf = { events: conf.events + [parse_event(arg)] with conf };
+        } else if str::eq(arg, "--layout") {
+            conf = { layout_width: 800u with conf };
+        } else if str::starts_with(arg, "--layout=") {
+            alt uint::from_str(str::slice(arg, 9u, str::len(arg))) {
+                option::some(w) { conf = { layout_width: w with conf }; }
+                option::none { fail #fmt("bad layout width: %s", arg) }
+            }
+        } else if str::eq(arg, "--render") {
+            conf = { render: "-" with conf };
+        } else if str::starts_with(arg, "--render=") {
+            conf = { render: str::slice(arg, 9u, str::len(arg)) with conf };
+        } else if str::starts_with(arg, "--ca-bundle=") {
+            let f = str::slice(arg, 12u, str::len(arg));
+            conf = { tls: { ca_file: f with conf.tls } with conf };
+        } else if str::starts_with(arg, "--tls-trust=") {
+            let f = str::slice(arg, 12u, str::len(arg));
+            conf = { tls: { trusted_cert: f with conf.tls } with conf };
+        } else if str::starts_with(arg, "--sandbox=") {
+            conf = { sandbox: str::slice(arg, 10u, str::len(arg)) with conf };
+        } else if str::eq(arg, "--shared-cookies") {
+            conf = { shared_cookies: true with conf };
+        } else if str::eq(arg, "--fs-writable") {
+            conf = { fs: { mode: fspolicy::read_write with conf.fs } with conf };
+        } else {
+            rest += [arg];
+        }
+    }
+    ret (conf, rest);
+}
+
+
+/*
+ * The file actor `id` renders to. The first actor uses the path given;
+ * the others put their id before its extension, as in foo-2.ppm, so that
+ * no two actors write the same file.
+ */
+fn render_path(path : str, id : int) -> str {
+    if id == 1 { ret path; }
+    let slash = str::rindex(path, '/' as u8), dot = str::rindex(path, '.' as u8);
+    if dot <= slash + 1 {
+        ret #fmt("%s-%d", path, id);
+    }
+    ret #fmt("%s-%d%s", str::slice(path, 0u, dot as uint), id,
+             str::slice(path, dot as uint, str::len(path)));
 }
 
 
 fn main(args : [str]) {
-    let maxbytes = 32u32 * 1024u32 * 1024u32,
-        map = treemap::init();
+    let map = treemap::init();
+    js::ext::set_url_hook(parse_url);
 
     let stdoutport = port::<out_msg>(),
         stdoutchan = chan(stdoutport),
         sendchanport = port::<(int, chan<ctl_msg>)>(),
         sendchanchan = chan(sendchanport);
 
-    let argc = vec::len(args),
-        argv = if argc == 1u {
+    let (conf, rest) = parse_options(vec::slice(args, 1u, vec::len(args))),
+        argv = if vec::len(rest) == 0u {
             ["test.js"]
         } else {
-            vec::slice(args, 1u, argc)
+            rest
         };
 
     let left = 0,
-        actorid = 0;
+        actorid = 0,
+        jar = if conf.shared_cookies { option::some(cookie::new_jar()) } else { option::none };
 
     for x in argv {
         left += 1;
         actorid += 1;
         task::spawn {||
-            run_actor(actorid, x, maxbytes, stdoutchan, sendchanchan);
+            run_actor(actorid, x, conf, jar, stdoutchan, sendchanchan);
         };
     }
 
@@ -383,7 +921,7 @@ fn main(args : [str]) {
                 actorid = actorid + 1;
                 left = left + 1;
                 task::spawn {||
-                    run_actor(actorid, src, maxbytes, stdoutchan, sendchanchan);
+                    run_actor(actorid, src, conf, jar, stdoutchan, sendchanchan);
                 };
                 let (theid, thechan) = recv(sendchanport);
                 treemap::insert(map, theid, thechan);
@@ -398,5 +936,6 @@ fn main(args : [str]) {
             _ { fail "unexpected case" }
         }
     }
+    alt jar { option::some(j) { cookie::close_jar(j); } option::none { } }
 }
 
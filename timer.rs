@@ -0,0 +1,112 @@
+/*
+ * Host timers, for setTimeout and XMLHttpRequest's timeout.
+ *
+ * Each actor has one timer task, which keeps the actor's pending timers
+ * in deadline order and sleeps until the first is due, so the actor keeps
+ * handling messages while its timers run. Adding or cancelling a timer
+ * wakes the task to look again.
+ */
+
+use std;
+import std::time;
+import ctypes::void;
+import comm::{ port, chan, send, recv, peek };
+
+export timers, new_timers, schedule, cancel, close_timers;
+
+#[link_args="-L."]
+#[link_name="spidermonkeyrustext"]
+native mod jsrust {
+    fn JSRust_NewAlarm() -> *void;
+    fn JSRust_WaitAlarm(alarm : *void, ms : u32);
+    fn JSRust_RingAlarm(alarm : *void);
+    fn JSRust_FreeAlarm(alarm : *void);
+}
+
+
+enum command<T: send> {
+    // Send the value after ms milliseconds, unless the id is cancelled.
+    add(uint, uint, T),
+    remove(uint),
+    stop(chan<()>)
+}
+
+type timers<T: send> = {commands: chan<command<T>>, alarm: *void};
+
+type pending<T> = {deadline: u64, id: uint, value: T};
+
+
+/* Starts a timer task that sends each timer's value on `out`. */
+fn new_timers<T: copy send>(out : chan<T>) -> timers<T> {
+    let alarm = jsrust::JSRust_NewAlarm(), reply = port();
+    let reply_chan = chan(reply);
+    // Waiting on the alarm blocks a thread, so the task gets one.
+    task::spawn_sched(task::single_threaded) {||
+        let commands = port::<command<T>>();
+        send(reply_chan, chan(commands));
+        run(commands, alarm, out);
+    };
+    ret {commands: recv(reply), alarm: alarm};
+}
+
+
+/* Sends `v` after `ms` milliseconds, unless timer `id` is cancelled. */
+fn schedule<T: send>(t : timers<T>, id : uint, ms : uint, v : T) {
+    send(t.commands, add(id, ms, v));
+    jsrust::JSRust_RingAlarm(t.alarm);
+}
+
+
+/* Drops timer `id` if it has not gone off yet. */
+fn cancel<T: send>(t : timers<T>, id : uint) {
+    send(t.commands, remove(id));
+    jsrust::JSRust_RingAlarm(t.alarm);
+}
+
+
+/* Stops the task; timers still pending never go off. */
+fn close_timers<T: send>(t : timers<T>) {
+    let done = port();
+    send(t.commands, stop(chan(done)));
+    jsrust::JSRust_RingAlarm(t.alarm);
+    recv(done);
+    jsrust::JSRust_FreeAlarm(t.alarm);
+}
+
+
+fn run<T: copy send>(commands : port<command<T>>, alarm : *void, out : chan<T>) {
+    let queue : [pending<T>] = [];
+    while true {
+        // Take what has arrived, and wait for more if nothing is pending.
+        while vec::len(queue) == 0u || peek(commands) {
+            alt recv(commands) {
+                add(id, ms, v) {
+                    let deadline = time::precise_time_ns() +
+                        (ms as u64) * 1000000u64;
+                    // After those due at the same time, so that timers
+                    // with equal delays go off in the order they were set.
+                    let i = 0u, n = vec::len(queue);
+                    while i < n && queue[i].deadline <= deadline { i += 1u; }
+                    queue = vec::slice(queue, 0u, i) +
+                        [{deadline: deadline, id: id, value: v}] +
+                        vec::slice(queue, i, n);
+                }
+                remove(id) {
+                    queue = vec::filter(queue) {|p| p.id != id };
+                }
+                stop(done) {
+                    send(done, ());
+                    ret;
+                }
+            }
+        }
+        let now = time::precise_time_ns(), first = queue[0];
+        if first.deadline <= now {
+            queue = vec::slice(queue, 1u, vec::len(queue));
+            send(out, first.value);
+        } else {
+            let ms = (first.deadline - now + 999999u64) / 1000000u64;
+            jsrust::JSRust_WaitAlarm(alarm, ms as u32);
+        }
+    }
+}
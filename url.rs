@@ -0,0 +1,551 @@
+/*
+ * URLs, parsed and resolved after the WHATWG URL standard.
+ *
+ * The special schemes (http, https, ws, wss, ftp and file) have a host
+ * and a path of segments: backslashes count as slashes, "." and ".."
+ * segments are removed, default ports are dropped and hosts are
+ * lowercased. Other schemes keep what follows the colon as an opaque
+ * path unless it starts with "//" or "/". Characters that may not appear
+ * in a component are percent-encoded as UTF-8. Internationalized domain
+ * names and IPv4 shorthands are left as they are.
+ */
+
+use std;
+import std::json;
+import result::{ ok, err };
+
+export url, parse, to_str, is_special, default_port, port_or_default;
+export origin, request_target, to_json;
+
+type url = {
+    scheme: str,            // lowercase, without the ':'
+    username: str,
+    password: str,
+    host: option<str>,      // none for URLs like about:blank
+    port: option<uint>,     // none for the scheme's default
+    path: str,
+    query: option<str>,     // without the '?'
+    fragment: option<str>   // without the '#'
+};
+
+
+fn is_special(scheme : str) -> bool {
+    ret str::eq(scheme, "http") || str::eq(scheme, "https") ||
+        str::eq(scheme, "ws") || str::eq(scheme, "wss") ||
+        str::eq(scheme, "ftp") || str::eq(scheme, "file");
+}
+
+
+fn default_port(scheme : str) -> option<uint> {
+    ret if str::eq(scheme, "http") || str::eq(scheme, "ws") {
+        option::some(80u)
+    } else if str::eq(scheme, "https") || str::eq(scheme, "wss") {
+        option::some(443u)
+    } else if str::eq(scheme, "ftp") {
+        option::some(21u)
+    } else {
+        option::none
+    };
+}
+
+
+/* The port to connect to, which may be the scheme's default. */
+fn port_or_default(u : url) -> uint {
+    ret alt u.port {
+        option::some(p) { p }
+        option::none {
+            alt default_port(u.scheme) { option::some(p) { p } option::none { 0u } }
+        }
+    };
+}
+
+
+// Percent-encode sets, as predicates on bytes.
+fn is_c0(b : u8) -> bool { ret b < 0x20u8 || b >= 0x7fu8; }
+
+fn in_fragment_set(b : u8) -> bool {
+    ret is_c0(b) || b == ' ' as u8 || b == '"' as u8 || b == '<' as u8 ||
+        b == '>' as u8 || b == '`' as u8;
+}
+
+fn in_query_set(b : u8) -> bool {
+    ret is_c0(b) || b == ' ' as u8 || b == '"' as u8 || b == '#' as u8 ||
+        b == '<' as u8 || b == '>' as u8;
+}
+
+fn in_special_query_set(b : u8) -> bool {
+    ret in_query_set(b) || b == '\'' as u8;
+}
+
+fn in_path_set(b : u8) -> bool {
+    ret in_query_set(b) || b == '?' as u8 || b == '`' as u8 ||
+        b == '{' as u8 || b == '}' as u8;
+}
+
+fn in_userinfo_set(b : u8) -> bool {
+    ret in_path_set(b) || b == '/' as u8 || b == ':' as u8 ||
+        b == ';' as u8 || b == '=' as u8 || b == '@' as u8 ||
+        b == '[' as u8 || b == '\\' as u8 || b == ']' as u8 ||
+        b == '^' as u8 || b == '|' as u8;
+}
+
+
+fn encode(s : str, in_set : fn(u8) -> bool) -> str {
+    let hex = str::bytes("0123456789ABCDEF"), out = [];
+    for b in str::bytes(s) {
+        if in_set(b) {
+            out += ['%' as u8, hex[(b >> 4u8) as uint], hex[(b & 15u8) as uint]];
+        } else {
+            out += [b];
+        }
+    }
+    ret str::from_bytes(out);
+}
+
+
+fn hex_digit(b : u8) -> option<u8> {
+    ret if b >= '0' as u8 && b <= '9' as u8 { option::some(b - ('0' as u8)) }
+        else if b >= 'a' as u8 && b <= 'f' as u8 { option::some(b - ('a' as u8) + 10u8) }
+        else if b >= 'A' as u8 && b <= 'F' as u8 { option::some(b - ('A' as u8) + 10u8) }
+        else { option::none };
+}
+
+
+// The bytes `s` percent-decodes to, which need not be UTF-8.
+fn decode(s : str) -> [u8] {
+    let bytes = str::bytes(s), n = vec::len(bytes), out = [], i = 0u;
+    while i < n {
+        if bytes[i] == '%' as u8 && i + 2u < n {
+            alt (hex_digit(bytes[i + 1u]), hex_digit(bytes[i + 2u])) {
+                (option::some(h), option::some(l)) {
+                    out += [h * 16u8 + l];
+                    i += 3u;
+                    cont;
+                }
+                _ { }
+            }
+        }
+        out += [bytes[i]];
+        i += 1u;
+    }
+    ret out;
+}
+
+
+// Whether a path segment is "." or "..", possibly percent-encoded.
+fn is_single_dot(seg : str) -> bool {
+    ret str::eq(seg, ".") || str::eq(str::to_lower(seg), "%2e");
+}
+
+fn is_double_dot(seg : str) -> bool {
+    let s = str::to_lower(seg);
+    ret str::eq(s, "..") || str::eq(s, ".%2e") || str::eq(s, "%2e.") ||
+        str::eq(s, "%2e%2e");
+}
+
+
+/*
+ * Joins path segments into an absolute path, dropping "." and applying
+ * "..". A trailing "." or ".." leaves a trailing slash.
+ */
+fn normalize_path(segments : [str], scheme : str) -> str {
+    let out = [], n = vec::len(segments), i = 0u;
+    for seg in segments {
+        i += 1u;
+        if is_double_dot(seg) {
+            // A Windows drive letter is not removed from file paths.
+            let keep = str::eq(scheme, "file") && vec::len(out) == 1u &&
+                is_drive_letter(out[0]);
+            if vec::len(out) > 0u && !keep {
+                out = vec::slice(out, 0u, vec::len(out) - 1u);
+            }
+            if i == n { out += [""]; }
+        } else if is_single_dot(seg) {
+            if i == n { out += [""]; }
+        } else {
+            out += [seg];
+        }
+    }
+    ret "/" + str::connect(out, "/");
+}
+
+
+fn is_drive_letter(seg : str) -> bool {
+    let b = str::bytes(seg);
+    ret vec::len(b) == 2u && char::is_alphabetic(b[0] as char) &&
+        (b[1] == ':' as u8 || b[1] == '|' as u8);
+}
+
+
+fn split_segments(path : str, special : bool) -> [str] {
+    let p = if special { str::replace(path, "\\", "/") } else { path };
+    ret str::split_char(p, '/');
+}
+
+
+// Splits off "?query" and "#fragment" from the end of `rest`.
+fn split_suffixes(rest : str) -> (str, option<str>, option<str>) {
+    let (before, fragment) = alt str::index(rest, '#' as u8) {
+        -1 { (rest, option::none) }
+        i {
+            (str::slice(rest, 0u, i as uint),
+             option::some(str::slice(rest, i as uint + 1u, str::len(rest))))
+        }
+    };
+    let (path, query) = alt str::index(before, '?' as u8) {
+        -1 { (before, option::none) }
+        i {
+            (str::slice(before, 0u, i as uint),
+             option::some(str::slice(before, i as uint + 1u, str::len(before))))
+        }
+    };
+    ret (path, query, fragment);
+}
+
+
+fn encode_suffixes(scheme : str, query : option<str>, fragment : option<str>)
+        -> (option<str>, option<str>) {
+    let query_set = if is_special(scheme) { in_special_query_set } else { in_query_set };
+    ret (option::map(query) {|q| encode(q, query_set) },
+         option::map(fragment) {|f| encode(f, in_fragment_set) });
+}
+
+
+fn forbidden_host_byte(b : u8) -> bool {
+    ret b == 0u8 || b == '\t' as u8 || b == '\n' as u8 || b == '\r' as u8 ||
+        b == ' ' as u8 || b == '#' as u8 || b == '/' as u8 ||
+        b == ':' as u8 || b == '<' as u8 || b == '>' as u8 ||
+        b == '?' as u8 || b == '@' as u8 || b == '[' as u8 ||
+        b == '\\' as u8 || b == ']' as u8 || b == '^' as u8 ||
+        b == '|' as u8 || b == '%' as u8;
+}
+
+
+fn parse_host(h : str, special : bool) -> result::t<str, str> {
+    if str::starts_with(h, "[") {
+        if !str::ends_with(h, "]") { ret err("invalid IPv6 address: " + h); }
+        for b in str::bytes(str::slice(h, 1u, str::len(h) - 1u)) {
+            if !(b == ':' as u8 || b == '.' as u8 || option::is_some(hex_digit(b))) {
+                ret err("invalid IPv6 address: " + h);
+            }
+        }
+        ret ok(str::to_lower(h));
+    }
+    let host = if special {
+        let bytes = decode(h);
+        if !str::is_utf8(bytes) { ret err("invalid host: " + h); }
+        str::to_lower(str::from_bytes(bytes))
+    } else { h };
+    for b in str::bytes(host) {
+        if forbidden_host_byte(b) && !(b == '%' as u8 && !special) {
+            ret err("invalid host: " + h);
+        }
+    }
+    ret ok(host);
+}
+
+
+type authority = {username: str, password: str, host: str, port: option<uint>};
+
+
+fn parse_authority(a : str, scheme : str) -> result::t<authority, str> {
+    let special = is_special(scheme);
+    let (userinfo, hostport) = alt str::rindex(a, '@' as u8) {
+        -1 { ("", a) }
+        i { (str::slice(a, 0u, i as uint), str::slice(a, i as uint + 1u, str::len(a))) }
+    };
+    let (username, password) = alt str::index(userinfo, ':' as u8) {
+        -1 { (userinfo, "") }
+        i {
+            (str::slice(userinfo, 0u, i as uint),
+             str::slice(userinfo, i as uint + 1u, str::len(userinfo)))
+        }
+    };
+    // A colon inside the brackets of an IPv6 address is not a port.
+    let close = str::rindex(hostport, ']' as u8);
+    let (host, port_str) = alt str::rindex(hostport, ':' as u8) {
+        -1 { (hostport, "") }
+        i {
+            if i < close { (hostport, "") } else {
+                (str::slice(hostport, 0u, i as uint),
+                 str::slice(hostport, i as uint + 1u, str::len(hostport)))
+            }
+        }
+    };
+    let port = if str::is_empty(port_str) {
+        option::none
+    } else {
+        alt uint::from_str(port_str) {
+            option::some(p) {
+                if p > 65535u { ret err("port out of range: " + port_str); }
+                if default_port(scheme) == option::some(p) { option::none } else { option::some(p) }
+            }
+            option::none { ret err("invalid port: " + port_str); }
+        }
+    };
+    if str::is_empty(host) && special && !str::eq(scheme, "file") {
+        ret err("missing host");
+    }
+    let host = alt parse_host(host, special) { ok(h) { h } err(e) { ret err(e); } };
+    // "localhost" is the empty host for file URLs.
+    if str::eq(scheme, "file") && str::eq(host, "localhost") { host = ""; }
+    ret ok({username: encode(username, in_userinfo_set),
+            password: encode(password, in_userinfo_set),
+            host: host, port: port});
+}
+
+
+// The URL at `rest`, which starts with an authority: "host/path?q#f".
+fn parse_with_authority(scheme : str, rest : str) -> result::t<url, str> {
+    let special = is_special(scheme), n = str::len(rest), end = 0u;
+    let bytes = str::bytes(rest);
+    while end < n {
+        let b = bytes[end];
+        if b == '/' as u8 || b == '?' as u8 || b == '#' as u8 ||
+           (special && b == '\\' as u8) {
+            break;
+        }
+        end += 1u;
+    }
+    let a = alt parse_authority(str::slice(rest, 0u, end), scheme) {
+        ok(a) { a }
+        err(e) { ret err(e); }
+    };
+    let (path, query, fragment) = split_suffixes(str::slice(rest, end, n));
+    let (query, fragment) = encode_suffixes(scheme, query, fragment);
+    let path = if str::is_empty(path) && !special {
+        ""
+    } else {
+        // Drop the empty segment before the leading slash.
+        let segs = split_segments(path, special);
+        normalize_path(if vec::len(segs) > 0u { vec::slice(segs, 1u, vec::len(segs)) } else { [] },
+                       scheme)
+    };
+    ret ok({scheme: scheme, username: a.username, password: a.password,
+            host: option::some(a.host), port: a.port,
+            path: encode(path, in_path_set),
+            query: query, fragment: fragment});
+}
+
+
+// Removes the characters the standard strips from input; none if the
+// input is not UTF-8.
+fn clean(input : str) -> option<str> {
+    let bytes = str::bytes(input), n = vec::len(bytes), start = 0u, end = n;
+    while start < n && bytes[start] <= ' ' as u8 { start += 1u; }
+    while end > start && bytes[end - 1u] <= ' ' as u8 { end -= 1u; }
+    let out = [];
+    for b in vec::slice(bytes, start, end) {
+        if b != '\t' as u8 && b != '\n' as u8 && b != '\r' as u8 { out += [b]; }
+    }
+    ret if str::is_utf8(out) { option::some(str::from_bytes(out)) } else { option::none };
+}
+
+
+// The scheme at the start of `s` and what follows its colon, if any.
+fn split_scheme(s : str) -> option<(str, str)> {
+    let bytes = str::bytes(s), n = vec::len(bytes), i = 0u;
+    if n == 0u || !char::is_alphabetic(bytes[0] as char) { ret option::none; }
+    while i < n {
+        let c = bytes[i] as char;
+        if c == ':' {
+            ret option::some((str::to_lower(str::slice(s, 0u, i)),
+                              str::slice(s, i + 1u, n)));
+        }
+        if !(char::is_alphanumeric(c) || c == '+' || c == '-' || c == '.') {
+            ret option::none;
+        }
+        i += 1u;
+    }
+    ret option::none;
+}
+
+
+fn starts_with_slash(s : str, special : bool) -> bool {
+    ret str::starts_with(s, "/") || (special && str::starts_with(s, "\\"));
+}
+
+
+// `rest` resolved against `base`, which has the same scheme.
+fn resolve(base : url, rest : str) -> result::t<url, str> {
+    let special = is_special(base.scheme);
+    let b = str::bytes(rest);
+    if vec::len(b) >= 2u && starts_with_slash(rest, special) &&
+       starts_with_slash(str::slice(rest, 1u, str::len(rest)), special) {
+        ret parse_with_authority(base.scheme, str::slice(rest, 2u, str::len(rest)));
+    }
+    let (path, query, fragment) = split_suffixes(rest);
+    let (query, fragment) = encode_suffixes(base.scheme, query, fragment);
+    if str::is_empty(path) {
+        // A query replaces the base's query and fragment; a fragment,
+        // or nothing, only its fragment.
+        let q = if str::starts_with(rest, "?") { query } else { base.query };
+        ret ok({query: q, fragment: fragment with base});
+    }
+    let segs = if starts_with_slash(path, special) {
+        let all = split_segments(path, special);
+        vec::slice(all, 1u, vec::len(all))
+    } else {
+        // Replace the last segment of the base's path.
+        let base_segs = split_segments(base.path, special);
+        let dir = if vec::len(base_segs) > 1u {
+            vec::slice(base_segs, 1u, vec::len(base_segs) - 1u)
+        } else { [] };
+        dir + split_segments(path, special)
+    };
+    ret ok({path: encode(normalize_path(segs, base.scheme), in_path_set),
+            query: query, fragment: fragment with base});
+}
+
+
+/*
+ * Parses `input`, resolving it against `base` if it is relative. Fails
+ * for relative input without a base and for malformed hosts and ports.
+ */
+fn parse(input : str, base : option<url>) -> result::t<url, str> {
+    let s = alt clean(input) {
+        option::some(s) { s }
+        option::none { ret err("invalid input: not UTF-8"); }
+    };
+    alt split_scheme(s) {
+        option::some((scheme, rest)) {
+            let special = is_special(scheme);
+            alt base {
+                option::some(b) {
+                    // "http:foo" is relative to an http base.
+                    if special && str::eq(b.scheme, scheme) &&
+                       !starts_with_slash(rest, true) {
+                        ret resolve(b, rest);
+                    }
+                }
+                option::none { }
+            }
+            if str::eq(scheme, "file") {
+                if starts_with_slash(rest, true) &&
+                   starts_with_slash(str::slice(rest, 1u, str::len(rest)), true) {
+                    ret parse_with_authority(scheme, str::slice(rest, 2u, str::len(rest)));
+                }
+                // "file:/x" and "file:x" have an empty host.
+                let empty = {scheme: "file", username: "", password: "",
+                             host: option::some(""), port: option::none,
+                             path: "/", query: option::none,
+                             fragment: option::none};
+                ret resolve(empty, if starts_with_slash(rest, true) { rest } else { "/" + rest });
+            }
+            if special {
+                // Any number of slashes may come before the authority.
+                let bytes = str::bytes(rest), i = 0u;
+                while i < vec::len(bytes) &&
+                      (bytes[i] == '/' as u8 || bytes[i] == '\\' as u8) {
+                    i += 1u;
+                }
+                ret parse_with_authority(scheme, str::slice(rest, i, str::len(rest)));
+            }
+            if str::starts_with(rest, "//") {
+                ret parse_with_authority(scheme, str::slice(rest, 2u, str::len(rest)));
+            }
+            let (path, query, fragment) = split_suffixes(rest);
+            let (query, fragment) = encode_suffixes(scheme, query, fragment);
+            let path = if str::starts_with(path, "/") {
+                let segs = split_segments(path, false);
+                encode(normalize_path(vec::slice(segs, 1u, vec::len(segs)), scheme),
+                       in_path_set)
+            } else {
+                // An opaque path, as in "about:blank" or "mailto:x@y".
+                encode(path, is_c0)
+            };
+            ret ok({scheme: scheme, username: "", password: "",
+                    host: option::none, port: option::none, path: path,
+                    query: query, fragment: fragment});
+        }
+        option::none {
+            alt base {
+                option::none { ret err("relative URL without a base: " + input); }
+                option::some(b) {
+                    if option::is_none(b.host) && !str::starts_with(b.path, "/") {
+                        // Only a fragment can be relative to "about:blank".
+                        if !str::starts_with(s, "#") {
+                            ret err("cannot resolve " + input + " against " + to_str(b));
+                        }
+                        let f = encode(str::slice(s, 1u, str::len(s)), in_fragment_set);
+                        ret ok({fragment: option::some(f) with b});
+                    }
+                    ret resolve(b, s);
+                }
+            }
+        }
+    }
+}
+
+
+fn host_and_port(u : url) -> str {
+    let h = alt u.host { option::some(h) { h } option::none { "" } };
+    ret alt u.port {
+        option::some(p) { #fmt("%s:%u", h, p) }
+        option::none { h }
+    };
+}
+
+
+/* The URL as a string, as in the href of a link. */
+fn to_str(u : url) -> str {
+    let out = u.scheme + ":";
+    alt u.host {
+        option::some(_) {
+            out += "//";
+            if !str::is_empty(u.username) || !str::is_empty(u.password) {
+                out += u.username;
+                if !str::is_empty(u.password) { out += ":" + u.password; }
+                out += "@";
+            }
+            out += host_and_port(u);
+        }
+        option::none {
+            // Keep "web+x:/.//p" from reading as having a host.
+            if str::starts_with(u.path, "//") { out += "/."; }
+        }
+    }
+    out += u.path;
+    alt u.query { option::some(q) { out += "?" + q; } option::none { } }
+    alt u.fragment { option::some(f) { out += "#" + f; } option::none { } }
+    ret out;
+}
+
+
+/* "scheme://host:port" for schemes with an origin, else "null". */
+fn origin(u : url) -> str {
+    let has_origin = is_special(u.scheme) && !str::eq(u.scheme, "file");
+    ret if has_origin { u.scheme + "://" + host_and_port(u) } else { "null" };
+}
+
+
+/* The path and query, as sent in an HTTP request line. */
+fn request_target(u : url) -> str {
+    let p = if str::is_empty(u.path) { "/" } else { u.path };
+    ret alt u.query { option::some(q) { p + "?" + q } option::none { p } };
+}
+
+
+/* The parts of the URL, named as in the JavaScript URL class. */
+fn to_json(u : url) -> str {
+    fn q(s : str) -> str { ret json::to_str(json::string(s)); }
+    let hostname = alt u.host { option::some(h) { h } option::none { "" } },
+        port = alt u.port { option::some(p) { uint::str(p) } option::none { "" } },
+        search = alt u.query {
+            option::some(s) { if str::is_empty(s) { "" } else { "?" + s } }
+            option::none { "" }
+        },
+        hash = alt u.fragment {
+            option::some(f) { if str::is_empty(f) { "" } else { "#" + f } }
+            option::none { "" }
+        };
+    let fields = [("href", to_str(u)), ("origin", origin(u)),
+                  ("protocol", u.scheme + ":"), ("username", u.username),
+                  ("password", u.password), ("host", host_and_port(u)),
+                  ("hostname", hostname), ("port", port),
+                  ("pathname", u.path), ("search", search), ("hash", hash)];
+    ret "{" + str::connect(vec::map(fields) {|f|
+        let (k, v) = f;
+        q(k) + ":" + q(v)
+    }, ",") + "}";
+}
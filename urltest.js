@@ -0,0 +1,76 @@
+// Exercises the host's URL parser through new URL(); see "make check-url".
+
+function check(name, ok, detail) {
+    print((ok ? "ok " : "FAIL ") + name + (ok ? "" : ": " + detail));
+}
+
+function href(input, base) {
+    try {
+        return base === undefined ? new URL(input).href : new URL(input, base).href;
+    } catch (e) {
+        return "throws";
+    }
+}
+
+function checkHref(name, input, base, expected) {
+    let got = href(input, base);
+    check(name, got === expected, got);
+}
+
+function checkThrows(name, input, message) {
+    try {
+        new URL(input);
+        check(name, false, "no exception");
+    } catch (e) {
+        check(name, e instanceof TypeError && e.message.indexOf(message) >= 0, e);
+    }
+}
+
+let base = "http://a.test/b/c/d?q#f";
+checkHref("relative path", "../e?x#y", base, "http://a.test/b/e?x#y");
+checkHref("absolute path", "/e", base, "http://a.test/e");
+checkHref("scheme-relative", "//other.test/e", base, "http://other.test/e");
+checkHref("query only", "?z", base, "http://a.test/b/c/d?z");
+checkHref("fragment only", "#g", base, "http://a.test/b/c/d?q#g");
+checkHref("same scheme without slashes", "http:e", base, "http://a.test/b/c/e");
+checkHref("absolute input ignores base", "https://x.test/", base, "https://x.test/");
+checkThrows("relative without a base", "e", "without a base");
+
+checkHref("dot segments", "http://a.test/a/./b/../../c/%2e/d", undefined,
+          "http://a.test/c/d");
+checkHref("trailing ..", "http://a.test/a/b/..", undefined, "http://a.test/a/");
+checkHref("above the root", "http://a.test/../../a", undefined, "http://a.test/a");
+checkHref("backslashes", "http:\\\\a.test\\b\\..\\c", undefined, "http://a.test/c");
+
+let u = new URL("HTTP://User:Pw@A.Test:8080/p?q#h");
+check("parts", [u.protocol, u.username, u.password, u.host, u.hostname, u.port,
+                u.pathname, u.search, u.hash, u.origin].join() ===
+      "http:,User,Pw,a.test:8080,a.test,8080,/p,?q,#h,http://a.test:8080",
+      JSON.stringify(u));
+checkHref("default port dropped", "http://a.test:80/", undefined, "http://a.test/");
+checkHref("https default port", "https://a.test:443/", undefined, "https://a.test/");
+checkThrows("port out of range", "http://a.test:65536/", "port out of range");
+checkThrows("bad port", "http://a.test:8x/", "invalid port");
+
+u = new URL("http://[::1]:8123/");
+check("IPv6 host", u.hostname === "[::1]" && u.port === "8123" &&
+      u.host === "[::1]:8123", u.host);
+checkHref("IPv6 lowercased", "http://[FE80::A]/", undefined, "http://[fe80::a]/");
+checkThrows("bad IPv6", "http://[::g]/", "invalid IPv6 address");
+
+checkHref("file:", "file:///tmp/x.txt", undefined, "file:///tmp/x.txt");
+checkHref("file: localhost", "file://localhost/tmp", undefined, "file:///tmp");
+checkHref("file: without slashes", "file:tmp/x", undefined, "file:///tmp/x");
+checkHref("file: relative", "../y", "file:///tmp/a/x", "file:///tmp/y");
+check("file: origin", new URL("file:///x").origin === "null", new URL("file:///x").origin);
+
+u = new URL("mailto:Someone@Example.test?subject=hi");
+check("opaque path", u.pathname === "Someone@Example.test" && u.host === "" &&
+      u.search === "?subject=hi" && u.origin === "null", JSON.stringify(u));
+checkHref("opaque fragment", "#x", "about:blank", "about:blank#x");
+checkHref("opaque base", "x", "about:blank", "throws");
+
+checkThrows("percent-decoded host not UTF-8", "http://%ff/", "invalid host");
+checkHref("percent-decoded host", "http://%41.test/", undefined, "http://a.test/");
+checkThrows("forbidden host byte", "http://a b/", "invalid host");
+checkHref("non-ASCII path", "http://a.test/\u00e9", undefined, "http://a.test/%C3%A9");
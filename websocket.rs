@@ -0,0 +1,513 @@
+/*
+ * A WebSocket client (RFC 6455) for the host, used by the WebSocket class.
+ *
+ * A connection opens with an HTTP/1.1 Upgrade handshake, over TLS for
+ * wss, carrying the cookies of the actor's HTTP client. Messages are then
+ * sent as single masked frames; those received may be fragmented and are
+ * reported whole. Pings are answered with pongs. Either side may start
+ * the closing handshake, and a connection that breaks or breaks the
+ * protocol ends with code 1006, as a browser reports it.
+ */
+
+use std;
+import std::{ json, time };
+import comm::{ port, chan, send, recv, peek };
+import result::{ ok, err };
+
+export command, send_text, send_binary, close, event, opened, message,
+       failed, closed;
+export request_from_json, run, base64_encode, base64_decode;
+
+#[link_args="-L."]
+#[link_name="spidermonkeyrustext"]
+native mod jsrust {
+    fn JSRust_Sha1(buf : *u8, len : ctypes::size_t, out : *u8);
+    fn JSRust_RandomBytes(buf : *u8, len : ctypes::size_t);
+}
+
+// How often a waiting read checks for commands from the page.
+const POLL_MS : uint = 20u;
+
+// How long to wait for the server's answer to our close frame.
+const CLOSE_WAIT_NS : u64 = 5000000000u64;
+
+// The largest message accepted, in bytes.
+const MAX_MESSAGE : uint = 16777216u;
+
+// Opcodes.
+const CONTINUATION : u8 = 0x0u8;
+const TEXT : u8 = 0x1u8;
+const BINARY : u8 = 0x2u8;
+const CLOSE : u8 = 0x8u8;
+const PING : u8 = 0x9u8;
+const PONG : u8 = 0xau8;
+
+// What the page asks of an open connection.
+enum command {
+    send_text(str),
+    send_binary([u8]),
+    close(uint, str),                   // code (0 for none), reason
+}
+
+enum event {
+    opened(str),                        // the protocol the server chose
+    message(bool, [u8]),                // binary, payload
+    failed(str),
+    closed(uint, str, bool),            // code, reason, clean
+}
+
+
+/*
+ * Reads a connection posted by jsrust_ws_open: a JSON object with url
+ * and protocols (a list of subprotocol names).
+ */
+fn request_from_json(s : str) -> result::t<(url::url, [str]), str> {
+    let m = alt json::from_str(s) {
+        result::ok(json::dict(m)) { m }
+        _ { ret err("malformed request: " + s); }
+    };
+    let u = alt m.find("url") {
+        option::some(json::string(v)) {
+            alt url::parse(v, option::none) { ok(u) { u } err(e) { ret err(e); } }
+        }
+        _ { ret err("malformed request: " + s); }
+    };
+    if !str::eq(u.scheme, "ws") && !str::eq(u.scheme, "wss") {
+        ret err("unsupported scheme: " + u.scheme);
+    }
+    let protocols = [];
+    alt m.find("protocols") {
+        option::some(json::list(l)) {
+            for p in *l {
+                // Each goes into the Sec-WebSocket-Protocol header, once.
+                alt p {
+                    json::string(v) if http::is_token(v) &&
+                        !vec::any(protocols, {|q| str::eq(q, v) }) {
+                        protocols += [v];
+                    }
+                    _ { ret err("invalid subprotocol in request: " + s); }
+                }
+            }
+        }
+        _ { }
+    }
+    ret ok((u, protocols));
+}
+
+
+fn random_bytes(n : uint) -> [u8] unsafe {
+    let buf = vec::init_elt(n, 0u8);
+    jsrust::JSRust_RandomBytes(vec::unsafe::to_ptr(buf), n as ctypes::size_t);
+    ret buf;
+}
+
+
+fn sha1(data : [u8]) -> [u8] unsafe {
+    let out = vec::init_elt(20u, 0u8);
+    jsrust::JSRust_Sha1(vec::unsafe::to_ptr(data), vec::len(data) as ctypes::size_t,
+                        vec::unsafe::to_ptr(out));
+    ret out;
+}
+
+
+fn base64_digit(v : u8) -> u8 {
+    let digits = str::bytes("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/");
+    ret digits[v as uint];
+}
+
+
+fn base64_encode(data : [u8]) -> str {
+    let out = [], i = 0u, n = vec::len(data);
+    while i < n {
+        let b0 = data[i] as uint,
+            b1 = if i + 1u < n { data[i + 1u] as uint } else { 0u },
+            b2 = if i + 2u < n { data[i + 2u] as uint } else { 0u };
+        let v = (b0 << 16u) | (b1 << 8u) | b2;
+        out += [base64_digit((v >> 18u & 63u) as u8), base64_digit((v >> 12u & 63u) as u8)];
+        out += [if i + 1u < n { base64_digit((v >> 6u & 63u) as u8) } else { '=' as u8 }];
+        out += [if i + 2u < n { base64_digit((v & 63u) as u8) } else { '=' as u8 }];
+        i += 3u;
+    }
+    ret str::from_bytes(out);
+}
+
+
+fn base64_decode(s : str) -> result::t<[u8], str> {
+    fn value(c : u8) -> option<uint> {
+        ret if c >= 'A' as u8 && c <= 'Z' as u8 { option::some((c - ('A' as u8)) as uint) }
+            else if c >= 'a' as u8 && c <= 'z' as u8 { option::some((c - ('a' as u8)) as uint + 26u) }
+            else if c >= '0' as u8 && c <= '9' as u8 { option::some((c - ('0' as u8)) as uint + 52u) }
+            else if c == '+' as u8 { option::some(62u) }
+            else if c == '/' as u8 { option::some(63u) }
+            else { option::none };
+    }
+    let out = [], acc = 0u, bits = 0u;
+    for c in str::bytes(s) {
+        if c == '=' as u8 { break; }
+        alt value(c) {
+            option::some(v) {
+                acc = (acc << 6u | v) & 0xffffffu;
+                bits += 6u;
+                if bits >= 8u {
+                    bits -= 8u;
+                    out += [(acc >> bits & 0xffu) as u8];
+                }
+            }
+            option::none { ret err("malformed base64"); }
+        }
+    }
+    ret ok(out);
+}
+
+
+// Sec-WebSocket-Accept for a Sec-WebSocket-Key.
+fn accept_key(key : str) -> str {
+    ret base64_encode(sha1(str::bytes(key + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11")));
+}
+
+
+// A masked frame with FIN set.
+fn encode_frame(opcode : u8, payload : [u8]) -> [u8] {
+    let n = vec::len(payload), out = [0x80u8 | opcode];
+    if n < 126u {
+        out += [0x80u8 | (n as u8)];
+    } else if n < 65536u {
+        out += [0x80u8 | 126u8, (n >> 8u) as u8, n as u8];
+    } else {
+        out += [0x80u8 | 127u8];
+        let i = 8u;
+        while i > 0u {
+            i -= 1u;
+            out += [(n >> (i * 8u)) as u8];
+        }
+    }
+    let mask = random_bytes(4u), i = 0u;
+    out += mask;
+    for b in payload {
+        out += [b ^ mask[i % 4u]];
+        i += 1u;
+    }
+    ret out;
+}
+
+
+// The payload of a close frame; code 0 sends none.
+fn close_payload(code : uint, reason : str) -> [u8] {
+    if code == 0u { ret []; }
+    ret [(code >> 8u) as u8, code as u8] + str::bytes(reason);
+}
+
+
+type frame = {fin: bool, opcode: u8, payload: [u8]};
+
+
+// The frame at the front of `buf` and its length, if it has all arrived.
+fn take_frame(buf : [u8]) -> result::t<option<(frame, uint)>, str> {
+    let n = vec::len(buf);
+    if n < 2u { ret ok(option::none); }
+    let b0 = buf[0], b1 = buf[1];
+    if (b0 & 0x70u8) != 0u8 { ret err("reserved bits set in a frame"); }
+    if (b1 & 0x80u8) != 0u8 { ret err("masked frame from the server"); }
+    let len = (b1 & 0x7fu8) as uint, start = 2u;
+    if len == 126u {
+        if n < 4u { ret ok(option::none); }
+        len = (buf[2] as uint) << 8u | (buf[3] as uint);
+        start = 4u;
+    } else if len == 127u {
+        if n < 10u { ret ok(option::none); }
+        len = 0u;
+        let i = 2u;
+        while i < 10u {
+            len = len << 8u | (buf[i] as uint);
+            i += 1u;
+        }
+        start = 10u;
+    }
+    if len > MAX_MESSAGE { ret err("message too big"); }
+    if n < start + len { ret ok(option::none); }
+    ret ok(option::some(({fin: (b0 & 0x80u8) != 0u8, opcode: b0 & 0x0fu8,
+                          payload: vec::slice(buf, start, start + len)},
+                         start + len)));
+}
+
+
+// The status and headers of the handshake's response, and what came
+// after them, once they have all arrived.
+fn take_head(buf : [u8]) -> option<(uint, [(str, str)], [u8])> {
+    let n = vec::len(buf), i = 3u;
+    while i < n {
+        if buf[i - 3u] == '\r' as u8 && buf[i - 2u] == '\n' as u8 &&
+           buf[i - 1u] == '\r' as u8 && buf[i] == '\n' as u8 {
+            let lines = str::split_char(http::from_utf8_lossy(vec::slice(buf, 0u, i - 3u)), '\n');
+            let status_line = str::splitn_char(str::trim(lines[0]), ' ', 2u);
+            let status = if vec::len(status_line) >= 2u {
+                alt uint::from_str(status_line[1]) { option::some(s) { s } option::none { 0u } }
+            } else { 0u };
+            let headers = [];
+            for l in vec::slice(lines, 1u, vec::len(lines)) {
+                alt str::index(l, ':' as u8) {
+                    -1 { }
+                    j {
+                        headers += [(str::trim(str::slice(l, 0u, j as uint)),
+                                     str::trim(str::slice(l, j as uint + 1u, str::len(l))))];
+                    }
+                }
+            }
+            ret option::some((status, headers, vec::slice(buf, i + 1u, n)));
+        }
+        i += 1u;
+    }
+    ret option::none;
+}
+
+
+fn has_token(headers : [(str, str)], name : str, token : str) -> bool {
+    ret alt http::header(headers, name) {
+        option::some(v) {
+            vec::any(str::split_char(v, ','), {|t|
+                str::eq(str::to_lower(str::trim(t)), token)
+            })
+        }
+        option::none { false }
+    };
+}
+
+
+/*
+ * Connects and makes the opening handshake. Returns the socket, the
+ * protocol the server chose and any frames that followed the handshake.
+ */
+fn open(c : http::client, u : url::url, protocols : [str],
+        cancelled : fn() -> bool) -> result::t<(net::socket, str, [u8]), str> {
+    let host = alt u.host { option::some(h) { h } option::none { "" } },
+        port = url::port_or_default(u),
+        secure = str::eq(u.scheme, "wss");
+    // Cookies are those of the same URL over http or https.
+    let cookie_url = {scheme: if secure { "https" } else { "http" } with u};
+    let connected = if secure {
+        net::connect_tls(host, port, c.tls)
+    } else {
+        net::connect(host, port)
+    };
+    let sock = alt connected { ok(s) { s } err(e) { ret err(e); } };
+    let key = base64_encode(random_bytes(16u));
+    let req = #fmt("GET %s HTTP/1.1\r\nHost: %s\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: %s\r\nSec-WebSocket-Version: 13\r\n",
+                   url::request_target(u),
+                   alt u.port { option::some(p) { #fmt("%s:%u", host, p) } option::none { host } },
+                   key);
+    if vec::len(protocols) > 0u {
+        req += #fmt("Sec-WebSocket-Protocol: %s\r\n", str::connect(protocols, ", "));
+    }
+    let cookies = cookie::cookie_header(c.cookies, cookie_url);
+    if !str::is_empty(cookies) {
+        req += #fmt("Cookie: %s\r\n", cookies);
+    }
+    alt net::write(sock, str::bytes(req + "\r\n")) {
+        ok(_) { }
+        err(e) { net::close(sock); ret err(e); }
+    }
+
+    let buf = [];
+    while true {
+        alt take_head(buf) {
+            option::some((status, headers, rest)) {
+                for h in headers {
+                    let (k, v) = h;
+                    if str::eq(str::to_lower(k), "set-cookie") {
+                        cookie::store(c.cookies, cookie_url, v);
+                    }
+                }
+                let protocol = alt http::header(headers, "sec-websocket-protocol") {
+                    option::some(p) { p }
+                    option::none { "" }
+                };
+                let problem = if status != 101u {
+                    #fmt("unexpected response status %u", status)
+                } else if !has_token(headers, "upgrade", "websocket") ||
+                          !has_token(headers, "connection", "upgrade") {
+                    "missing Upgrade or Connection header"
+                } else if http::header(headers, "sec-websocket-accept") !=
+                          option::some(accept_key(key)) {
+                    "wrong Sec-WebSocket-Accept"
+                } else if !str::is_empty(protocol) &&
+                          !vec::any(protocols, {|p| str::eq(p, protocol) }) {
+                    "server chose a protocol that was not offered: " + protocol
+                } else { "" };
+                if !str::is_empty(problem) {
+                    net::close(sock);
+                    ret err(problem);
+                }
+                ret ok((sock, protocol, rest));
+            }
+            option::none { }
+        }
+        alt net::read(sock, 4096u, POLL_MS) {
+            ok(option::some(d)) {
+                if vec::len(d) == 0u {
+                    net::close(sock);
+                    ret err("connection closed during the handshake");
+                }
+                buf += d;
+            }
+            ok(option::none) {
+                if cancelled() { net::close(sock); ret err("aborted"); }
+            }
+            err(e) { net::close(sock); ret err(e); }
+        }
+    }
+    fail;
+}
+
+
+/*
+ * Exchanges frames until the connection closes, sending what `commands`
+ * asks. Returns the code, reason and cleanness of the close.
+ */
+fn talk(sock : net::socket, rest : [u8], commands : port<command>,
+        sink : fn(event)) -> (uint, str, bool) {
+    let buf = rest, fragments = [], fragment_op = CONTINUATION,
+        closing = false, close_sent_at = 0u64;
+
+    // Gives up on the connection, telling the server why if it can.
+    fn fail_with(sock : net::socket, code : uint, why : str,
+                 sink : fn(event)) -> (uint, str, bool) {
+        let _ = net::write(sock, encode_frame(CLOSE, close_payload(code, "")));
+        sink(failed(why));
+        ret (1006u, "", false);
+    }
+
+    while true {
+        while peek(commands) {
+            let frame = alt recv(commands) {
+                send_text(s) { option::some(encode_frame(TEXT, str::bytes(s))) }
+                send_binary(b) { option::some(encode_frame(BINARY, b)) }
+                close(code, reason) {
+                    if closing { option::none } else {
+                        closing = true;
+                        close_sent_at = time::precise_time_ns();
+                        option::some(encode_frame(CLOSE, close_payload(code, reason)))
+                    }
+                }
+            };
+            alt frame {
+                option::some(f) {
+                    // Nothing more is sent after a close frame.
+                    if closing && (f[0] & 0x0fu8) != CLOSE { cont; }
+                    alt net::write(sock, f) {
+                        ok(_) { }
+                        err(e) { sink(failed(e)); ret (1006u, "", false); }
+                    }
+                }
+                option::none { }
+            }
+        }
+        if closing && time::precise_time_ns() - close_sent_at > CLOSE_WAIT_NS {
+            ret (1006u, "", false);
+        }
+
+        while true {
+            let (f, used) = alt take_frame(buf) {
+                ok(option::some(fu)) { fu }
+                ok(option::none) { break; }
+                err(e) { ret fail_with(sock, 1002u, e, sink); }
+            };
+            buf = vec::slice(buf, used, vec::len(buf));
+            if f.opcode >= CLOSE && (!f.fin || vec::len(f.payload) > 125u) {
+                ret fail_with(sock, 1002u, "malformed control frame", sink);
+            }
+            if f.opcode == PING {
+                if !closing {
+                    alt net::write(sock, encode_frame(PONG, f.payload)) {
+                        ok(_) { }
+                        err(e) { sink(failed(e)); ret (1006u, "", false); }
+                    }
+                }
+            } else if f.opcode == PONG {
+                // Unsolicited pongs are allowed and ignored.
+            } else if f.opcode == CLOSE {
+                let n = vec::len(f.payload);
+                if n == 1u { ret fail_with(sock, 1002u, "malformed close frame", sink); }
+                let code = if n >= 2u {
+                    (f.payload[0] as uint) << 8u | (f.payload[1] as uint)
+                } else { 1005u };
+                let reason = vec::slice(f.payload, if n >= 2u { 2u } else { 0u }, n);
+                if !str::is_utf8(reason) {
+                    ret fail_with(sock, 1007u, "close reason is not UTF-8", sink);
+                }
+                if !closing {
+                    // Answer with the same code.
+                    let _ = net::write(sock, encode_frame(CLOSE, vec::slice(f.payload, 0u, if n >= 2u { 2u } else { 0u })));
+                }
+                ret (code, str::from_bytes(reason), true);
+            } else if f.opcode == TEXT || f.opcode == BINARY {
+                if fragment_op != CONTINUATION {
+                    ret fail_with(sock, 1002u, "new message inside a fragmented one", sink);
+                }
+                if f.fin {
+                    if f.opcode == TEXT && !str::is_utf8(f.payload) {
+                        ret fail_with(sock, 1007u, "text message is not UTF-8", sink);
+                    }
+                    if !closing { sink(message(f.opcode == BINARY, f.payload)); }
+                } else {
+                    fragment_op = f.opcode;
+                    fragments = f.payload;
+                }
+            } else if f.opcode == CONTINUATION {
+                if fragment_op == CONTINUATION {
+                    ret fail_with(sock, 1002u, "continuation without a message", sink);
+                }
+                fragments += f.payload;
+                if vec::len(fragments) > MAX_MESSAGE {
+                    ret fail_with(sock, 1009u, "message too big", sink);
+                }
+                if f.fin {
+                    if fragment_op == TEXT && !str::is_utf8(fragments) {
+                        ret fail_with(sock, 1007u, "text message is not UTF-8", sink);
+                    }
+                    if !closing { sink(message(fragment_op == BINARY, fragments)); }
+                    fragment_op = CONTINUATION;
+                    fragments = [];
+                }
+            } else {
+                ret fail_with(sock, 1002u, #fmt("unknown opcode %u", f.opcode as uint), sink);
+            }
+        }
+
+        alt net::read(sock, 32768u, POLL_MS) {
+            ok(option::some(d)) {
+                if vec::len(d) == 0u {
+                    if !closing { sink(failed("connection closed without a close frame")); }
+                    ret (1006u, "", false);
+                }
+                buf += d;
+            }
+            ok(option::none) { }
+            err(e) { sink(failed(e)); ret (1006u, "", false); }
+        }
+    }
+    fail;
+}
+
+
+/*
+ * Opens a connection to `u` and runs it until it closes, taking what to
+ * send from `commands` and reporting to `sink`: opened, then messages,
+ * then closed, with failed before closed if the connection failed. A
+ * close command before the connection opens fails it. Blocks on the
+ * network, so run it on a thread of its own.
+ */
+fn run(c : http::client, u : url::url, protocols : [str],
+       commands : port<command>, sink : fn(event)) {
+    alt open(c, u, protocols, {|| peek(commands) }) {
+        ok((sock, protocol, rest)) {
+            sink(opened(protocol));
+            let (code, reason, clean) = talk(sock, rest, commands, sink);
+            net::close(sock);
+            sink(closed(code, reason, clean));
+        }
+        err(e) {
+            sink(failed(e));
+            sink(closed(1006u, "", false));
+        }
+    }
+}
@@ -3,36 +3,184 @@ var XMLHttpRequest = (function(global) {
 
 var _xhrs = {};
 
-function urlparse(url) {
-    let result = {};
-    let netloc = '';
-    let query = '';
-    let fragment = '';
-    let i = url.indexOf(':')
-    if (i > 0) {
-        result.scheme = url.substring(0, i);
-        url = url.substring(i + 1);
-        i = url.indexOf('/', 2);
-        if (i > 0) {
-            result.netloc = url.substring(2, i);
-            url = url.substring(i);
+// Parsed by the host, following the URL Standard. Relative URLs need a
+// base, which is either a URL or a string.
+function URL(url, base) {
+    let parsed = base === undefined ?
+        jsrust_parse_url(String(url)) :
+        jsrust_parse_url(String(url), String(base));
+    parsed = JSON.parse(parsed);
+    if (parsed.error) {
+        throw new TypeError("Invalid URL: " + parsed.error);
+    }
+    for (let k in parsed) {
+        this[k] = parsed[k];
+    }
+}
+URL.prototype.toString = function toString() { return this.href; };
+URL.prototype.toJSON = function toJSON() { return this.href; };
+global.URL = URL;
+
+// What relative URLs resolve against: the document's URL, if there is one.
+function documentBase() {
+    if (typeof window === "undefined" || !window.location) return undefined;
+    let href = String(window.location.href);
+    return href === "about:blank" ? undefined : href;
+}
+
+// Methods and header names are HTTP tokens, and no header value may
+// hold a CR, LF or NUL: they are sent as they are.
+var TOKEN = /^[!#$%&'*+\-.^_`|~0-9A-Za-z]+$/;
+
+function checkMethod(method) {
+    if (!TOKEN.test(method)) {
+        throw new SyntaxError("Invalid method: " + JSON.stringify(method));
+    }
+}
+
+function checkHeader(name, value) {
+    if (!TOKEN.test(name)) {
+        throw new SyntaxError("Invalid header name: " + JSON.stringify(name));
+    }
+    if (/[\r\n\0]/.test(value)) {
+        throw new SyntaxError("Invalid value for header " + name);
+    }
+}
+
+// Promise reactions are jobs in the host's queue: they run, in order,
+// once the current script or callback has returned and before the next
+// timer or I/O callback.
+global.queueMicrotask = function queueMicrotask(callback) {
+    jsrust_enqueue_job(callback);
+};
+
+// A Promise, as in ES2015, for engines without one.
+function Promise(executor) {
+    this._state = "pending";
+    this._value = undefined;
+    this._reactions = [];
+    let self = this, done = false;
+    function resolve(v) {
+        if (done) return;
+        done = true;
+        resolvePromise(self, v);
+    }
+    function reject(e) {
+        if (done) return;
+        done = true;
+        settlePromise(self, "rejected", e);
+    }
+    try {
+        executor(resolve, reject);
+    } catch (e) {
+        reject(e);
+    }
+}
+
+// Follows thenables, as the resolve function of a promise does.
+function resolvePromise(p, v) {
+    if (v === p) {
+        settlePromise(p, "rejected", new TypeError("A promise cannot resolve to itself"));
+        return;
+    }
+    if (v && (typeof v === "object" || typeof v === "function")) {
+        let then;
+        try {
+            then = v.then;
+        } catch (e) {
+            settlePromise(p, "rejected", e);
+            return;
+        }
+        if (typeof then === "function") {
+            jsrust_enqueue_job(function() {
+                let called = false;
+                try {
+                    then.call(v, function(w) {
+                        if (!called) { called = true; resolvePromise(p, w); }
+                    }, function(e) {
+                        if (!called) { called = true; settlePromise(p, "rejected", e); }
+                    });
+                } catch (e) {
+                    if (!called) { called = true; settlePromise(p, "rejected", e); }
+                }
+            });
+            return;
         }
-    } else {
-        result.scheme = "TODO use relative"
-        result.netloc = "TODO use relative"
     }
-    i = url.indexOf('#');
-    if (i > 0) {
-        result.fragment = url.substring(i + 1)
-        url = url.substring(0, i);
+    settlePromise(p, "fulfilled", v);
+}
+
+function settlePromise(p, state, value) {
+    let reactions = p._reactions;
+    p._state = state;
+    p._value = value;
+    p._reactions = null;
+    for (let i = 0; i < reactions.length; i++) {
+        react(p, reactions[i]);
     }
-    i = url.indexOf('?');
-    if (i > 0) {
-        result.query = url.substring(i + 1);
-        url = url.substring(0, i);
+}
+
+// Queues the job that passes a settled promise's value to a reaction.
+function react(p, r) {
+    jsrust_enqueue_job(function() {
+        let fulfilled = p._state === "fulfilled";
+        let handler = fulfilled ? r.onFulfilled : r.onRejected;
+        if (typeof handler !== "function") {
+            if (fulfilled) {
+                resolvePromise(r.promise, p._value);
+            } else {
+                settlePromise(r.promise, "rejected", p._value);
+            }
+            return;
+        }
+        let v;
+        try {
+            v = handler(p._value);
+        } catch (e) {
+            settlePromise(r.promise, "rejected", e);
+            return;
+        }
+        resolvePromise(r.promise, v);
+    });
+}
+
+Promise.prototype.then = function then(onFulfilled, onRejected) {
+    let r = {
+        onFulfilled: onFulfilled,
+        onRejected: onRejected,
+        promise: new Promise(function() {})
+    };
+    if (this._state === "pending") {
+        this._reactions.push(r);
+    } else {
+        react(this, r);
     }
-    result.url = url;
-    return result;
+    return r.promise;
+};
+Promise.prototype["catch"] = function(onRejected) {
+    return this.then(undefined, onRejected);
+};
+Promise.resolve = function(v) {
+    if (v instanceof Promise) return v;
+    return new Promise(function(resolve) { resolve(v); });
+};
+Promise.reject = function(e) {
+    return new Promise(function(resolve, reject) { reject(e); });
+};
+Promise.all = function(values) {
+    return new Promise(function(resolve, reject) {
+        let results = [], left = values.length;
+        if (left === 0) resolve(results);
+        values.forEach(function(v, i) {
+            Promise.resolve(v).then(function(r) {
+                results[i] = r;
+                if (--left === 0) resolve(results);
+            }, reject);
+        });
+    });
+};
+if (typeof global.Promise !== "function") {
+    global.Promise = Promise;
 }
 
 var CONN = 0;
@@ -44,7 +192,18 @@ var ERR = 5;
 var SPAWN = 6;
 var CAST = 7;
 var TIME = 8;
-var URL = 9;
+var NAVIGATE = 9;
+var FILE_READ = 11;
+var FILE_WRITE = 12;
+var FILE_ERROR = 13;
+var HTTP_HEADERS = 16;
+var HTTP_DATA = 17;
+var HTTP_DONE = 18;
+var HTTP_ERROR = 19;
+var WS_OPENED = 25;
+var WS_MESSAGE = 26;
+var WS_CLOSED = 27;
+var WS_ERROR = 28;
 
 function XMLHttpRequest() {
     this.readyState = 0;
@@ -52,196 +211,579 @@ function XMLHttpRequest() {
     this.statusText = "";
     this._response = "";
     this.responseText = "";
+    this.responseURL = "";
     this.responseXML = null;
     this._headers = [];
     this._responseHeaders = [];
+    this._loaded = 0;
+    this._total = 0;
+    this._timer = 0;
 }
 XMLHttpRequest.requests_outstanding = 0;
+
+// Calls the on<type> handler, if any, with a progress event.
+function fire(xhr, type) {
+    let handler = xhr["on" + type];
+    if (typeof handler !== "function") return;
+    handler.call(xhr, {
+        type: type,
+        target: xhr,
+        loaded: xhr._loaded,
+        total: xhr._total,
+        lengthComputable: xhr._total > 0
+    });
+}
+
+// Ends a request that is still waiting on the host.
+function settle(xhr) {
+    if (xhr._timer) {
+        jsrust_clear_timeout(xhr._timer);
+        delete xhr_timers[xhr._timer];
+        xhr._timer = 0;
+    }
+    delete _xhrs[xhr._id];
+    XMLHttpRequest.requests_outstanding--;
+    xhr.readyState = XMLHttpRequest.prototype.DONE;
+}
+
+// Ends a request without a response: type is "error", "abort" or
+// "timeout".
+function fail(xhr, type) {
+    if (type !== "error") {
+        jsrust_http_abort(xhr._id);
+    }
+    settle(xhr);
+    xhr.status = 0;
+    xhr.statusText = "";
+    xhr.responseText = "";
+    xhr._responseHeaders = [];
+    xhr.onreadystatechange();
+    fire(xhr, type);
+    fire(xhr, "loadend");
+}
+
+function setHead(xhr, head) {
+    xhr.status = head.status;
+    xhr.statusText = head.statusText;
+    xhr.responseURL = head.url;
+    xhr._responseHeaders = head.headers;
+    xhr._total = parseInt(xhr.getResponseHeader("Content-Length")) || 0;
+}
+
+// The script waits for the whole response, or until the timeout has
+// passed. Only the DONE readystatechange, load and loadend are fired, and
+// a failed request throws; one that timed out fires timeout instead of
+// load.
+function sendSync(xhr, request) {
+    let result = jsrust_http_request_sync(request);
+    xhr.readyState = XMLHttpRequest.prototype.DONE;
+    if (result[2]) {
+        let timedOut = result[2] === "timeout";
+        xhr.onreadystatechange();
+        fire(xhr, timedOut ? "timeout" : "error");
+        fire(xhr, "loadend");
+        throw new Error((timedOut ? "TimeoutError: " : "NetworkError: ") + result[2]);
+    }
+    setHead(xhr, JSON.parse(result[0]));
+    xhr.responseText = result[1];
+    xhr._loaded = xhr._total;
+    xhr.onreadystatechange();
+    fire(xhr, "load");
+    fire(xhr, "loadend");
+}
+
 XMLHttpRequest.prototype = {
     UNSENT: 0,
     OPENED: 1,
     HEADERS_RECEIVED: 2,
     LOADING: 3,
     DONE: 4,
-    onload: function() {},
-    onreadystatechange: function() {
-        if (this.readyState === 4) {
-            this.onload();
-        }
-    },
+    // Milliseconds to wait for the whole response; 0 waits forever.
+    timeout: 0,
+    onreadystatechange: function() {},
+    onprogress: null,
+    onload: null,
+    onerror: null,
+    onabort: null,
+    ontimeout: null,
+    onloadend: null,
     open: function open(method, url, async, user, pw) {
-        let parts = urlparse(url);
-        let host = parts.netloc;
-        let port = 0;
-        if (parts.scheme === 'http') {
-            port = 80;
-        } else if (parts.scheme === 'https') {
-            port = 443;
-        } else if (parts.scheme === 'file') {
+        method = String(method);
+        checkMethod(method);
+        let parsed = new URL(url, documentBase());
+        if (parsed.protocol === 'file:') {
             this.status = 0;
             this.statusText = "";
-            // Hack: Work around dom.js giving us file:/foo/bar urls
-            this.responseText = jsrust_read(url.slice(5));
+            this.responseURL = parsed.href;
+            // The host maps the path onto the actor's filesystem policy.
+            this.responseText = jsrust_read(decodeURIComponent(parsed.pathname));
             this.readyState = XMLHttpRequest.prototype.DONE;
             this.onreadystatechange();
+            fire(this, "load");
+            fire(this, "loadend");
             return;
-        } else {
-            throw new Error("Unsupported scheme: " + parts.scheme);
+        } else if (parsed.protocol !== 'http:' && parsed.protocol !== 'https:') {
+            throw new Error("Unsupported scheme: " + parsed.protocol);
         }
 
-        let i = host.indexOf(':');
-        if (i > 0) {
-            port = parseInt(host.substring(i + 1));
-            host = host.substring(0, i);
-        }
-        this._fd = jsrust_connect(host); // TODO support port
-        XMLHttpRequest.requests_outstanding++;
-        this._host = host;
         this._method = method;
-        this._url = parts.url;
+        this._url = parsed.href;
+        this._async = async !== false;
         this._user = user;
         this._pw = pw;
+        this._headers = [];
         this.readyState = XMLHttpRequest.prototype.OPENED;
-        _xhrs[this._fd] = this;
+        this.onreadystatechange();
     },
     setRequestHeader: function setRequestHeader(header, value) {
+        header = String(header);
+        value = String(value);
+        checkHeader(header, value);
         this._headers.push([header, value]);
     },
     send: function send(data) {
-        this._request = this._method + ' ' + this._url + ' HTTP/1.0\r\n';
-        //this._request += 'Host: ' + this._host + '\r\n';
-        if (data) {
-            this._request += 'Content-Length: ' + data.length + '\r\n';
-        }
-        for (let i = 0; i < this._headers.length; i++) {
-            let key = this._headers[i][0];
-            let val = this._headers[i][1];
-            this._request += key + ': ' + val + '\r\n';
-        }
-        this._request += '\r\n';
-        if (data) {
-            this._request += data;
-        }
-        if (this.connected) {
-            jsrust_send(this._fd, this._request);
-            jsrust_recv(this._fd, 32768);
-            this._request = "";
-        } else {
-            //jsrust_send(this._fd, "");
+        if (this.readyState !== XMLHttpRequest.prototype.OPENED) {
+            // file: URLs are already done
+            return;
+        }
+        // The host's HTTP client adds Host and Content-Length, keeps
+        // connections alive and follows redirects.
+        let request = JSON.stringify({
+            method: this._method,
+            url: this._url,
+            headers: this._headers,
+            body: data ? String(data) : "",
+            // Only a synchronous request leaves the timeout to the host.
+            timeout: this._async ? 0 : this.timeout
+        });
+        this.responseText = "";
+        this._loaded = 0;
+        this._total = 0;
+        if (!this._async) {
+            sendSync(this, request);
+            return;
+        }
+        this._id = jsrust_http_request(request);
+        _xhrs[this._id] = this;
+        XMLHttpRequest.requests_outstanding++;
+
+        if (this.timeout > 0) {
+            // Unlike setTimeout, this does not keep the actor alive once
+            // the request is over; settling the request cancels it.
+            this._timer = jsrust_timeout(this.timeout);
+            xhr_timers[this._timer] = [this, this._id];
         }
     },
     abort: function abort() {
-        // TODO ???
+        if (_xhrs[this._id] === this) {
+            fail(this, "abort");
+        }
+        if (this.readyState === XMLHttpRequest.prototype.DONE) {
+            this.readyState = XMLHttpRequest.prototype.UNSENT;
+        }
     },
     getResponseHeader: function getResponseHeader(header) {
-        for (var i = 0; i < this._responseHeaders.length; i++) {
-            if (header === this._responseHeaders[i][0]) {
-                return this._responseHeaders[i][1];
+        let name = String(header).toLowerCase();
+        let values = [];
+        for (let i = 0; i < this._responseHeaders.length; i++) {
+            if (this._responseHeaders[i][0].toLowerCase() === name) {
+                values.push(this._responseHeaders[i][1]);
             }
         }
+        return values.length ? values.join(", ") : null;
     },
     getAllResponseHeaders: function getAllResponseHeaders() {
-        return this._responseHeaders;
+        let all = "";
+        for (let i = 0; i < this._responseHeaders.length; i++) {
+            all += this._responseHeaders[i][0] + ": " +
+                this._responseHeaders[i][1] + "\r\n";
+        }
+        return all;
     }
 }
 
-global._resume = function _resume(what, data, req_id) {
+global._resume = function _resume(what, data, req_id, bytes) {
     //print("Handling request. Total:", XMLHttpRequest.requests_outstanding);
     //print("_resume", what, data, req_id);
     var xhr = _xhrs[req_id] || null;
-    if (what === CONN) {
-        xhr.readyState = XMLHttpRequest.prototype.OPENED;
-        xhr.onreadystatechange.apply(xhr);
-        if (xhr._request.length) {
-            jsrust_send(xhr._fd, xhr._request);
-        }
-        this.connected = true;
-    } else if (what === SEND) {
-        jsrust_recv(xhr._fd, 32768);
-        // TODO need to get number of bytes actually written back into js
-        // to support writing large requests in chunks
-        //xhr._request = ""; //xhr._request.substring(data[1]);
-    } else if (what === RECV) {
-        xhr._response += data;
-//                xhr.responseText += data[1];
-        if (!xhr.statusText) {
-            let newline = "\r\n";
-            let i = xhr._response.indexOf(newline + newline);
-            if (i === -1) {
-                newline = "\n";
-                i = xhr._response.indexOf(newline + newline);
-            }
-            xhr._bodyIndex = i + 4;
-            if (i > 0) {
-                let j = xhr._response.indexOf(newline);
-                let parts = xhr._response.substring(0, j).split(' ');
-                xhr.status = parseInt(parts[1]);
-                for (let q = 1; q < parts.length; q++) {
-                    xhr.statusText += parts[q] + " ";
-                }
-                xhr.statusText = xhr.statusText.substring(0, xhr.statusText.length - 1);
-                let headers = xhr._response.substring(j + 2, i);
-                while (headers) {
-                    let k = headers.indexOf(newline);
-                    let header = "";
-                    if (k > 0) {
-                        header = headers.substring(0, k);
-                        headers = headers.substring(k + 2);
-                    } else {
-                        header = headers;
-                        headers = "";
-                    }
-                    let l = header.indexOf(": ");
-                    let key = header.substring(0, l);
-                    let val = header.substring(l + 2);
-                    if (key.toLowerCase() === "content-length") {
-                        xhr._contentLength = parseInt(val);
-                    }
-                    xhr._responseHeaders.push([key, val]);
-                }
-                xhr.readyState = XMLHttpRequest.prototype.HEADERS_RECEIVED;
-                xhr.onreadystatechange.apply(xhr);
-            }
-        }
-        if (xhr._bodyIndex + xhr._contentLength === xhr._response.length) {
-            xhr.responseText = xhr._response.substring(xhr._bodyIndex);
-            xhr.readyState = XMLHttpRequest.prototype.DONE;
-            xhr.onreadystatechange.apply(xhr);
-            delete _xhrs[xhr._id];
-            //jsrust_close(xhr._id);
-        } else {
-            xhr.readyState = XMLHttpRequest.prototype.LOADING;
-            xhr.onreadystatechange.apply(xhr);
+    var pending = _fetches[req_id] || null;
+    if (what >= HTTP_HEADERS && what <= HTTP_ERROR && !xhr && !pending) {
+        // The request was aborted or timed out while this was on its way.
+        return;
+    }
+    if (pending && what >= HTTP_HEADERS && what <= HTTP_ERROR) {
+        fetchEvent(req_id, pending, what, data);
+    } else if (what >= WS_OPENED && what <= WS_ERROR) {
+        socketEvent(req_id, what, data, bytes);
+    } else if (what === HTTP_HEADERS) {
+        // The host has parsed the head of the final response.
+        setHead(xhr, JSON.parse(data));
+        xhr.readyState = XMLHttpRequest.prototype.HEADERS_RECEIVED;
+        xhr.onreadystatechange();
+    } else if (what === HTTP_DATA) {
+        // bytes is the size of the piece before decoding.
+        xhr.responseText += data;
+        xhr._loaded += bytes;
+        xhr.readyState = XMLHttpRequest.prototype.LOADING;
+        xhr.onreadystatechange();
+        fire(xhr, "progress");
+    } else if (what === HTTP_DONE) {
+        // A sequence cut off by the end of the body, as U+FFFD.
+        xhr.responseText += data;
+        settle(xhr);
+        xhr.onreadystatechange();
+        fire(xhr, "load");
+        fire(xhr, "loadend");
+    } else if (what === HTTP_ERROR) {
+        fail(xhr, "error");
+    } else if (what === TIME && xhr_timers[req_id]) {
+        let timer = xhr_timers[req_id];
+        delete xhr_timers[req_id];
+        if (_xhrs[timer[1]] !== timer[0]) {
+            // The request is already over.
+            return;
         }
-    } else if (what === TIME) {
-        timeouts[req_id][0].apply(global, timeouts[req_id][1]);
-        timeouts[req_id] = undefined;
+        fail(timer[0], "timeout");
+    } else if (what === TIME && timeouts[req_id]) {
+        let timeout = timeouts[req_id];
+        delete timeouts[req_id];
         // piggyback on this
         XMLHttpRequest.requests_outstanding--;
-    } else if (what === URL) {
-        window.location = data;
-    } else if (what === CLOSE) {
-        this._fd = undefined;
+        timeout[0].apply(global, timeout[1]);
+    } else if (what === FILE_READ || what === FILE_WRITE ||
+               what === FILE_ERROR) {
+        let callback = files[req_id];
+        delete files[req_id];
         XMLHttpRequest.requests_outstanding--;
+        if (what === FILE_ERROR) {
+            callback(new Error(data));
+        } else {
+            callback(null, data);
+        }
+    } else if (what === NAVIGATE) {
+        window.location = data;
     }
-    if (XMLHttpRequest.requests_outstanding === 0) {
-        postMessage(9, "exitproc");
-    }
+    // The host checks whether the page is done once this callback's jobs
+    // have run.
+    global._resumed = true;
 }
+global._resumed = false;
 
 var timeouts = {};
 
+// XMLHttpRequest timeouts: [xhr, request id] by timer id. A timer that
+// goes off just as its request settles is in neither table, and ignored.
+var xhr_timers = {};
+
 global.setTimeout = function(func, time) {
     var args = [];
     for (var i = 0; i < arguments.length; i++) {
         args.push(arguments[i]);
     }
     var timeoutnum = jsrust_timeout(time);
-    timeouts[timeoutnum] = [func, args];
+    timeouts[timeoutnum] = [func, args.slice(2)];
     // piggyback on this
     XMLHttpRequest.requests_outstanding++;
 }
 
+var files = {};
+
+// Read and write files through the host, which applies the actor's
+// filesystem policy. The callback receives (error, data).
+global.readFile = function(path, callback) {
+    files[jsrust_read_async(path)] = callback;
+    XMLHttpRequest.requests_outstanding++;
+}
+
+global.writeFile = function(path, data, callback) {
+    files[jsrust_write_async(path, data)] = callback || function() {};
+    XMLHttpRequest.requests_outstanding++;
+}
+
+// The headers of a fetch() request or response. Names are matched
+// without regard to case.
+function Headers(init) {
+    this._list = [];
+    if (init instanceof Headers) {
+        init = init._list;
+    }
+    if (Array.isArray(init)) {
+        for (let i = 0; i < init.length; i++) {
+            this.append(init[i][0], init[i][1]);
+        }
+    } else if (init) {
+        for (let k in init) {
+            this.append(k, init[k]);
+        }
+    }
+}
+Headers.prototype = {
+    append: function append(name, value) {
+        this._list.push([String(name), String(value)]);
+    },
+    set: function set(name, value) {
+        this["delete"](name);
+        this.append(name, value);
+    },
+    get: function get(name) {
+        return XMLHttpRequest.prototype.getResponseHeader.call(
+            {_responseHeaders: this._list}, name);
+    },
+    has: function has(name) {
+        return this.get(name) !== null;
+    },
+    "delete": function(name) {
+        let lower = String(name).toLowerCase();
+        this._list = this._list.filter(function(h) {
+            return h[0].toLowerCase() !== lower;
+        });
+    },
+    forEach: function forEach(callback, thisArg) {
+        for (let i = 0; i < this._list.length; i++) {
+            callback.call(thisArg, this._list[i][1], this._list[i][0], this);
+        }
+    }
+};
+global.Headers = Headers;
+
+// What fetch() resolves to. The body arrives later; text() and json()
+// wait for all of it.
+function Response(head, body) {
+    this.type = "basic";
+    this.url = head.url;
+    this.status = head.status;
+    this.statusText = head.statusText;
+    this.ok = head.status >= 200 && head.status < 300;
+    this.headers = new Headers(head.headers);
+    this.bodyUsed = false;
+    this._body = body;
+}
+Response.prototype = {
+    text: function text() {
+        if (this.bodyUsed) {
+            return Promise.reject(new TypeError("Body has already been read"));
+        }
+        this.bodyUsed = true;
+        return this._body;
+    },
+    json: function json() {
+        return this.text().then(JSON.parse);
+    }
+};
+global.Response = Response;
+
+// fetch() requests waiting on the host, by request id.
+var _fetches = {};
+
+function fetchEvent(id, pending, what, data) {
+    if (what === HTTP_HEADERS) {
+        let head = JSON.parse(data);
+        pending.body = new Promise(function(resolve, reject) {
+            pending.done = resolve;
+            pending.fail = reject;
+        });
+        pending.resolve(new Response(head, pending.body));
+        return;
+    }
+    if (what === HTTP_DATA) {
+        pending.text += data;
+        return;
+    }
+    delete _fetches[id];
+    XMLHttpRequest.requests_outstanding--;
+    if (what === HTTP_DONE) {
+        pending.done(pending.text + data);
+    } else {
+        // Before the head, fetch() itself fails; after it, the body does.
+        (pending.fail || pending.reject)(new TypeError("NetworkError: " + data));
+    }
+}
+
+// Requests a URL through the host's HTTP client, or reads a file: URL
+// through the filesystem policy. Resolves to a Response once the head
+// has arrived; a network error rejects with a TypeError.
+global.fetch = function fetch(input, init) {
+    init = init || {};
+    return new Promise(function(resolve, reject) {
+        let url = new URL(input, documentBase());
+        if (url.protocol === "file:") {
+            readFile(decodeURIComponent(url.pathname), function(err, data) {
+                if (err) {
+                    reject(new TypeError("NetworkError: " + err.message));
+                    return;
+                }
+                resolve(new Response({
+                    url: url.href, status: 200, statusText: "OK", headers: []
+                }, Promise.resolve(data)));
+            });
+            return;
+        }
+        if (url.protocol !== "http:" && url.protocol !== "https:") {
+            throw new TypeError("Unsupported scheme: " + url.protocol);
+        }
+        let id = jsrust_http_request(JSON.stringify({
+            method: init.method || "GET",
+            url: url.href,
+            headers: new Headers(init.headers)._list,
+            body: init.body ? String(init.body) : ""
+        }));
+        _fetches[id] = {resolve: resolve, reject: reject, text: ""};
+        XMLHttpRequest.requests_outstanding++;
+    });
+};
+
+var BASE64 = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+function bytesToBase64(bytes) {
+    let out = "";
+    for (let i = 0; i < bytes.length; i += 3) {
+        let n = bytes[i] << 16 | (bytes[i + 1] || 0) << 8 | (bytes[i + 2] || 0);
+        out += BASE64[n >> 18 & 63] + BASE64[n >> 12 & 63] +
+            (i + 1 < bytes.length ? BASE64[n >> 6 & 63] : "=") +
+            (i + 2 < bytes.length ? BASE64[n & 63] : "=");
+    }
+    return out;
+}
+
+function base64ToBuffer(s) {
+    s = s.replace(/=+$/, "");
+    let bytes = new Uint8Array(Math.floor(s.length * 3 / 4));
+    let acc = 0, bits = 0, j = 0;
+    for (let i = 0; i < s.length; i++) {
+        acc = (acc << 6 | BASE64.indexOf(s[i])) & 0xffffff;
+        bits += 6;
+        if (bits >= 8) {
+            bits -= 8;
+            bytes[j++] = acc >> bits & 0xff;
+        }
+    }
+    return bytes.buffer;
+}
+
+// Open WebSocket connections, by id.
+var _sockets = {};
+
+// A WebSocket connection through the host, which does the handshake and
+// framing. Binary messages arrive as ArrayBuffers.
+function WebSocket(url, protocols) {
+    let parsed = new URL(url, documentBase());
+    if (parsed.protocol !== "ws:" && parsed.protocol !== "wss:") {
+        throw new SyntaxError("WebSocket URLs must be ws: or wss:, not " + parsed.protocol);
+    }
+    if (parsed.hash) {
+        throw new SyntaxError("WebSocket URLs cannot have a fragment");
+    }
+    if (protocols === undefined) {
+        protocols = [];
+    } else if (!Array.isArray(protocols)) {
+        protocols = [String(protocols)];
+    }
+    // Each goes into the Sec-WebSocket-Protocol header, once.
+    protocols = protocols.map(String);
+    protocols.forEach(function (p, i) {
+        if (!TOKEN.test(p)) {
+            throw new SyntaxError("Invalid subprotocol: " + JSON.stringify(p));
+        }
+        if (protocols.indexOf(p) !== i) {
+            throw new SyntaxError("Duplicate subprotocol: " + p);
+        }
+    });
+    this.url = parsed.href;
+    this.readyState = WebSocket.CONNECTING;
+    this.protocol = "";
+    this.extensions = "";
+    this.bufferedAmount = 0;
+    this.binaryType = "arraybuffer";
+    this._id = jsrust_ws_open(JSON.stringify({url: this.url, protocols: protocols}));
+    _sockets[this._id] = this;
+    // An open connection keeps the actor alive.
+    XMLHttpRequest.requests_outstanding++;
+}
+WebSocket.CONNECTING = 0;
+WebSocket.OPEN = 1;
+WebSocket.CLOSING = 2;
+WebSocket.CLOSED = 3;
+WebSocket.prototype = {
+    CONNECTING: 0,
+    OPEN: 1,
+    CLOSING: 2,
+    CLOSED: 3,
+    onopen: null,
+    onmessage: null,
+    onerror: null,
+    onclose: null,
+    // Strings are sent as text; ArrayBuffers and typed arrays as binary.
+    send: function send(data) {
+        if (this.readyState === WebSocket.CONNECTING) {
+            throw new Error("InvalidStateError: the connection is not open yet");
+        }
+        if (this.readyState !== WebSocket.OPEN) {
+            return;
+        }
+        if (data instanceof ArrayBuffer) {
+            jsrust_ws_send(this._id, bytesToBase64(new Uint8Array(data)), true);
+        } else if (data && data.buffer instanceof ArrayBuffer) {
+            jsrust_ws_send(this._id, bytesToBase64(
+                new Uint8Array(data.buffer, data.byteOffset, data.byteLength)), true);
+        } else {
+            jsrust_ws_send(this._id, String(data), false);
+        }
+    },
+    // Code is 1000 or 3000 to 4999; the reason is at most 123 bytes of
+    // UTF-8.
+    close: function close(code, reason) {
+        if (code !== undefined && code !== 1000 && !(code >= 3000 && code <= 4999)) {
+            throw new Error("InvalidAccessError: bad close code " + code);
+        }
+        reason = reason === undefined ? "" : String(reason);
+        if (unescape(encodeURIComponent(reason)).length > 123) {
+            throw new SyntaxError("close reason is too long");
+        }
+        if (this.readyState >= WebSocket.CLOSING) {
+            return;
+        }
+        this.readyState = WebSocket.CLOSING;
+        jsrust_ws_close(this._id, code === undefined ? (reason ? 1000 : 0) : code, reason);
+    }
+};
+global.WebSocket = WebSocket;
+
+function socketEvent(id, what, data, arg) {
+    let ws = _sockets[id];
+    if (!ws) return;
+    if (what === WS_OPENED) {
+        ws.readyState = WebSocket.OPEN;
+        ws.protocol = data;
+        fireSocket(ws, {type: "open"});
+    } else if (what === WS_MESSAGE) {
+        fireSocket(ws, {
+            type: "message",
+            data: arg ? base64ToBuffer(data) : data,
+            origin: new URL(ws.url).origin
+        });
+    } else if (what === WS_ERROR) {
+        fireSocket(ws, {type: "error", message: data});
+    } else if (what === WS_CLOSED) {
+        let close = JSON.parse(data);
+        delete _sockets[id];
+        XMLHttpRequest.requests_outstanding--;
+        ws.readyState = WebSocket.CLOSED;
+        fireSocket(ws, {
+            type: "close",
+            code: close.code,
+            reason: close.reason,
+            wasClean: close.wasClean
+        });
+    }
+}
+
+function fireSocket(ws, event) {
+    let handler = ws["on" + event.type];
+    if (typeof handler !== "function") return;
+    event.target = ws;
+    handler.call(ws, event);
+}
+
 return XMLHttpRequest;
 })(this);
 